@@ -74,11 +74,20 @@ app.kubernetes.io/component: webhooks
 
 {{/* Config */}}
 {{- define "trow.hasConfigFile" -}}
-{{- or (not (empty .Values.trow.proxyRegistries.config)) (not (empty .Values.trow.validationWebhook.config)) -}}
+{{- or (not (empty .Values.trow.proxyRegistries.config)) (not (empty .Values.trow.validationWebhook.config)) (not (empty .Values.trow.prefetch)) (not (empty .Values.trow.repoMirrors)) (not (empty .Values.trow.replication)) -}}
 {{- end -}}
 {{- define "trow.config" -}}
 registry_proxies: {{- .Values.trow.proxyRegistries.config | toYaml | nindent 2 }}
 image_validation: {{- .Values.trow.validationWebhook.config | toYaml | nindent 2 }}
+{{- with .Values.trow.prefetch }}
+prefetch: {{- toYaml . | nindent 2 }}
+{{- end }}
+{{- with .Values.trow.repoMirrors }}
+repo_mirrors: {{- toYaml . | nindent 2 }}
+{{- end }}
+{{- with .Values.trow.replication }}
+replication: {{- toYaml . | nindent 2 }}
+{{- end }}
 {{- end -}}
 
 {{/* Webhook certificate generation is done either via patch or certmanager */}}
@@ -0,0 +1,4 @@
+-- Serialized SHA-256 state of the data received so far, used to verify the
+-- digest when the upload is completed. NULL for uploads started before this
+-- column existed.
+ALTER TABLE blob_upload ADD COLUMN "hash_state" BLOB;
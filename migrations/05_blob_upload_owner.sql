@@ -0,0 +1,4 @@
+-- User that started the upload and when, shown by the admin uploads listing.
+-- NULL for uploads started before these columns existed.
+ALTER TABLE blob_upload ADD COLUMN "owner" TEXT;
+ALTER TABLE blob_upload ADD COLUMN "created_at" INTEGER;
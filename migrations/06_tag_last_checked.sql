@@ -0,0 +1,3 @@
+-- Unix timestamp of the last time a proxied tag was checked against its upstream.
+-- NULL for local tags and for proxied tags cached before this column existed.
+ALTER TABLE tag ADD COLUMN "last_checked" INTEGER;
@@ -0,0 +1,18 @@
+-- Images to push to the replication targets, queued when a manifest is pushed.
+-- Jobs stay `pending` until pushed (`done`) or out of attempts (`failed`).
+CREATE TABLE "replication_job" (
+    "id" INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    "repo" TEXT NOT NULL,
+    -- Tag or digest the manifest was pushed with
+    "reference" TEXT NOT NULL,
+    "manifest_digest" TEXT NOT NULL,
+    -- Target repository, e.g. `dr.example.com/team/app`
+    "target" TEXT NOT NULL,
+    "status" TEXT NOT NULL DEFAULT 'pending',
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "last_error" TEXT,
+    "next_attempt_at" INTEGER NOT NULL DEFAULT (unixepoch()),
+    "created_at" INTEGER NOT NULL DEFAULT (unixepoch()),
+    "updated_at" INTEGER NOT NULL DEFAULT (unixepoch())
+) STRICT;
+CREATE INDEX "IDX_replication_job_due" ON "replication_job" ("status", "next_attempt_at");
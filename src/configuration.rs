@@ -1,5 +1,9 @@
+use std::path::PathBuf;
+
 use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::PROXY_DIR;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ImageValidationConfig {
     pub default: String,
@@ -12,6 +16,125 @@ pub struct ConfigFile {
     #[serde(deserialize_with = "de_unwrap_or_default")]
     pub registry_proxies: RegistryProxiesConfig,
     pub image_validation: Option<ImageValidationConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// Platform (`os/arch[/variant]`) served to clients that don't accept
+    /// image indexes, defaults to `linux/amd64`
+    pub default_platform: Option<String>,
+    /// Proxied images downloaded ahead of time, on a schedule
+    pub prefetch: Option<PrefetchConfig>,
+    /// Upstream repositories periodically copied into local repositories
+    pub repo_mirrors: Option<RepoMirrorsConfig>,
+    /// Registries that pushed images are copied to
+    pub replication: Option<ReplicationConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicationConfig {
+    /// Attempts of a job before it is marked failed. Retries start after
+    /// 30 seconds, doubling up to an hour.
+    #[serde(default = "default_replication_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub rules: Vec<ReplicationRule>,
+}
+
+fn default_replication_max_attempts() -> u32 {
+    10
+}
+
+/// Pushes to the matching repositories are copied to `target`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ReplicationRule {
+    /// Regex matched against the name of pushed repositories, e.g. `^team-a/`
+    pub repositories: String,
+    /// With `repository_prefix: dr`, `team/app` is pushed as `dr/team/app`
+    pub target: RegistryEndpoint,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RepoMirrorsConfig {
+    /// Seconds between two syncs, the first one starts with Trow
+    #[serde(default = "default_repo_mirrors_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub repositories: Vec<RepoMirror>,
+}
+
+fn default_repo_mirrors_interval() -> u64 {
+    3600
+}
+
+/// Tags of an upstream repository copied into a local repository
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RepoMirror {
+    /// Upstream repository, e.g. `ghcr.io/org/app`
+    pub source: String,
+    /// Local repository, e.g. `mirrors/app`
+    pub target: String,
+    /// Only the upstream tags matching this regex are copied, all if unset
+    pub tag_regex: Option<String>,
+    /// Delete the local tags matching `tag_regex` that were removed upstream
+    #[serde(default)]
+    pub delete_removed_tags: bool,
+    /// Platforms (`os/arch[/variant]`) copied from indexes, all if empty
+    #[serde(default)]
+    pub platforms: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrefetchConfig {
+    /// Seconds between two prefetch runs, the first one starts with Trow
+    #[serde(default = "default_prefetch_interval")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub images: Vec<PrefetchImage>,
+}
+
+fn default_prefetch_interval() -> u64 {
+    3600
+}
+
+/// Tags of an upstream repository to prefetch
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PrefetchImage {
+    /// Upstream repository, e.g. `docker.io/library/alpine`
+    pub repository: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Tags listed upstream matching this regex are prefetched too
+    pub tag_regex: Option<String>,
+}
+
+/// Where blobs and uploads are persisted. Metadata always lives in the database.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// Store blobs in the data directory
+    #[default]
+    Filesystem,
+    /// Store blobs in an S3-compatible object store
+    S3(S3StorageConfig),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct S3StorageConfig {
+    pub bucket: String,
+    /// Key prefix under which all objects are stored (e.g. "trow/")
+    #[serde(default)]
+    pub prefix: String,
+    /// Defaults to the region from the AWS environment, or `us-east-1`
+    pub region: Option<String>,
+    /// Custom endpoint for S3-compatible stores (MinIO, Ceph, R2...)
+    pub endpoint: Option<String>,
+    /// Use `<endpoint>/<bucket>` URLs instead of `<bucket>.<endpoint>`
+    #[serde(default)]
+    pub force_path_style: bool,
+    /// Static credentials, the AWS default credentials chain is used otherwise
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Size of multipart upload parts, must be at least 5MiB for AWS S3
+    pub part_size: Option<size::Size>,
 }
 
 fn de_unwrap_or_default<'de, T, D>(d: D) -> Result<T, D::Error>
@@ -33,6 +156,48 @@ pub struct RegistryProxiesConfig {
     pub offline: bool,
     #[serde(default)]
     pub max_size: Option<size::Size>,
+    /// HTTP(S) proxy for the requests to upstream registries, unless the
+    /// registry sets its own. `HTTPS_PROXY`/`NO_PROXY` are used if unset.
+    #[serde(default)]
+    pub egress_proxy: Option<EgressProxyConfig>,
+    /// Upstream serving pulls of repos that are neither local nor under `f/`
+    /// (mirror mode), e.g. `docker.io` for Docker Engine `registry-mirrors`
+    #[serde(default)]
+    pub default_upstream: Option<String>,
+    /// Repository prefixes served by an upstream in mirror mode, tried
+    /// before `default_upstream`
+    #[serde(default)]
+    pub rewrites: Vec<UpstreamRewrite>,
+}
+
+/// In mirror mode, pulls of `<prefix>/<repo>` are served from `<upstream>/<repo>`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct UpstreamRewrite {
+    #[serde(deserialize_with = "normalize_prefix")]
+    pub prefix: String,
+    /// Registry host, optionally followed by a path (`ghcr.io/my-org`)
+    pub upstream: String,
+}
+
+fn normalize_prefix<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(String::deserialize(deserializer)?
+        .trim_matches('/')
+        .to_string())
+}
+
+/// Proxy through which upstream registries are reached
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EgressProxyConfig {
+    /// e.g. `http://proxy.internal:3128`, can contain credentials
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Comma-separated hosts, domains and CIDRs reached directly.
+    /// Defaults to the `NO_PROXY` environment variable.
+    pub no_proxy: Option<String>,
 }
 
 fn normalize_path_prefix<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
@@ -56,11 +221,98 @@ pub struct SingleRegistryProxyConfig {
     /// When multiple entries match the same host, the longest matching prefix wins.
     #[serde(default, deserialize_with = "normalize_path_prefix")]
     pub path_prefix: Option<String>,
-    /// TODO: insecure currently means "use HTTP", we should also support self-signed TLS
+    /// Use plain HTTP instead of HTTPS
+    #[serde(default)]
+    pub insecure: bool,
+    /// PEM bundle of CA certificates trusted in addition to the system ones,
+    /// for registries using a private CA
+    pub ca_file: Option<PathBuf>,
+    /// Accept any TLS certificate. Only for testing!
+    #[serde(default)]
+    pub tls_skip_verify: bool,
+    /// PEM client certificate and key, for registries requiring mutual TLS.
+    /// Not supported yet: the config is rejected if they are set.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Overrides the global `egress_proxy` for this registry
+    pub egress_proxy: Option<EgressProxyConfig>,
+    /// Docker `config.json` to take credentials from when `username` is unset,
+    /// or a directory where a `kubernetes.io/dockerconfigjson` secret is mounted.
+    /// Its `credHelpers` and `credsStore` helpers are run if needed.
+    pub docker_config: Option<PathBuf>,
+    /// Seconds during which a cached tag is served without checking upstream
+    /// for a newer digest. Tags are checked on every pull if unset.
+    pub tag_ttl_secs: Option<u64>,
+    /// Whether cached tags are served when upstream can't be checked
+    #[serde(default)]
+    pub serve_stale: ServeStalePolicy,
+    /// Registries tried in order before this one, e.g. pull-through caches
+    #[serde(default)]
+    pub mirrors: Vec<RegistryEndpoint>,
+    /// Platforms (`os/arch[/variant]`) to cache. Proxying an index also
+    /// downloads their manifests and layers, and the GC drops the others.
+    /// All platforms are downloaded on demand if empty.
+    #[serde(default)]
+    pub platforms: Vec<String>,
+}
+
+/// A registry reached with its own credentials and TLS settings: a mirror
+/// tried before a proxied registry, or a replication target
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RegistryEndpoint {
+    pub host: String,
+    /// Prepended to repository names on this registry, e.g. the name of an
+    /// Artifactory remote repository
+    pub repository_prefix: Option<String>,
+    /// Use plain HTTP instead of HTTPS
     #[serde(default)]
     pub insecure: bool,
+    /// PEM bundle of CA certificates trusted in addition to the system ones
+    pub ca_file: Option<PathBuf>,
+    /// Accept any TLS certificate. Only for testing!
+    #[serde(default)]
+    pub tls_skip_verify: bool,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Docker `config.json` to take credentials from when `username` is unset
+    pub docker_config: Option<PathBuf>,
+    /// Overrides the global `egress_proxy` of `registry_proxies`
+    pub egress_proxy: Option<EgressProxyConfig>,
+}
+
+impl RegistryEndpoint {
+    /// The settings of the client reaching this registry
+    pub fn registry_config(&self) -> SingleRegistryProxyConfig {
+        SingleRegistryProxyConfig {
+            host: self.host.clone(),
+            insecure: self.insecure,
+            ca_file: self.ca_file.clone(),
+            tls_skip_verify: self.tls_skip_verify,
+            username: self.username.clone(),
+            password: self.password.clone(),
+            docker_config: self.docker_config.clone(),
+            egress_proxy: self.egress_proxy.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// What to do on a tag pull when the upstream registry can't be checked
+/// and a cached digest exists.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServeStalePolicy {
+    /// Serve the cached digest whatever the error
+    #[default]
+    Always,
+    /// Serve the cached digest if upstream is unreachable, rate limited (429)
+    /// or failing (5xx), but not if it rejects the request (e.g. tag deleted,
+    /// credentials revoked)
+    WhenUnavailable,
+    /// Fail the pull
+    Never,
 }
 
 impl Default for RegistryProxiesConfig {
@@ -69,7 +321,42 @@ impl Default for RegistryProxiesConfig {
             registries: RegistryProxyConfigs(Vec::new()),
             offline: true,
             max_size: None,
+            egress_proxy: None,
+            default_upstream: None,
+            rewrites: Vec::new(),
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Rejects settings that parse but can't be honoured
+    pub fn validate(&self) -> Result<(), String> {
+        for registry in &self.registry_proxies.registries.0 {
+            if registry.client_cert.is_some() || registry.client_key.is_some() {
+                return Err(format!(
+                    "{}: client certificates (`client_cert`, `client_key`) are not supported",
+                    registry.host
+                ));
+            }
         }
+        Ok(())
+    }
+}
+
+impl RegistryProxiesConfig {
+    /// The proxied repository (`f/<host>/<repo>`) serving `repo` in mirror
+    /// mode, from the longest matching rewrite or the default upstream
+    pub fn mirror_repo(&self, repo: &str) -> Option<String> {
+        let rewrite = self
+            .rewrites
+            .iter()
+            .filter_map(|r| Some((r, repo.strip_prefix(&r.prefix)?.strip_prefix('/')?)))
+            .max_by_key(|(r, _)| r.prefix.len());
+        let (upstream, repo) = match rewrite {
+            Some((rewrite, rest)) => (rewrite.upstream.as_str(), rest),
+            None => (self.default_upstream.as_deref()?, repo),
+        };
+        Some(format!("{PROXY_DIR}{}/{repo}", upstream.trim_matches('/')))
     }
 }
 
@@ -111,6 +398,60 @@ impl From<Vec<SingleRegistryProxyConfig>> for RegistryProxyConfigs {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_storage_config_deserialize() {
+        let config: ConfigFile = serde_yaml_ng::from_str("registry_proxies: {}").unwrap();
+        assert_eq!(config.storage, StorageConfig::Filesystem);
+
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies: {}
+storage:
+  backend: s3
+  bucket: trow
+  endpoint: http://127.0.0.1:9000
+  force_path_style: true
+  part_size: 16MiB
+"#,
+        )
+        .unwrap();
+        let StorageConfig::S3(s3) = config.storage else {
+            panic!("expected S3 storage config");
+        };
+        assert_eq!(s3.bucket, "trow");
+        assert_eq!(s3.prefix, "");
+        assert_eq!(s3.endpoint.as_deref(), Some("http://127.0.0.1:9000"));
+        assert!(s3.force_path_style);
+        assert_eq!(s3.part_size.unwrap().bytes(), 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_validate_rejects_client_certificates() {
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies:
+  registries:
+    - host: harbor.internal
+      ca_file: /etc/trow/ca.pem
+"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies:
+  registries:
+    - host: harbor.internal
+      client_cert: /etc/trow/client.pem
+      client_key: /etc/trow/client-key.pem
+"#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("harbor.internal"), "{err}");
+    }
+
     #[test]
     fn test_registry_proxy_deserialize_path_prefix() {
         let proxy_config: SingleRegistryProxyConfig =
@@ -124,6 +465,160 @@ mod tests {
         assert_eq!(proxy_config.path_prefix, None);
     }
 
+    #[test]
+    fn test_registry_proxy_deserialize_mirrors() {
+        let proxy_config: SingleRegistryProxyConfig = serde_yaml_ng::from_str(
+            r#"
+host: docker.io
+mirrors:
+  - host: artifactory.internal
+    repository_prefix: dockerhub-remote
+    username: trow
+  - host: mirror.gcr.io
+"#,
+        )
+        .unwrap();
+        let hosts: Vec<_> = proxy_config
+            .mirrors
+            .iter()
+            .map(|m| m.host.as_str())
+            .collect();
+        assert_eq!(hosts, ["artifactory.internal", "mirror.gcr.io"]);
+        assert_eq!(
+            proxy_config.mirrors[0].repository_prefix.as_deref(),
+            Some("dockerhub-remote")
+        );
+    }
+
+    #[test]
+    fn test_registry_proxies_deserialize_egress_proxy() {
+        let config: RegistryProxiesConfig = serde_yaml_ng::from_str(
+            r#"
+egress_proxy:
+  url: http://proxy.corp:3128
+  username: trow
+  password: hunter2
+  no_proxy: .corp,10.0.0.0/8
+registries:
+  - host: registry.corp
+    egress_proxy:
+      url: http://other-proxy.corp:8080
+"#,
+        )
+        .unwrap();
+        let global = config.egress_proxy.unwrap();
+        assert_eq!(global.url, "http://proxy.corp:3128");
+        assert_eq!(global.no_proxy.as_deref(), Some(".corp,10.0.0.0/8"));
+        let registry = config.registries.get_for("registry.corp", "app").unwrap();
+        assert_eq!(
+            registry.egress_proxy.as_ref().unwrap().url,
+            "http://other-proxy.corp:8080"
+        );
+    }
+
+    #[test]
+    fn test_prefetch_deserialize() {
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies: {}
+prefetch:
+  images:
+    - repository: docker.io/library/alpine
+      tags: ["3.20", "latest"]
+    - repository: ghcr.io/my-org/app
+      tag_regex: ^v1\.[0-9]+$
+"#,
+        )
+        .unwrap();
+        let prefetch = config.prefetch.unwrap();
+        assert_eq!(prefetch.interval_secs, 3600);
+        assert_eq!(prefetch.images[0].tags, ["3.20", "latest"]);
+        assert_eq!(
+            prefetch.images[1].tag_regex.as_deref(),
+            Some(r"^v1\.[0-9]+$")
+        );
+    }
+
+    #[test]
+    fn test_repo_mirrors_deserialize() {
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies: {}
+repo_mirrors:
+  interval_secs: 600
+  repositories:
+    - source: ghcr.io/org/app
+      target: mirrors/app
+      tag_regex: ^v\d+
+      delete_removed_tags: true
+"#,
+        )
+        .unwrap();
+        let repo_mirrors = config.repo_mirrors.unwrap();
+        assert_eq!(repo_mirrors.interval_secs, 600);
+        let mirror = &repo_mirrors.repositories[0];
+        assert_eq!(mirror.target, "mirrors/app");
+        assert!(mirror.delete_removed_tags);
+        assert!(mirror.platforms.is_empty());
+    }
+
+    #[test]
+    fn test_replication_deserialize() {
+        let config: ConfigFile = serde_yaml_ng::from_str(
+            r#"
+registry_proxies: {}
+replication:
+  rules:
+    - repositories: ^team-a/
+      target:
+        host: dr.example.com
+        username: robot
+        password: s3cr3t
+        repository_prefix: trow
+"#,
+        )
+        .unwrap();
+        let replication = config.replication.unwrap();
+        assert_eq!(replication.max_attempts, 10);
+        let rule = &replication.rules[0];
+        assert_eq!(rule.repositories, "^team-a/");
+        assert_eq!(rule.target.host, "dr.example.com");
+        assert_eq!(rule.target.repository_prefix.as_deref(), Some("trow"));
+    }
+
+    #[test]
+    fn test_registry_proxies_mirror_repo() {
+        let config: RegistryProxiesConfig = serde_yaml_ng::from_str(
+            r#"
+default_upstream: docker.io
+rewrites:
+  - prefix: quay/
+    upstream: quay.io
+  - prefix: quay/my-org
+    upstream: ghcr.io/my-org
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mirror_repo("library/alpine").as_deref(),
+            Some("f/docker.io/library/alpine")
+        );
+        assert_eq!(
+            config.mirror_repo("quay/coreos/etcd").as_deref(),
+            Some("f/quay.io/coreos/etcd")
+        );
+        assert_eq!(
+            config.mirror_repo("quay/my-org/app").as_deref(),
+            Some("f/ghcr.io/my-org/app")
+        );
+        // Prefixes match whole path segments
+        assert_eq!(
+            config.mirror_repo("quayside/app").as_deref(),
+            Some("f/docker.io/quayside/app")
+        );
+        assert_eq!(RegistryProxiesConfig::default().mirror_repo("alpine"), None);
+    }
+
     #[test]
     fn test_registry_proxy_configs_path_prefix_longest_match_wins() {
         let config = RegistryProxiesConfig {
@@ -1,9 +1,9 @@
 pub mod configuration;
-pub mod file_storage;
 
 pub mod repositories;
 pub mod routes;
 pub mod services;
+pub mod storage;
 #[cfg(test)]
 pub mod test_utilities;
 pub mod types;
@@ -15,12 +15,11 @@ use std::sync::Arc;
 use std::{env, fs};
 
 use axum::Router;
-use file_storage::StorageBackendError;
+use storage::StorageBackendError;
 use thiserror::Error;
 use uuid::Uuid;
 
 use crate::configuration::ConfigFile;
-use crate::file_storage::FileStorage;
 use crate::repositories::Repositories;
 use crate::services::Services;
 
@@ -89,6 +88,8 @@ pub enum TrowConfigError {
     IoError(#[from] std::io::Error),
     #[error("Could not parse config file: {0}")]
     SerdeError(#[from] serde_yaml_ng::Error),
+    #[error("Invalid config file: {0}")]
+    InvalidConfig(String),
     #[error("Could not setup database: {0}")]
     DbSetupError(#[from] sqlx::migrate::MigrateError),
     #[error("Could not setup storage backend: {0}")]
@@ -118,6 +119,7 @@ impl TrowConfig {
         let config_file = config_file.as_ref();
         let config_str = fs::read_to_string(config_file)?;
         let config = serde_yaml_ng::from_str::<ConfigFile>(&config_str)?;
+        config.validate().map_err(TrowConfigError::InvalidConfig)?;
         self.config_file = config;
         Ok(self)
     }
@@ -165,7 +167,8 @@ impl TrowConfig {
                 p.to_string_lossy().to_string()
             }
         };
-        let storage = Arc::new(FileStorage::new(self.data_dir.clone())?);
+        let storage =
+            storage::from_config(&self.config_file.storage, self.data_dir.clone()).await?;
         let repos = Arc::new(Repositories::new(&db_file).await?);
         let config_arc = Arc::new(self.clone());
         let services = Arc::new(Services::new(repos, storage, config_arc));
@@ -184,6 +187,18 @@ impl TrowConfig {
             let gc = state.services.gc.clone();
             async move { gc.watchdog().await }
         });
+        tokio::spawn({
+            let prefetch = state.services.prefetch.clone();
+            async move { prefetch.watchdog().await }
+        });
+        tokio::spawn({
+            let repo_mirror = state.services.repo_mirror.clone();
+            async move { repo_mirror.watchdog().await }
+        });
+        tokio::spawn({
+            let replication = state.services.replication.clone();
+            async move { replication.watchdog().await }
+        });
         Ok(routes::create_app(state))
     }
 }
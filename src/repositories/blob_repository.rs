@@ -77,6 +77,28 @@ impl BlobRepository {
         Ok(res == 1)
     }
 
+    /// SELECT b.size FROM blob b JOIN repo_blob_assoc rba ON ... WHERE b.digest = $1 AND rba.repo_name = $2
+    ///
+    /// Returns `None` if the blob doesn't belong to the repo.
+    pub async fn find_size_in_repo(
+        &self,
+        digest: &str,
+        repo_name: &str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT b.size
+            FROM blob b
+            JOIN repo_blob_assoc rba ON rba.blob_digest = b.digest
+            WHERE b.digest = $1 AND rba.repo_name = $2
+            "#,
+            digest,
+            repo_name
+        )
+        .fetch_optional(&self.db_ro)
+        .await
+    }
+
     /// SELECT SUM(b.size) FROM blob b
     pub async fn sum_size(&self) -> Result<usize, sqlx::Error> {
         let res = sqlx::query_scalar!(r#"SELECT SUM(b.size) as "size!" FROM blob b"#)
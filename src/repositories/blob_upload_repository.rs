@@ -24,7 +24,7 @@ impl BlobUploadRepository {
         sqlx::query_as!(
             BlobUpload,
             r#"
-            SELECT uuid, repo, offset, updated_at
+            SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at
             FROM blob_upload
             WHERE uuid=$1
             "#,
@@ -56,38 +56,50 @@ impl BlobUploadRepository {
         Ok(())
     }
 
-    /// INSERT INTO blob_upload (uuid, repo, offset) VALUES ($1, $2, $3)
-    pub async fn create(&self, uuid: &str, repo: &str) -> Result<(), sqlx::Error> {
+    /// INSERT INTO blob_upload (uuid, repo, offset, owner, created_at) VALUES ($1, $2, $3, $4, unixepoch())
+    pub async fn create(&self, uuid: &str, repo: &str, owner: &str) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
-            INSERT INTO blob_upload (uuid, repo, offset)
-            VALUES ($1, $2, $3)
+            INSERT INTO blob_upload (uuid, repo, offset, owner, created_at)
+            VALUES ($1, $2, $3, $4, unixepoch())
             "#,
             uuid,
             repo,
-            0_i64
+            0_i64,
+            owner
         )
         .execute(&self.db_rw)
         .await?;
         Ok(())
     }
 
-    /// UPDATE blob_upload SET offset=$2, updated_at=unixepoch() WHERE uuid=$1
-    pub async fn update_offset(&self, uuid: &str, offset: i64) -> Result<(), sqlx::Error> {
+    /// UPDATE blob_upload SET offset=$2, hash_state=$3, updated_at=unixepoch() WHERE uuid=$1
+    pub async fn update_progress(
+        &self,
+        uuid: &str,
+        offset: i64,
+        hash_state: Option<&[u8]>,
+    ) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "UPDATE blob_upload SET offset=$2, updated_at=unixepoch() WHERE uuid=$1",
+            "UPDATE blob_upload SET offset=$2, hash_state=$3, updated_at=unixepoch() WHERE uuid=$1",
             uuid,
-            offset
+            offset,
+            hash_state
         )
         .execute(&self.db_rw)
         .await?;
         Ok(())
     }
 
-    /// SELECT offset FROM blob_upload WHERE uuid = $1 AND repo = $2
-    pub async fn find_offset_in_repo(&self, uuid: &str, repo: &str) -> Result<i64, sqlx::Error> {
-        sqlx::query_scalar!(
-            "SELECT offset FROM blob_upload WHERE uuid = $1 AND repo = $2",
+    /// SELECT * FROM blob_upload WHERE uuid = $1 AND repo = $2
+    pub async fn find_in_repo(&self, uuid: &str, repo: &str) -> Result<BlobUpload, sqlx::Error> {
+        sqlx::query_as!(
+            BlobUpload,
+            r#"
+            SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at
+            FROM blob_upload
+            WHERE uuid = $1 AND repo = $2
+            "#,
             uuid,
             repo
         )
@@ -95,12 +107,12 @@ impl BlobUploadRepository {
         .await
     }
 
-    /// SELECT uuid, repo, offset, updated_at FROM blob_upload bu WHERE bu.updated_at < ...
+    /// SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at FROM blob_upload bu WHERE bu.updated_at < ...
     pub async fn list_stale_older_than_days(&self) -> Result<Vec<BlobUpload>, sqlx::Error> {
         sqlx::query_as!(
             BlobUpload,
             r#"
-            SELECT uuid, repo, offset, updated_at
+            SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at
             FROM blob_upload bu
             WHERE bu.updated_at < strftime('%s', 'now', '-1 day')
             "#
@@ -109,6 +121,22 @@ impl BlobUploadRepository {
         .await
     }
 
+    /// SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at FROM blob_upload WHERE ($1 IS NULL OR repo = $1) ORDER BY repo, created_at
+    pub async fn list(&self, repo: Option<&str>) -> Result<Vec<BlobUpload>, sqlx::Error> {
+        sqlx::query_as!(
+            BlobUpload,
+            r#"
+            SELECT uuid, repo, offset, updated_at, hash_state, owner, created_at
+            FROM blob_upload
+            WHERE ($1 IS NULL OR repo = $1)
+            ORDER BY repo, created_at
+            "#,
+            repo
+        )
+        .fetch_all(&self.db_ro)
+        .await
+    }
+
     /// SELECT SUM(u.offset) FROM blob_upload u
     pub async fn sum_offset(&self) -> Result<usize, sqlx::Error> {
         let res = sqlx::query_scalar!(r#"SELECT SUM(u.offset) as "size!" FROM blob_upload u"#)
@@ -1,7 +1,7 @@
 use sqlx::SqlitePool;
 use sqlx::types::Json;
 
-use super::models::{Manifest, ManifestReferrer};
+use super::models::{Manifest, ManifestReferrer, RepoIndex};
 use crate::utils::manifest::OCIManifest;
 
 pub struct ManifestRepository {
@@ -83,6 +83,23 @@ impl ManifestRepository {
         .await
     }
 
+    /// SELECT rba.repo_name, json(m.json) FROM manifest m JOIN repo_blob_assoc rba ... WHERE rba.repo_name LIKE 'f/%' AND <m is an index>
+    pub async fn list_proxied_indexes(&self) -> Result<Vec<RepoIndex>, sqlx::Error> {
+        sqlx::query_as!(
+            RepoIndex,
+            r#"
+            SELECT rba.repo_name as "repo_name!",
+                json(m.json) as "content!: Json<OCIManifest>"
+            FROM manifest m
+            INNER JOIN repo_blob_assoc rba ON rba.manifest_digest = m.digest
+            WHERE rba.repo_name LIKE 'f/%'
+                AND json_type(m.json, '$.manifests') = 'array'
+            "#
+        )
+        .fetch_all(&self.db_ro)
+        .await
+    }
+
     /// SELECT DISTINCT manifest_digest FROM manifest_blob_assoc WHERE blob_digest = $1
     pub async fn list_manifests_using_blob(
         &self,
@@ -95,4 +112,88 @@ impl ManifestRepository {
         .fetch_all(&self.db_ro)
         .await
     }
+
+    /// SELECT DISTINCT mba.manifest_digest FROM manifest_blob_assoc mba JOIN repo_blob_assoc rba ... WHERE mba.blob_digest = $1 AND rba.repo_name = $2
+    pub async fn list_manifests_in_repo_using_blob(
+        &self,
+        repo_name: &str,
+        blob_digest: &str,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT mba.manifest_digest
+            FROM manifest_blob_assoc mba
+            JOIN repo_blob_assoc rba ON rba.manifest_digest = mba.manifest_digest
+            WHERE mba.blob_digest = $1 AND rba.repo_name = $2
+            "#,
+            blob_digest,
+            repo_name
+        )
+        .fetch_all(&self.db_ro)
+        .await
+    }
+
+    /// SELECT EXISTS(SELECT 1 FROM manifest m JOIN repo_blob_assoc rba ... WHERE rba.repo_name = $2 AND <m references $1>)
+    ///
+    /// Unlike `manifest_blob_assoc`, this also sees blobs that are not stored.
+    pub async fn blob_referenced_in_repo(
+        &self,
+        blob_digest: &str,
+        repo_name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let exists = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1
+                FROM manifest m
+                JOIN repo_blob_assoc rba ON rba.manifest_digest = m.digest
+                WHERE rba.repo_name = $2
+                    AND (
+                        json_extract(m.json, '$.config.digest') = $1
+                        OR EXISTS (
+                            SELECT 1 FROM json_each(json_extract(m.json, '$.layers'))
+                            WHERE json_extract(value, '$.digest') = $1
+                        )
+                    )
+            ) as "exists!: bool"
+            "#,
+            blob_digest,
+            repo_name
+        )
+        .fetch_one(&self.db_ro)
+        .await?;
+        Ok(exists)
+    }
+
+    /// INSERT OR IGNORE INTO manifest_blob_assoc SELECT m.digest, $1 FROM manifest m JOIN repo_blob_assoc rba ... WHERE rba.repo_name = $2 AND <m references $1>
+    ///
+    /// The insert trigger on manifest only maps blobs that already exist, this
+    /// maps a blob stored after the manifests of the repo that use it.
+    pub async fn link_blob_in_repo(
+        &self,
+        blob_digest: &str,
+        repo_name: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT OR IGNORE INTO manifest_blob_assoc (manifest_digest, blob_digest)
+            SELECT m.digest, $1
+            FROM manifest m
+            JOIN repo_blob_assoc rba ON rba.manifest_digest = m.digest
+            WHERE rba.repo_name = $2
+                AND (
+                    json_extract(m.json, '$.config.digest') = $1
+                    OR EXISTS (
+                        SELECT 1 FROM json_each(json_extract(m.json, '$.layers'))
+                        WHERE json_extract(value, '$.digest') = $1
+                    )
+                )
+            "#,
+            blob_digest,
+            repo_name
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(())
+    }
 }
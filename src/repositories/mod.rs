@@ -7,6 +7,7 @@ pub mod blob_repository;
 pub mod blob_upload_repository;
 pub mod manifest_repository;
 pub mod models;
+pub mod replication_job_repository;
 pub mod repo_blob_assoc_repository;
 pub mod tag_repository;
 
@@ -16,6 +17,7 @@ use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
 pub use self::blob_repository::BlobRepository;
 pub use self::blob_upload_repository::BlobUploadRepository;
 pub use self::manifest_repository::ManifestRepository;
+pub use self::replication_job_repository::ReplicationJobRepository;
 pub use self::repo_blob_assoc_repository::RepoBlobAssocRepository;
 pub use self::tag_repository::TagRepository;
 
@@ -27,6 +29,7 @@ pub struct Repositories {
     pub manifest: ManifestRepository,
     pub tag: TagRepository,
     pub repo_blob_assoc: RepoBlobAssocRepository,
+    pub replication_job: ReplicationJobRepository,
 }
 
 impl std::fmt::Debug for Repositories {
@@ -61,6 +64,7 @@ impl Repositories {
             blob_upload: BlobUploadRepository::new(db_ro.clone(), db_rw.clone()),
             manifest: ManifestRepository::new(db_ro.clone(), db_rw.clone()),
             tag: TagRepository::new(db_ro.clone(), db_rw.clone()),
+            replication_job: ReplicationJobRepository::new(db_ro.clone(), db_rw.clone()),
             repo_blob_assoc: RepoBlobAssocRepository::new(db_ro, db_rw),
         })
     }
@@ -72,6 +76,7 @@ impl Repositories {
             blob_upload: BlobUploadRepository::new(db_ro.clone(), db_rw.clone()),
             manifest: ManifestRepository::new(db_ro.clone(), db_rw.clone()),
             tag: TagRepository::new(db_ro.clone(), db_rw.clone()),
+            replication_job: ReplicationJobRepository::new(db_ro.clone(), db_rw.clone()),
             repo_blob_assoc: RepoBlobAssocRepository::new(db_ro, db_rw),
         }
     }
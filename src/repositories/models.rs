@@ -1,6 +1,7 @@
 //! Domain models returned by repositories. Field names and order match SQL
 //! columns for zero-friction hand-off with `sqlx::query_as!`.
 
+use serde::Serialize;
 use sqlx::FromRow;
 use sqlx::types::Json;
 
@@ -19,6 +20,13 @@ pub struct BlobUpload {
     pub repo: String,
     pub offset: i64,
     pub updated_at: String,
+    /// Serialized [`IncrementalDigest`](crate::utils::digest::IncrementalDigest)
+    /// of the data received so far
+    pub hash_state: Option<Vec<u8>>,
+    /// User that started the upload, `NULL` for legacy rows
+    pub owner: Option<String>,
+    /// Unix timestamp, `NULL` for legacy rows
+    pub created_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -34,6 +42,8 @@ pub struct Tag {
     pub repo: String,
     pub tag: String,
     pub manifest_digest: String,
+    /// Unix timestamp of the last upstream check, for proxied tags
+    pub last_checked: Option<i64>,
 }
 
 #[derive(Debug, FromRow)]
@@ -42,3 +52,30 @@ pub struct ManifestReferrer {
     pub digest: String,
     pub size: i64,
 }
+
+/// An image index stored in a repo
+#[derive(Debug, FromRow)]
+pub struct RepoIndex {
+    pub repo_name: String,
+    pub content: Json<OCIManifest>,
+}
+
+/// An image queued for a replication target, see `replication` in the config
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct ReplicationJob {
+    pub id: i64,
+    pub repo: String,
+    /// Tag or digest the manifest was pushed with
+    pub reference: String,
+    pub manifest_digest: String,
+    /// Target repository, including the registry host
+    pub target: String,
+    /// `pending`, `done` or `failed`
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    /// Unix timestamps
+    pub next_attempt_at: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
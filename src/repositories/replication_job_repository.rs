@@ -0,0 +1,142 @@
+use sqlx::SqlitePool;
+
+use super::models::ReplicationJob;
+
+pub struct ReplicationJobRepository {
+    db_ro: SqlitePool,
+    db_rw: SqlitePool,
+}
+
+impl std::fmt::Debug for ReplicationJobRepository {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplicationJobRepository")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReplicationJobRepository {
+    pub fn new(db_ro: SqlitePool, db_rw: SqlitePool) -> Self {
+        Self { db_ro, db_rw }
+    }
+
+    /// INSERT INTO replication_job (repo, reference, manifest_digest, target) VALUES ($1, $2, $3, $4)
+    pub async fn insert(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest_digest: &str,
+        target: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO replication_job (repo, reference, manifest_digest, target)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+            repo,
+            reference,
+            manifest_digest,
+            target
+        )
+        .fetch_one(&self.db_rw)
+        .await?;
+        Ok(id)
+    }
+
+    /// SELECT * FROM replication_job WHERE status='pending' AND next_attempt_at <= unixepoch() ORDER BY id LIMIT $1
+    pub async fn list_due(&self, limit: i64) -> Result<Vec<ReplicationJob>, sqlx::Error> {
+        sqlx::query_as!(
+            ReplicationJob,
+            r#"
+            SELECT id, repo, reference, manifest_digest, target, status, attempts,
+                last_error, next_attempt_at, created_at, updated_at
+            FROM replication_job
+            WHERE status = 'pending' AND next_attempt_at <= unixepoch()
+            ORDER BY id
+            LIMIT $1
+            "#,
+            limit
+        )
+        .fetch_all(&self.db_ro)
+        .await
+    }
+
+    /// SELECT * FROM replication_job [WHERE status=$1] ORDER BY id DESC LIMIT $2
+    pub async fn list(
+        &self,
+        status: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ReplicationJob>, sqlx::Error> {
+        sqlx::query_as!(
+            ReplicationJob,
+            r#"
+            SELECT id, repo, reference, manifest_digest, target, status, attempts,
+                last_error, next_attempt_at, created_at, updated_at
+            FROM replication_job
+            WHERE $1 IS NULL OR status = $1
+            ORDER BY id DESC
+            LIMIT $2
+            "#,
+            status,
+            limit
+        )
+        .fetch_all(&self.db_ro)
+        .await
+    }
+
+    /// UPDATE replication_job SET status='done', attempts=attempts+1, last_error=NULL WHERE id=$1
+    pub async fn mark_done(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE replication_job
+            SET status = 'done', attempts = attempts + 1, last_error = NULL,
+                updated_at = unixepoch()
+            WHERE id = $1
+            "#,
+            id
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(())
+    }
+
+    /// UPDATE replication_job SET attempts=attempts+1, last_error=$2, next_attempt_at=unixepoch()+$3 WHERE id=$1
+    pub async fn retry_later(
+        &self,
+        id: i64,
+        error: &str,
+        delay_secs: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE replication_job
+            SET attempts = attempts + 1, last_error = $2,
+                next_attempt_at = unixepoch() + $3, updated_at = unixepoch()
+            WHERE id = $1
+            "#,
+            id,
+            error,
+            delay_secs
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(())
+    }
+
+    /// UPDATE replication_job SET status='failed', attempts=attempts+1, last_error=$2 WHERE id=$1
+    pub async fn mark_failed(&self, id: i64, error: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE replication_job
+            SET status = 'failed', attempts = attempts + 1, last_error = $2,
+                updated_at = unixepoch()
+            WHERE id = $1
+            "#,
+            id,
+            error
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(())
+    }
+}
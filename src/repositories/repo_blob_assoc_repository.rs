@@ -54,6 +54,18 @@ impl RepoBlobAssocRepository {
         Ok(matches!(res, Some(Some(_digest))))
     }
 
+    /// SELECT EXISTS(SELECT 1 FROM repo_blob_assoc WHERE repo_name = $1)
+    pub async fn repo_exists(&self, repo_name: &str) -> Result<bool, sqlx::Error> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM repo_blob_assoc WHERE repo_name = $1)"#,
+            repo_name
+        )
+        .fetch_one(&self.db_ro)
+        .await?;
+
+        Ok(exists == 1)
+    }
+
     /// SELECT EXISTS(SELECT 1 FROM repo_blob_assoc WHERE manifest_digest = $1 AND repo_name = $2)
     pub async fn manifest_exists_in_repo(
         &self,
@@ -157,6 +169,24 @@ impl RepoBlobAssocRepository {
         Ok(())
     }
 
+    /// DELETE FROM repo_blob_assoc WHERE repo_name = $1 AND blob_digest = $2
+    ///
+    /// Returns `false` if the blob wasn't associated with the repo.
+    pub async fn delete_blob_assoc(
+        &self,
+        repo_name: &str,
+        blob_digest: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let res = sqlx::query!(
+            "DELETE FROM repo_blob_assoc WHERE repo_name = $1 AND blob_digest = $2",
+            repo_name,
+            blob_digest
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
     /// SELECT COUNT(*) FROM repo_blob_assoc WHERE manifest_digest = $1
     pub async fn count_manifest_assoc(&self, manifest_digest: &str) -> Result<i64, sqlx::Error> {
         sqlx::query_scalar!(
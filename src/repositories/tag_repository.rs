@@ -1,5 +1,7 @@
 use sqlx::SqlitePool;
 
+use super::models::Tag;
+
 pub struct TagRepository {
     db_ro: SqlitePool,
     db_rw: SqlitePool,
@@ -31,6 +33,22 @@ impl TagRepository {
         .await
     }
 
+    /// SELECT t.repo, t.tag, t.manifest_digest, t.last_checked FROM tag t WHERE t.repo = $1 AND t.tag = $2
+    pub async fn find(&self, repo: &str, tag: &str) -> Result<Option<Tag>, sqlx::Error> {
+        sqlx::query_as!(
+            Tag,
+            r#"
+            SELECT t.repo, t.tag, t.manifest_digest, t.last_checked
+            FROM tag t
+            WHERE t.repo = $1 AND t.tag = $2
+            "#,
+            repo,
+            tag
+        )
+        .fetch_optional(&self.db_ro)
+        .await
+    }
+
     /// SELECT t.tag FROM tag t WHERE t.repo = $1 AND t.tag > $2 ORDER BY ... LIMIT $3
     pub async fn list(
         &self,
@@ -55,7 +73,7 @@ impl TagRepository {
         .await
     }
 
-    /// INSERT INTO tag VALUES ($1, $2, $3) ON CONFLICT (repo, tag) DO UPDATE SET manifest_digest = EXCLUDED.manifest_digest
+    /// INSERT INTO tag (tag, repo, manifest_digest) VALUES ($1, $2, $3) ON CONFLICT (repo, tag) DO UPDATE SET manifest_digest = EXCLUDED.manifest_digest
     /// Note: first param is tag name, second is repo, third is digest (matches original SQL order)
     pub async fn upsert(
         &self,
@@ -65,7 +83,7 @@ impl TagRepository {
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
-            INSERT INTO tag
+            INSERT INTO tag (tag, repo, manifest_digest)
             VALUES ($1, $2, $3)
             ON CONFLICT (repo, tag) DO UPDATE
                 SET manifest_digest = EXCLUDED.manifest_digest
@@ -79,6 +97,34 @@ impl TagRepository {
         Ok(())
     }
 
+    /// UPDATE tag SET last_checked = unixepoch() WHERE repo = $1 AND tag = $2
+    pub async fn mark_checked(&self, repo: &str, tag: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE tag SET last_checked = unixepoch() WHERE repo = $1 AND tag = $2",
+            repo,
+            tag
+        )
+        .execute(&self.db_rw)
+        .await?;
+        Ok(())
+    }
+
+    /// SELECT EXISTS(SELECT 1 FROM tag WHERE repo = $1 AND manifest_digest = $2)
+    pub async fn manifest_is_tagged(
+        &self,
+        repo: &str,
+        manifest_digest: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM tag WHERE repo = $1 AND manifest_digest = $2) as "exists!: bool""#,
+            repo,
+            manifest_digest
+        )
+        .fetch_one(&self.db_ro)
+        .await?;
+        Ok(exists)
+    }
+
     /// DELETE FROM tag WHERE repo = $1 AND tag = $2
     pub async fn delete(&self, repo: &str, tag: &str) -> Result<(), sqlx::Error> {
         sqlx::query!(r#"DELETE FROM tag WHERE repo = $1 AND tag = $2"#, repo, tag)
@@ -0,0 +1,96 @@
+//! Administration endpoints, under `/admin`.
+
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::routing::get;
+use serde_derive::Deserialize;
+
+use crate::TrowServerState;
+use crate::repositories::models::ReplicationJob;
+use crate::routes::response::OciJson;
+use crate::routes::response::errors::Error;
+use crate::routes::response::trow_token::TrowToken;
+use crate::services::blob_upload_service::UploadSummary;
+use crate::services::prefetch_service::PrefetchStatus;
+use crate::services::proxy_service::upstreams::UpstreamStatus;
+
+#[derive(Debug, Deserialize)]
+pub struct UploadsQuery {
+    repo: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicationQuery {
+    status: Option<String>,
+    n: Option<i64>,
+}
+
+/*
+GET /admin/uploads?repo=<name>
+Lists in-progress uploads (all repositories unless `repo` is given),
+with the number of bytes received, age and owner of each.
+*/
+async fn list_uploads(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Query(query): Query<UploadsQuery>,
+) -> Result<OciJson<Vec<UploadSummary>>, Error> {
+    let uploads = state
+        .services
+        .blob_upload
+        .list_uploads(query.repo.as_deref())
+        .await?;
+    Ok(OciJson::new(&uploads))
+}
+
+/*
+GET /admin/upstreams
+Lists the upstream registries that failed since startup, with their circuit
+state (closed, open or half_open) and when they'll be called again.
+*/
+async fn list_upstreams(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+) -> OciJson<Vec<UpstreamStatus>> {
+    OciJson::new(&state.services.proxy.upstream_status())
+}
+
+/*
+GET /admin/prefetch
+Lists the images of the prefetch config with the outcome of their last
+download: the digest, or the error.
+*/
+async fn list_prefetch(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+) -> OciJson<Vec<PrefetchStatus>> {
+    OciJson::new(&state.services.prefetch.status())
+}
+
+/*
+GET /admin/replication?status=<pending|done|failed>&n=<count>
+Lists the latest replication jobs (100 unless `n` is given), with their
+target, status, number of attempts and last error.
+*/
+async fn list_replication(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Query(query): Query<ReplicationQuery>,
+) -> Result<OciJson<Vec<ReplicationJob>>, Error> {
+    let jobs = state
+        .services
+        .replication
+        .jobs(query.status.as_deref(), query.n.unwrap_or(100))
+        .await?;
+    Ok(OciJson::new(&jobs))
+}
+
+pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerState>> {
+    app = app.route("/admin/uploads", get(list_uploads));
+    app = app.route("/admin/upstreams", get(list_upstreams));
+    app = app.route("/admin/prefetch", get(list_prefetch));
+    app = app.route("/admin/replication", get(list_replication));
+    app
+}
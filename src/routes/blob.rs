@@ -1,50 +1,100 @@
-use std::pin::Pin;
 use std::sync::Arc;
 
 use axum::Router;
 use axum::extract::{Path, Query, State};
+use axum::http::{Method, header};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use tokio::io::AsyncRead;
 
 use super::macros::endpoint_fn_7_levels;
 use crate::TrowServerState;
-use crate::routes::extracts::ImageNamespace;
+use crate::routes::extracts::{BlobRange, ImageNamespace};
 use crate::routes::macros::route_7_levels;
 use crate::routes::response::errors::Error;
 use crate::routes::response::trow_token::TrowToken;
-use crate::services::blob_service::BlobReader;
+use crate::services;
+use crate::types::BlobDeleted;
 use crate::utils::digest::Digest;
 
 /*
 ---
 Pulling a Layer
 GET /v2/<name>/blobs/<digest>
+HEAD requests don't follow the mirror mode rewrite
 name - name of the repository
 digest - unique identifier for the blob to be downloaded
 # Responses
 200 - blob is downloaded
+206 - the requested `Range` of the blob is downloaded
 307 - redirect to another service for downloading (docker API, not OCI)
+416 - the requested `Range` cannot be satisfied
  */
 async fn get_blob(
     _auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
+    method: Method,
     Path((repo, digest)): Path<(String, Digest)>,
     Query(query): Query<ImageNamespace>,
-) -> Result<BlobReader<Pin<Box<dyn AsyncRead + Send>>>, Error> {
-    Ok(state
-        .services
-        .blob
-        .get_blob(repo, digest, query.ns.as_deref())
-        .await?)
+    BlobRange(range): BlobRange,
+) -> Result<Response, Error> {
+    let blobs = &state.services.blob;
+    let reader = if method == Method::HEAD {
+        blobs.head_blob(repo, digest, query.ns.as_deref()).await
+    } else {
+        blobs
+            .get_blob(repo, digest, query.ns.as_deref(), range)
+            .await
+    };
+    match reader {
+        Ok(reader) => Ok(reader.into_response()),
+        Err(services::Error::UnsatisfiableRange(size)) => {
+            let mut resp = Error::UnsatisfiableRange.into_response();
+            resp.headers_mut().insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{size}").parse().unwrap(),
+            );
+            Ok(resp)
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
 endpoint_fn_7_levels!(
     get_blob(
         auth_user: TrowToken,
-        state: State<Arc<TrowServerState>>;
+        state: State<Arc<TrowServerState>>,
+        method: Method;
         path: [image_name, digest: Digest],
-        query: Query<ImageNamespace>
-    ) -> Result<BlobReader<Pin<Box<dyn AsyncRead + Send>>>, Error>
+        query: Query<ImageNamespace>,
+        range: BlobRange
+    ) -> Result<Response, Error>
+);
+
+/*
+---
+Deleting a Layer
+DELETE /v2/<name>/blobs/<digest>
+# Responses
+202 - blob removed from the repository
+404 - blob unknown to the repository
+405 - repository is proxied
+409 - blob is still referenced by a manifest of the repository, error code
+      UNSUPPORTED with the referencing manifests in the detail
+ */
+async fn delete_blob(
+    _auth_user: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((repo, digest)): Path<(String, Digest)>,
+) -> Result<BlobDeleted, Error> {
+    Ok(state.services.blob.delete_blob(&repo, &digest).await?)
+}
+
+endpoint_fn_7_levels!(
+    delete_blob(
+        auth_user: TrowToken,
+        state: State<Arc<TrowServerState>>;
+        path: [image_name, digest: Digest]
+    ) -> Result<BlobDeleted, Error>
 );
 
 pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerState>> {
@@ -52,7 +102,8 @@ pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerStat
     route_7_levels!(
         app,
         "/v2" "/blobs/{digest}",
-        get(get_blob, get_blob_2level, get_blob_3level, get_blob_4level, get_blob_5level, get_blob_6level, get_blob_7level)
+        get(get_blob, get_blob_2level, get_blob_3level, get_blob_4level, get_blob_5level, get_blob_6level, get_blob_7level),
+        delete(delete_blob, delete_blob_2level, delete_blob_3level, delete_blob_4level, delete_blob_5level, delete_blob_6level, delete_blob_7level)
     );
     app
 }
@@ -13,7 +13,7 @@ use crate::routes::response::content_info::ContentInfo;
 use crate::routes::response::errors::Error;
 use crate::routes::response::trow_token::TrowToken;
 use crate::routes::response::upload_info::UploadInfo;
-use crate::types::{AcceptedUpload, DigestQuery, OptionalDigestQuery, Upload};
+use crate::types::{AcceptedUpload, DigestQuery, MountQuery, OptionalDigestQuery, Upload};
 use crate::{PROXY_DIR, TrowServerState};
 
 /*
@@ -27,7 +27,7 @@ Content-Type: application/octet-stream
 Completes the upload.
 */
 async fn put_blob_upload(
-    _auth_user: TrowToken,
+    auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     Path((repo, uuid)): Path<(String, uuid::Uuid)>,
     Query(digest): Query<DigestQuery>,
@@ -41,7 +41,14 @@ async fn put_blob_upload(
     Ok(state
         .services
         .blob_upload
-        .complete_upload(&repo, &uuid_str, &digest.digest, chunk, None)
+        .complete_upload(
+            &repo,
+            &auth_user.user,
+            &uuid_str,
+            &digest.digest,
+            chunk,
+            None,
+        )
         .await?)
 }
 
@@ -68,7 +75,7 @@ Uploads a blob or chunk of a blob.
 Checks UUID. Returns UploadInfo with range set to correct position.
 */
 async fn patch_blob_upload(
-    _auth_user: TrowToken,
+    auth_user: TrowToken,
     content_info: Option<ContentInfo>,
     State(state): State<Arc<TrowServerState>>,
     Path((repo, uuid)): Path<(String, uuid::Uuid)>,
@@ -78,7 +85,7 @@ async fn patch_blob_upload(
     Ok(state
         .services
         .blob_upload
-        .patch_upload(repo, uuid, content_range, chunk)
+        .patch_upload(repo, &auth_user.user, uuid, content_range, chunk)
         .await?)
 }
 
@@ -98,18 +105,34 @@ Starting point for uploading a new image or new version of an image.
 We respond with details of location and UUID to upload to with patch/put.
 No data is being transferred _unless_ the request ends with "?digest".
 In this case the whole blob is attached.
+
+POST /v2/<name>/blobs/uploads/?mount=<digest>&from=<repository name>
+Mounts a blob from another repository: 201 Created if the blob is known in
+`from`, otherwise this falls back to starting a regular upload (202).
+Any authenticated user can read every repository, so no further access check
+is done on `from`.
 */
 async fn post_blob_upload(
-    _auth_user: TrowToken,
+    auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     Query(digest): Query<OptionalDigestQuery>,
+    Query(mount): Query<MountQuery>,
     Path(repo_name): Path<String>,
     data: Body,
 ) -> Result<Upload, Error> {
+    if let (Some(mount_digest), Some(from)) = (mount.mount, mount.from)
+        && let Some(mounted) = state
+            .services
+            .blob_upload
+            .mount_blob(&repo_name, &mount_digest, &from)
+            .await?
+    {
+        return Ok(Upload::Mounted(mounted));
+    }
     Ok(state
         .services
         .blob_upload
-        .start_upload(repo_name, digest.digest, data)
+        .start_upload(repo_name, &auth_user.user, digest.digest, data)
         .await?)
 }
 
@@ -117,7 +140,8 @@ endpoint_fn_7_levels!(
     post_blob_upload(
         auth_user: TrowToken,
         state: State<Arc<TrowServerState>>,
-        digest: Query<OptionalDigestQuery>;
+        digest: Query<OptionalDigestQuery>,
+        mount: Query<MountQuery>;
         path: [image_name],
         data: Body
     ) -> Result<Upload, Error>
@@ -127,14 +151,14 @@ endpoint_fn_7_levels!(
 GET /v2/<name>/blobs/uploads/<upload_id>
 */
 async fn get_blob_upload(
-    _auth: TrowToken,
+    auth: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     Path((repo_name, upload_id)): Path<(String, uuid::Uuid)>,
 ) -> Result<Response, Error> {
     let status = state
         .services
         .blob_upload
-        .get_upload_status(repo_name.clone(), upload_id)
+        .get_upload_status(repo_name.clone(), &auth.user, upload_id)
         .await?;
     let location = format!("/v2/{}/blobs/uploads/{}", status.repo, status.uuid);
 
@@ -159,6 +183,31 @@ endpoint_fn_7_levels!(
     ) -> Result<Response, Error>
 );
 
+/*
+DELETE /v2/<name>/blobs/uploads/<upload_id>
+Cancels an upload, its data is discarded.
+*/
+async fn delete_blob_upload(
+    auth: TrowToken,
+    State(state): State<Arc<TrowServerState>>,
+    Path((repo_name, upload_id)): Path<(String, uuid::Uuid)>,
+) -> Result<StatusCode, Error> {
+    state
+        .services
+        .blob_upload
+        .cancel_upload(&repo_name, &auth.user, upload_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+endpoint_fn_7_levels!(
+    delete_blob_upload(
+        auth: TrowToken,
+        state: State<Arc<TrowServerState>>;
+        path: [image_name, upload_id: uuid::Uuid]
+    ) -> Result<StatusCode, Error>
+);
+
 pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerState>> {
     #[rustfmt::skip]
     route_7_levels!(
@@ -172,7 +221,8 @@ pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerStat
         "/v2" "/blobs/uploads/{uuid}",
         put(put_blob_upload, put_blob_upload_2level, put_blob_upload_3level, put_blob_upload_4level, put_blob_upload_5level, put_blob_upload_6level, put_blob_upload_7level),
         patch(patch_blob_upload, patch_blob_upload_2level, patch_blob_upload_3level, patch_blob_upload_4level, patch_blob_upload_5level, patch_blob_upload_6level, patch_blob_upload_7level),
-        get(get_blob_upload, get_blob_upload_2level, get_blob_upload_3level, get_blob_upload_4level, get_blob_upload_5level, get_blob_upload_6level, get_blob_upload_7level)
+        get(get_blob_upload, get_blob_upload_2level, get_blob_upload_3level, get_blob_upload_4level, get_blob_upload_5level, get_blob_upload_6level, get_blob_upload_7level),
+        delete(delete_blob_upload, delete_blob_upload_2level, delete_blob_upload_3level, delete_blob_upload_4level, delete_blob_upload_5level, delete_blob_upload_6level, delete_blob_upload_7level)
     );
     app
 }
@@ -189,6 +239,7 @@ mod tests {
     use uuid::Uuid;
 
     use super::*;
+    use crate::storage::into_blob_stream;
     use crate::test_utilities::{self, resp_header};
     use crate::utils::digest::Digest;
 
@@ -201,6 +252,7 @@ mod tests {
             TrowToken::default(),
             State(state.clone()),
             Query(OptionalDigestQuery::default()),
+            Query(MountQuery::default()),
             Path("test/blobs".to_owned()),
             Body::empty(),
         )
@@ -319,7 +371,11 @@ mod tests {
         state
             .services
             .storage()
-            .write_blob_part_stream(&upload_uuid, Body::from("whazaaa").into_data_stream(), None)
+            .write_blob_part_stream(
+                &upload_uuid,
+                into_blob_stream(Body::from("whazaaa").into_data_stream()),
+                None,
+            )
             .await
             .unwrap();
 
@@ -2,19 +2,65 @@ use std::sync::Arc;
 
 use axum::RequestPartsExt;
 use axum::extract::{FromRef, FromRequestParts};
-use axum::http::StatusCode;
 use axum::http::request::Parts;
+use axum::http::{StatusCode, header};
 use axum_extra::TypedHeader;
 use axum_extra::headers::Host;
 use serde::Deserialize;
 
 use crate::TrowServerState;
+use crate::routes::response::errors::Error;
+use crate::services::blob_service::ByteRange;
 
 #[derive(Deserialize)]
 pub struct ImageNamespace {
     pub ns: Option<String>,
 }
 
+/// Optional `Range` header of a blob request
+pub struct BlobRange(pub Option<ByteRange>);
+
+impl<S: Send + Sync> FromRequestParts<S> for BlobRange {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(header) = parts.headers.get(header::RANGE) else {
+            return Ok(Self(None));
+        };
+        let Ok(header) = header.to_str() else {
+            return Ok(Self(None));
+        };
+        ByteRange::parse(header)
+            .map(Self)
+            .map_err(|_| Error::UnsatisfiableRange)
+    }
+}
+
+/// Media types listed in the `Accept` headers, without parameters.
+/// Empty if the client accepts anything.
+pub struct AcceptedMediaTypes(pub Vec<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for AcceptedMediaTypes {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let media_types: Vec<String> = parts
+            .headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|mt| mt.split(';').next())
+            .map(|mt| mt.trim().to_ascii_lowercase())
+            .filter(|mt| !mt.is_empty())
+            .collect();
+        if media_types.iter().any(|mt| mt == "*/*") {
+            return Ok(Self(Vec::new()));
+        }
+        Ok(Self(media_types))
+    }
+}
+
 pub struct AlwaysHost(pub String);
 
 impl<S> FromRequestParts<S> for AlwaysHost
@@ -9,7 +9,7 @@ use super::extracts::AlwaysHost;
 use super::macros::endpoint_fn_7_levels;
 use super::response::OciJson;
 use crate::TrowServerState;
-use crate::routes::extracts::ImageNamespace;
+use crate::routes::extracts::{AcceptedMediaTypes, ImageNamespace};
 use crate::routes::macros::route_7_levels;
 use crate::routes::response::errors::Error;
 use crate::routes::response::trow_token::TrowToken;
@@ -21,11 +21,12 @@ async fn get_manifest(
     State(state): State<Arc<TrowServerState>>,
     Path((repo, raw_reference)): Path<(String, String)>,
     Query(query): Query<ImageNamespace>,
+    AcceptedMediaTypes(accept): AcceptedMediaTypes,
 ) -> Result<OciJson<OCIManifest>, Error> {
     let payload = state
         .services
         .manifest
-        .get_manifest(repo, raw_reference, query.ns.as_deref())
+        .get_manifest(repo, raw_reference, query.ns.as_deref(), &accept)
         .await?;
     Ok(OciJson::new_raw(payload.bytes)
         .set_digest(payload.digest)
@@ -37,7 +38,8 @@ endpoint_fn_7_levels!(
         auth_user: TrowToken,
         state: State<Arc<TrowServerState>>;
         path: [image_name, reference: String],
-        ns: Query<ImageNamespace>
+        ns: Query<ImageNamespace>,
+        accept: AcceptedMediaTypes
     ) -> Result<OciJson<OCIManifest>, Error>
 );
 
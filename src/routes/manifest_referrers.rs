@@ -1,9 +1,9 @@
 use std::sync::Arc;
 
 use axum::Router;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
-use oci_spec::image::ImageIndex;
 
 use super::macros::endpoint_fn_7_levels;
 use super::response::OciJson;
@@ -11,6 +11,7 @@ use crate::TrowServerState;
 use crate::routes::macros::route_7_levels;
 use crate::routes::response::errors::Error;
 use crate::routes::response::trow_token::TrowToken;
+use crate::types::ArtifactTypeQuery;
 
 /*
 ---
@@ -20,28 +21,36 @@ GET /v2/<name>/referrers/<digest>
 name - The namespace of the repository
 digest - The digest of the manifest specified in the subject field.
 # Query Parameters
-(TODO) artifactType: The type of artifact to list referrers for.
+artifactType: The type of artifact to list referrers for.
+  When given, the response has the header `OCI-Filters-Applied: artifactType`.
  */
 async fn get_referrers(
     _auth_user: TrowToken,
     State(state): State<Arc<TrowServerState>>,
     Path((repo, digest)): Path<(String, String)>,
-) -> Result<OciJson<ImageIndex>, Error> {
+    Query(query): Query<ArtifactTypeQuery>,
+) -> Result<Response, Error> {
     let index = state
         .services
         .referrers
-        .list_referrers(repo, digest)
+        .list_referrers(repo, digest, query.artifact_type.as_deref())
         .await?;
     let content_type = index.media_type().as_ref().unwrap().as_ref();
-    Ok(OciJson::new(&index).set_content_type(content_type))
+    let json = OciJson::new(&index).set_content_type(content_type);
+    if query.artifact_type.is_some() {
+        Ok(([("OCI-Filters-Applied", "artifactType")], json).into_response())
+    } else {
+        Ok(json.into_response())
+    }
 }
 
 endpoint_fn_7_levels!(
     get_referrers(
         auth_user: TrowToken,
         state: State<Arc<TrowServerState>>;
-        path: [image_name, reference: String]
-    ) -> Result<OciJson<ImageIndex>, Error>
+        path: [image_name, reference: String],
+        query: Query<ArtifactTypeQuery>
+    ) -> Result<Response, Error>
 );
 
 pub fn route(mut app: Router<Arc<TrowServerState>>) -> Router<Arc<TrowServerState>> {
@@ -132,6 +141,7 @@ mod tests {
         }
 
         let resp = router
+            .clone()
             .oneshot(
                 Request::get(format!("/v2/test/referrers/{man_referred_digest}"))
                     .body(Body::empty())
@@ -144,6 +154,7 @@ mod tests {
             StatusCode::OK,
             "unexpected status code: {resp:?}"
         );
+        assert!(resp.headers().get("OCI-Filters-Applied").is_none());
         let val: ImageIndex = test_utilities::response_body_json(resp).await;
         let descriptors = val.manifests();
         assert_eq!(descriptors.len(), 1);
@@ -152,5 +163,33 @@ mod tests {
             MediaType::ImageManifest
         );
         assert_eq!(descriptors[0].digest().as_ref(), subj_digest.as_str());
+        assert_eq!(
+            descriptors[0].artifact_type().clone(),
+            Some(MediaType::EmptyJSON)
+        );
+
+        for (artifact_type, expected) in [
+            ("application/vnd.oci.empty.v1%2Bjson", 1),
+            ("application/x-other", 0),
+        ] {
+            let resp = router
+                .clone()
+                .oneshot(
+                    Request::get(format!(
+                        "/v2/test/referrers/{man_referred_digest}?artifactType={artifact_type}"
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers().get("OCI-Filters-Applied").unwrap(),
+                "artifactType"
+            );
+            let val: ImageIndex = test_utilities::response_body_json(resp).await;
+            assert_eq!(val.manifests().len(), expected, "filter {artifact_type}");
+        }
     }
 }
@@ -1,4 +1,5 @@
 // routes
+mod admin;
 mod admission;
 mod blob;
 mod blob_upload;
@@ -127,6 +128,7 @@ pub fn create_app(state: Arc<super::TrowServerState>) -> Router {
     app = manifest::route(app);
     app = manifest_referrers::route(app);
     app = admission::route(app);
+    app = admin::route(app);
 
     app = add_router_layers(app, &state.config.cors);
     app.with_state(state)
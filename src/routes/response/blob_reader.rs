@@ -1,5 +1,5 @@
 use axum::body;
-use axum::http::header;
+use axum::http::{StatusCode, header};
 use axum::response::{IntoResponse, Response};
 use tokio::io::AsyncRead;
 use tokio_util::codec::{BytesCodec, FramedRead};
@@ -10,13 +10,22 @@ impl<S: AsyncRead + Send + 'static> IntoResponse for BlobReader<S> {
     fn into_response(self) -> Response {
         let digest = self.digest().to_string();
         let size = self.blob_size();
+        let range = self.range().cloned();
         let reader = self.get_reader();
         let stream = FramedRead::new(*reader, BytesCodec::new());
 
-        Response::builder()
+        let mut builder = Response::builder()
             .header(header::CONTENT_TYPE, "application/octet-stream")
             .header(header::CONTENT_LENGTH, size)
-            .header("Docker-Content-Digest", digest)
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header("Docker-Content-Digest", digest);
+        if let Some((range, total_size)) = range {
+            builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{total_size}", range.start(), range.end()),
+            );
+        }
+        builder
             .body(body::Body::from_stream(stream))
             .unwrap()
             .into_response()
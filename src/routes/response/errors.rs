@@ -6,7 +6,7 @@ use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::file_storage::StorageBackendError;
+use crate::storage::StorageBackendError;
 use crate::utils::digest::DigestError;
 
 #[derive(Debug)]
@@ -15,8 +15,11 @@ pub enum Error {
     BlobUploadInvalid(String),
     ManifestUnknown(String),
     ManifestInvalid(String),
+    ManifestBlobUnknown(String),
+    SizeInvalid(String),
     Unauthorized,
     BlobUnknown,
+    BlobInUse(Vec<String>),
     BlobUploadUnknown,
     Unsupported,
     Internal,
@@ -24,6 +27,8 @@ pub enum Error {
     NotFound,
     UnsupportedForProxiedRepo,
     UnsatisfiableRange,
+    /// Upstream registry of a proxied repo unavailable, retry after the given seconds
+    UpstreamUnavailable(u64),
 }
 
 // Create ErrorMsg struct that serializes to json of appropriate type
@@ -48,6 +53,12 @@ impl fmt::Display for Error {
                 format_error_json(f, "UNAUTHORIZED", "Authorization required", None)
             }
             Error::BlobUnknown => format_error_json(f, "BLOB_UNKNOWN", "Blob Unknown", None),
+            Error::BlobInUse(ref manifests) => format_error_json(
+                f,
+                "UNSUPPORTED",
+                "Blob is referenced by manifests of the repository",
+                Some(json!({ "Manifests": manifests })),
+            ),
             Error::BlobUploadUnknown => write!(f, "Blob Upload Unknown"),
             Error::BlobUploadInvalid(ref detail) => format_error_json(
                 f,
@@ -71,6 +82,18 @@ impl fmt::Display for Error {
                 "Manifest invalid",
                 Some(json!({ "detail": detail })),
             ),
+            Error::ManifestBlobUnknown(ref digest) => format_error_json(
+                f,
+                "MANIFEST_BLOB_UNKNOWN",
+                "Manifest references a manifest or blob unknown to registry",
+                Some(json!({ "digest": digest })),
+            ),
+            Error::SizeInvalid(ref detail) => format_error_json(
+                f,
+                "SIZE_INVALID",
+                "Provided length did not match content length",
+                Some(json!({ "detail": detail })),
+            ),
             Error::ManifestUnknown(ref tag) => format_error_json(
                 f,
                 "MANIFEST_UNKNOWN",
@@ -90,6 +113,12 @@ impl fmt::Display for Error {
                 "The range specified in the request header cannot be satisfied by the current blob.",
                 None,
             ),
+            Error::UpstreamUnavailable(retry_after) => format_error_json(
+                f,
+                "UNAVAILABLE",
+                "The upstream registry is unavailable.",
+                Some(json!({ "RetryAfter": retry_after })),
+            ),
         }
     }
 }
@@ -144,14 +173,21 @@ impl IntoResponse for Error {
             }
             Error::Internal => StatusCode::INTERNAL_SERVER_ERROR,
             Error::BlobUploadInvalid(_) => StatusCode::RANGE_NOT_SATISFIABLE,
-            Error::DigestInvalid | Error::ManifestInvalid(_) | Error::NameInvalid(_) => {
-                StatusCode::BAD_REQUEST
-            }
+            Error::DigestInvalid
+            | Error::ManifestInvalid(_)
+            | Error::ManifestBlobUnknown(_)
+            | Error::SizeInvalid(_)
+            | Error::NameInvalid(_) => StatusCode::BAD_REQUEST,
             Error::NotFound => StatusCode::NOT_FOUND,
             Error::UnsatisfiableRange => StatusCode::RANGE_NOT_SATISFIABLE,
+            Error::BlobInUse(_) => StatusCode::CONFLICT,
+            Error::UpstreamUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
         };
-        Response::builder()
-            .header(header::CONTENT_TYPE, "application/json")
+        let mut resp = Response::builder();
+        if let Error::UpstreamUnavailable(retry_after) = self {
+            resp = resp.header(header::RETRY_AFTER, retry_after);
+        }
+        resp.header(header::CONTENT_TYPE, "application/json")
             .header(header::CONTENT_LENGTH, json.len())
             .status(status)
             .body(body::Body::from(json))
@@ -178,6 +214,7 @@ impl From<StorageBackendError> for Error {
         match err {
             StorageBackendError::BlobNotFound(_) => Self::BlobUnknown,
             StorageBackendError::InvalidContentRange => Self::UnsatisfiableRange,
+            StorageBackendError::InvalidDigest => Self::DigestInvalid,
             _ => Self::Internal,
         }
     }
@@ -199,6 +236,10 @@ impl From<crate::services::proxy_service::errors::DownloadRemoteImageError> for
             crate::services::proxy_service::errors::DownloadRemoteImageError::OciClientError(
                 OciDistributionError::ImageManifestNotFoundError(_),
             ) => Self::ManifestUnknown(err.to_string()),
+            crate::services::proxy_service::errors::DownloadRemoteImageError::UpstreamUnavailable {
+                retry_after_secs,
+                ..
+            } => Self::UpstreamUnavailable(*retry_after_secs),
             _ => {
                 tracing::error!("Error(DownloadRemoteImageError): {err}");
                 Self::Internal
@@ -216,7 +257,12 @@ impl From<crate::services::Error> for Error {
             S::UnsupportedForProxiedRepo => Error::UnsupportedForProxiedRepo,
             S::ManifestInvalid(s) => Error::ManifestInvalid(s),
             S::ManifestUnknown(s) => Error::ManifestUnknown(s),
+            S::ManifestBlobUnknown(s) => Error::ManifestBlobUnknown(s),
+            S::SizeInvalid(s) => Error::SizeInvalid(s),
+            S::BlobUnknown => Error::BlobUnknown,
+            S::BlobInUse(manifests) => Error::BlobInUse(manifests),
             S::BlobUploadUnknown => Error::BlobUploadUnknown,
+            S::UnsatisfiableRange(_) => Error::UnsatisfiableRange,
             S::Db(sqlx::Error::RowNotFound) => Error::NotFound,
             S::Db(e) => {
                 tracing::error!("Error(DbErr): {e}");
@@ -7,6 +7,7 @@ pub mod errors;
 pub mod health;
 pub mod html;
 pub mod manifest_deleted;
+pub mod mounted_blob;
 pub mod readiness;
 pub mod tag_list;
 pub mod trow_token;
@@ -0,0 +1,18 @@
+use axum::body;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+use crate::types::MountedBlob;
+
+impl IntoResponse for MountedBlob {
+    fn into_response(self) -> Response {
+        let location = format!("/v2/{}/blobs/{}", self.repo_name(), self.digest());
+        Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Location", location)
+            .header("Docker-Content-Digest", self.digest().to_string())
+            .header("Content-Length", "0")
+            .body(body::Body::empty())
+            .unwrap()
+    }
+}
@@ -7,6 +7,7 @@ impl IntoResponse for Upload {
         match self {
             Upload::Info(info) => info.into_response(),
             Upload::Accepted(accepted) => accepted.into_response(),
+            Upload::Mounted(mounted) => mounted.into_response(),
         }
     }
 }
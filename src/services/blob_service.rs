@@ -1,19 +1,78 @@
+use std::ops::RangeInclusive;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use tokio::io::AsyncRead;
 
-use crate::file_storage::FileStorage;
 use crate::repositories::Repositories;
 use crate::services::Error;
-use crate::types::BoundedStream;
+use crate::services::proxy_service::ProxyService;
+use crate::services::proxy_service::errors::DownloadRemoteImageError;
+use crate::storage::{StorageBackend, StorageBackendError};
+use crate::types::{BlobDeleted, BoundedStream};
 use crate::utils::digest::Digest;
 use crate::utils::resolve_reference::parse_reference;
+use crate::{PROXY_DIR, TrowConfig};
+
+/// A single byte range requested with a `Range: bytes=...` header,
+/// not yet checked against the size of the blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=<start>-` or `bytes=<start>-<end>`
+    From(u64, Option<u64>),
+    /// `bytes=-<len>`: the last `len` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a `Range` header value.
+    /// Returns `Ok(None)` for values that should be ignored (other units,
+    /// malformed ranges) and an error for multiple ranges, which we don't serve.
+    pub fn parse(header: &str) -> Result<Option<Self>, Error> {
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return Ok(None);
+        };
+        if spec.contains(',') {
+            return Err(Error::Invalid(
+                "Multiple ranges are not supported".to_string(),
+            ));
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Ok(None);
+        };
+        let range = match (start.parse::<u64>(), end) {
+            (Ok(start), "") => Self::From(start, None),
+            (Ok(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => Self::From(start, Some(end)),
+                _ => return Ok(None),
+            },
+            (Err(_), end) if start.is_empty() => match end.parse::<u64>() {
+                Ok(len) => Self::Suffix(len),
+                Err(_) => return Ok(None),
+            },
+            (Err(_), _) => return Ok(None),
+        };
+        Ok(Some(range))
+    }
+
+    /// Returns the inclusive range of bytes to send, or `None` if it can't be satisfied
+    pub fn resolve(&self, size: u64) -> Option<RangeInclusive<u64>> {
+        match *self {
+            Self::From(start, _) if start >= size => None,
+            Self::From(start, end) => Some(start..=end.map_or(size - 1, |end| end.min(size - 1))),
+            Self::Suffix(0) => None,
+            Self::Suffix(_) if size == 0 => None,
+            Self::Suffix(len) => Some(size.saturating_sub(len)..=size - 1),
+        }
+    }
+}
 
 pub struct BlobReader<S: AsyncRead + ?Sized + Send> {
     digest: Digest,
     reader: Box<S>,
     size: u64,
+    /// Range being sent (inclusive) and total size of the blob, for partial reads
+    range: Option<(RangeInclusive<u64>, u64)>,
 }
 
 impl<S: tokio::io::AsyncRead + Send> BlobReader<S> {
@@ -23,6 +82,7 @@ impl<S: tokio::io::AsyncRead + Send> BlobReader<S> {
             digest,
             reader: Box::new(file.reader()),
             size: file_size,
+            range: None,
         }
     }
 
@@ -37,6 +97,10 @@ impl<S: tokio::io::AsyncRead + Send> BlobReader<S> {
     pub fn blob_size(&self) -> u64 {
         self.size
     }
+
+    pub fn range(&self) -> Option<&(RangeInclusive<u64>, u64)> {
+        self.range.as_ref()
+    }
 }
 
 impl BlobReader<Pin<Box<dyn AsyncRead + Send>>> {
@@ -45,6 +109,7 @@ impl BlobReader<Pin<Box<dyn AsyncRead + Send>>> {
             digest,
             reader: Box::new(reader),
             size: size as u64,
+            range: None,
         }
     }
 }
@@ -52,12 +117,24 @@ impl BlobReader<Pin<Box<dyn AsyncRead + Send>>> {
 #[derive(Debug)]
 pub struct BlobService {
     repos: Arc<Repositories>,
-    storage: Arc<FileStorage>,
+    storage: Arc<dyn StorageBackend>,
+    config: Arc<TrowConfig>,
+    proxy: Arc<ProxyService>,
 }
 
 impl BlobService {
-    pub fn new(repos: Arc<Repositories>, storage: Arc<FileStorage>) -> Self {
-        Self { repos, storage }
+    pub fn new(
+        repos: Arc<Repositories>,
+        storage: Arc<dyn StorageBackend>,
+        config: Arc<TrowConfig>,
+        proxy: Arc<ProxyService>,
+    ) -> Self {
+        Self {
+            repos,
+            storage,
+            config,
+            proxy,
+        }
     }
 
     pub async fn get_blob(
@@ -65,6 +142,37 @@ impl BlobService {
         mut repo: String,
         digest: Digest,
         namespace: Option<&str>,
+        range: Option<ByteRange>,
+    ) -> Result<BlobReader<Pin<Box<dyn AsyncRead + Send>>>, Error> {
+        let registry_proxies = &self.config.config_file.registry_proxies;
+        if let Some(mirrored) = self
+            .proxy
+            .mirrored_repo(registry_proxies, &repo, namespace)
+            .await?
+        {
+            repo = mirrored;
+        }
+        self.read_blob(repo, digest, namespace, range).await
+    }
+
+    /// Like `get_blob`, without the mirror mode rewrite: clients pushing to a
+    /// new repository check with HEAD requests which blobs it already has,
+    /// and must not be told about those of the mirrored repository.
+    pub async fn head_blob(
+        &self,
+        repo: String,
+        digest: Digest,
+        namespace: Option<&str>,
+    ) -> Result<BlobReader<Pin<Box<dyn AsyncRead + Send>>>, Error> {
+        self.read_blob(repo, digest, namespace, None).await
+    }
+
+    async fn read_blob(
+        &self,
+        mut repo: String,
+        digest: Digest,
+        namespace: Option<&str>,
+        range: Option<ByteRange>,
     ) -> Result<BlobReader<Pin<Box<dyn AsyncRead + Send>>>, Error> {
         let digest_str = digest.as_str();
         let blob = parse_reference(&repo, digest_str, namespace)?;
@@ -73,15 +181,135 @@ impl BlobService {
             repo = format!("f/{}/{}", blob.registry(), blob.repository());
         }
 
+        if !repo.starts_with(PROXY_DIR)
+            && !self
+                .repos
+                .repo_blob_assoc
+                .blob_belongs_to_repo(digest_str, &repo)
+                .await?
+        {
+            return Err(Error::BlobUnknown);
+        }
+        if repo.starts_with(PROXY_DIR) {
+            let mut in_flight = self.proxy.in_flight_blob(digest_str);
+            if in_flight.is_none() && !self.is_stored(digest_str).await? {
+                // Only blobs of cached manifests are downloaded again
+                if !self
+                    .repos
+                    .manifest
+                    .blob_referenced_in_repo(digest_str, &repo)
+                    .await?
+                {
+                    return Err(Error::BlobUnknown);
+                }
+                let proxy_config = self
+                    .config
+                    .config_file
+                    .registry_proxies
+                    .registries
+                    .get_for(blob.registry(), blob.repository());
+                in_flight =
+                    self.proxy
+                        .refetch_blob(&blob, proxy_config)
+                        .await
+                        .map_err(|e| match e {
+                            Error::Proxy(e)
+                                if matches!(
+                                    *e,
+                                    DownloadRemoteImageError::UpstreamUnavailable { .. }
+                                ) =>
+                            {
+                                Error::Proxy(e)
+                            }
+                            e => {
+                                tracing::warn!("Could not download missing blob {blob}: {e}");
+                                Error::BlobUnknown
+                            }
+                        })?;
+            }
+            if let Some(in_flight) = in_flight {
+                // Still downloading: follow the download
+                let total_size = in_flight.size();
+                let Some(range) = range else {
+                    let reader = in_flight.reader(None);
+                    return Ok(BlobReader::new_boxed(digest, total_size as usize, reader));
+                };
+                let range = range
+                    .resolve(total_size)
+                    .ok_or(Error::UnsatisfiableRange(total_size))?;
+                let size = (range.end() - range.start() + 1) as usize;
+                let reader = in_flight.reader(Some(range.clone()));
+                let mut reader = BlobReader::new_boxed(digest, size, reader);
+                reader.range = Some((range, total_size));
+                return Ok(reader);
+            }
+        }
         self.repos
             .blob
             .touch_last_accessed(digest_str, &repo)
             .await?;
 
-        let bounded = self.storage.get_blob_stream(&repo, digest_str).await?;
-        let size = bounded.size();
-        let reader: Pin<Box<dyn AsyncRead + Send>> = Box::pin(bounded.reader());
-        Ok(BlobReader::new_boxed(digest, size, reader))
+        let Some(range) = range else {
+            let bounded = self
+                .storage
+                .get_blob_stream(&repo, digest_str, None)
+                .await?;
+            let size = bounded.size();
+            return Ok(BlobReader::new_boxed(digest, size, bounded.reader()));
+        };
+
+        let total_size = self.storage.blob_size(digest_str).await?;
+        let range = range
+            .resolve(total_size)
+            .ok_or(Error::UnsatisfiableRange(total_size))?;
+        let bounded = self
+            .storage
+            .get_blob_stream(&repo, digest_str, Some(range.clone()))
+            .await?;
+        let mut reader = BlobReader::new_boxed(digest, bounded.size(), bounded.reader());
+        reader.range = Some((range, total_size));
+        Ok(reader)
+    }
+
+    /// Whether the blob is both recorded and present in the storage backend
+    async fn is_stored(&self, digest: &str) -> Result<bool, Error> {
+        if !self.repos.blob.exists(digest).await? {
+            return Ok(false);
+        }
+        match self.storage.blob_size(digest).await {
+            Ok(_) => Ok(true),
+            Err(StorageBackendError::BlobNotFound(_)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes a blob from a repository.
+    ///
+    /// Refused while a manifest of the repository references the blob.
+    /// The blob itself is left in storage: once no manifest references it,
+    /// it is picked up by `GcService::delete_orphan_blobs`.
+    pub async fn delete_blob(&self, repo: &str, digest: &Digest) -> Result<BlobDeleted, Error> {
+        if repo.starts_with(PROXY_DIR) {
+            return Err(Error::UnsupportedForProxiedRepo);
+        }
+        let digest_str = digest.as_str();
+        let manifests = self
+            .repos
+            .manifest
+            .list_manifests_in_repo_using_blob(repo, digest_str)
+            .await?;
+        if !manifests.is_empty() {
+            return Err(Error::BlobInUse(manifests));
+        }
+        if !self
+            .repos
+            .repo_blob_assoc
+            .delete_blob_assoc(repo, digest_str)
+            .await?
+        {
+            return Err(Error::BlobUnknown);
+        }
+        Ok(BlobDeleted {})
     }
 }
 
@@ -89,8 +317,15 @@ impl BlobService {
 mod tests {
     use std::sync::Arc;
 
-    use crate::file_storage::FileStorage;
-    use crate::services::blob_service::BlobService;
+    use tokio::io::AsyncReadExt;
+
+    use crate::TrowConfig;
+    use crate::configuration::SingleRegistryProxyConfig;
+    use crate::repositories::Repositories;
+    use crate::services::blob_service::{BlobService, ByteRange};
+    use crate::services::error::Error;
+    use crate::services::proxy_service::ProxyService;
+    use crate::storage::FileStorage;
     use crate::test_utilities::repos_in_memory;
     use crate::utils::digest::Digest;
 
@@ -100,18 +335,181 @@ mod tests {
         (storage, blobs_dir)
     }
 
+    fn setup_service(repos: Arc<Repositories>, storage: Arc<FileStorage>) -> BlobService {
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        BlobService::new(repos, storage, Arc::new(TrowConfig::new()), proxy)
+    }
+
+    /// Caches in `repo` a manifest with `digest` as config
+    async fn cache_manifest_using(repos: &Repositories, repo: &str, digest: &Digest, size: usize) {
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{digest}","size":{size}}},"layers":[]}}"#
+        );
+        let manifest_digest = Digest::digest_sha256_slice(manifest.as_bytes());
+        repos
+            .manifest
+            .insert_or_ignore(manifest_digest.as_str(), manifest.as_bytes())
+            .await
+            .unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_manifest_assoc(repo, manifest_digest.as_str())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_blob_refetches_missing_proxied_blob() {
+        let content: &'static [u8] = b"evicted layer";
+        let digest = Digest::digest_sha256_slice(content);
+        let upstream_path = format!("/v2/foo/blobs/{digest}");
+        let app = axum::Router::new().route(
+            &upstream_path,
+            axum::routing::get(move || async move { content }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: addr.to_string(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let (storage, _blobs_dir) = setup_storage(&dir);
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        let svc = BlobService::new(repos.clone(), storage, Arc::new(config), proxy);
+
+        let repo = format!("f/{addr}/foo");
+        // Not referenced by a cached manifest
+        let result = svc.get_blob(repo.clone(), digest.clone(), None, None).await;
+        assert!(matches!(result, Err(Error::BlobUnknown)));
+
+        cache_manifest_using(&repos, &repo, &digest, content.len()).await;
+        // Recorded, but gone from storage
+        repos
+            .blob
+            .insert_or_ignore(digest.as_str(), content.len() as i64)
+            .await
+            .unwrap();
+
+        let reader = svc
+            .get_blob(repo.clone(), digest.clone(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(reader.blob_size(), content.len() as u64);
+        let mut buf = Vec::new();
+        reader.get_reader().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, content);
+
+        // Stored once the download is over
+        for _ in 0..50 {
+            if svc.is_stored(digest.as_str()).await.unwrap() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(
+            repos
+                .repo_blob_assoc
+                .blob_belongs_to_repo(digest.as_str(), &repo)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_blob_mirror_mode() {
+        let content: &'static [u8] = b"mirrored layer";
+        let digest = Digest::digest_sha256_slice(content);
+        let upstream_path = format!("/v2/library/alpine/blobs/{digest}");
+        let app = axum::Router::new().route(
+            &upstream_path,
+            axum::routing::get(move || async move { content }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.default_upstream = Some(addr.to_string());
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: addr.to_string(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let (storage, _blobs_dir) = setup_storage(&dir);
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        let svc = BlobService::new(repos.clone(), storage, Arc::new(config), proxy);
+
+        let proxied_repo = format!("f/{addr}/library/alpine");
+        cache_manifest_using(&repos, &proxied_repo, &digest, content.len()).await;
+        // Pulled without the f/ prefix, like a Docker Engine using Trow as mirror
+        let reader = svc
+            .get_blob("library/alpine".to_string(), digest.clone(), None, None)
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        reader.get_reader().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, content);
+        // HEAD requests check the repository itself, e.g. before a push
+        let res = svc
+            .head_blob("library/alpine".to_string(), digest.clone(), None)
+            .await;
+        assert!(matches!(res, Err(Error::BlobUnknown)));
+
+        // Local repos are not mirrored
+        repos
+            .blob
+            .insert_or_ignore("sha256:other", 1)
+            .await
+            .unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_blob_assoc("myapp", "sha256:other")
+            .await
+            .unwrap();
+        let res = svc
+            .get_blob("myapp".to_string(), digest.clone(), None, None)
+            .await;
+        assert!(matches!(res, Err(Error::BlobUnknown)));
+    }
+
+    #[tokio::test]
+    async fn get_blob_proxied_unknown_when_upstream_unreachable() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let (storage, _blobs_dir) = setup_storage(&dir);
+        let svc = setup_service(repos.clone(), storage);
+
+        let digest = Digest::digest_sha256_slice(b"missing");
+        cache_manifest_using(&repos, "f/127.0.0.1:1/foo", &digest, 7).await;
+        // Nothing listens on port 1
+        let result = svc
+            .get_blob("f/127.0.0.1:1/foo".to_string(), digest, None, None)
+            .await;
+        assert!(matches!(result, Err(Error::BlobUnknown)));
+    }
+
     #[tokio::test]
     async fn get_blob_not_found_when_no_db_entry() {
         let repos = repos_in_memory().await;
         let dir = test_temp_dir::test_temp_dir!();
         let (storage, _blobs_dir) = setup_storage(&dir);
-        let svc = BlobService::new(repos, storage);
+        let svc = setup_service(repos, storage);
 
         let digest = Digest::try_from_raw(
             "sha256:abc123def456789012345678901234567890123456789012345678901234567",
         )
         .unwrap();
-        let result = svc.get_blob("myrepo".to_string(), digest, None).await;
+        let result = svc.get_blob("myrepo".to_string(), digest, None, None).await;
         assert!(result.is_err());
     }
 
@@ -143,13 +541,138 @@ mod tests {
         let blob_path = blobs_dir.join(digest_str);
         tokio::fs::write(&blob_path, b"test").await.unwrap();
 
-        let svc = BlobService::new(repos.clone(), storage);
+        let svc = setup_service(repos.clone(), storage);
         let digest = Digest::try_from_raw(digest_str).unwrap();
         let result = svc
-            .get_blob("myrepo".to_string(), digest, None)
+            .get_blob("myrepo".to_string(), digest, None, None)
             .await
             .unwrap();
 
         assert_eq!(result.blob_size(), 4);
     }
+
+    #[test]
+    fn byte_range_parse_and_resolve() {
+        let parse = |h| ByteRange::parse(h).unwrap();
+        assert_eq!(parse("bytes=0-499"), Some(ByteRange::From(0, Some(499))));
+        assert_eq!(parse("bytes=500-"), Some(ByteRange::From(500, None)));
+        assert_eq!(parse("bytes=-200"), Some(ByteRange::Suffix(200)));
+        assert_eq!(parse("items=0-1"), None);
+        assert_eq!(parse("bytes=5-1"), None);
+        assert_eq!(parse("bytes=abc"), None);
+        assert!(ByteRange::parse("bytes=0-1,5-6").is_err());
+
+        assert_eq!(ByteRange::From(0, Some(499)).resolve(100), Some(0..=99));
+        assert_eq!(ByteRange::From(10, None).resolve(100), Some(10..=99));
+        assert_eq!(ByteRange::From(100, None).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(200).resolve(100), Some(0..=99));
+        assert_eq!(ByteRange::Suffix(10).resolve(100), Some(90..=99));
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(0), None);
+    }
+
+    #[tokio::test]
+    async fn get_blob_range() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let (storage, blobs_dir) = setup_storage(&dir);
+        let digest = Digest::digest_sha256_slice(b"0123456789");
+        tokio::fs::write(blobs_dir.join(digest.as_str()), b"0123456789")
+            .await
+            .unwrap();
+        repos
+            .blob
+            .insert_or_ignore(digest.as_str(), 10)
+            .await
+            .unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_blob_assoc("myrepo", digest.as_str())
+            .await
+            .unwrap();
+        let svc = setup_service(repos, storage);
+
+        let reader = svc
+            .get_blob(
+                "myrepo".to_string(),
+                digest.clone(),
+                None,
+                Some(ByteRange::From(2, Some(4))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reader.blob_size(), 3);
+        assert_eq!(reader.range(), Some(&(2..=4, 10)));
+        let mut data = Vec::new();
+        reader.get_reader().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"234");
+
+        let result = svc
+            .get_blob(
+                "myrepo".to_string(),
+                digest,
+                None,
+                Some(ByteRange::From(10, None)),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::UnsatisfiableRange(10))));
+    }
+
+    #[tokio::test]
+    async fn delete_blob_refused_while_referenced() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let (storage, _blobs_dir) = setup_storage(&dir);
+        let svc = setup_service(repos.clone(), storage);
+
+        let config = Digest::digest_sha256_slice(b"{}");
+        let config_str = config.as_str();
+        let manifest_json = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config_str}","size":2}},"layers":[]}}"#
+        );
+        let manifest_digest = Digest::digest_sha256_slice(manifest_json.as_bytes());
+        let manifest_str = manifest_digest.as_str();
+        repos.blob.insert_or_ignore(config_str, 2).await.unwrap();
+        for repo in ["repo1", "repo2"] {
+            repos
+                .repo_blob_assoc
+                .insert_blob_assoc(repo, config_str)
+                .await
+                .unwrap();
+        }
+        let manifest_bytes = manifest_json.as_bytes();
+        sqlx::query!(
+            "INSERT INTO manifest (digest, json, blob) VALUES ($1, jsonb($2), $2)",
+            manifest_str,
+            manifest_bytes
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_manifest_assoc("repo1", manifest_str)
+            .await
+            .unwrap();
+
+        let result = svc.delete_blob("repo1", &config).await;
+        assert!(
+            matches!(&result, Err(Error::BlobInUse(m)) if m == &[manifest_str.to_string()]),
+            "{result:?}"
+        );
+
+        svc.delete_blob("repo2", &config).await.unwrap();
+        assert!(
+            !repos
+                .repo_blob_assoc
+                .blob_belongs_to_repo(config_str, "repo2")
+                .await
+                .unwrap()
+        );
+        let result = svc.delete_blob("repo2", &config).await;
+        assert!(matches!(result, Err(Error::BlobUnknown)));
+
+        let result = svc.delete_blob("f/docker.io/library/alpine", &config).await;
+        assert!(matches!(result, Err(Error::UnsupportedForProxiedRepo)));
+    }
 }
@@ -1,15 +1,20 @@
 use std::ops::RangeInclusive;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use axum::body::Body;
+use futures::StreamExt;
+use serde::Serialize;
+use sha2::{Sha256, Sha512};
 use uuid::Uuid;
 
 use crate::PROXY_DIR;
-use crate::file_storage::FileStorage;
 use crate::repositories::Repositories;
+use crate::repositories::models::BlobUpload;
 use crate::services::Error;
-use crate::types::{AcceptedUpload, Upload, UploadInfo};
-use crate::utils::digest::Digest;
+use crate::storage::{StorageBackend, StorageBackendError, Stored, into_blob_stream};
+use crate::types::{AcceptedUpload, MountedBlob, Upload, UploadInfo};
+use crate::utils::digest::{Digest, DigestError, IncrementalDigest};
 
 #[derive(Debug)]
 pub struct UploadStatus {
@@ -18,20 +23,32 @@ pub struct UploadStatus {
     pub offset: i64,
 }
 
+/// In-progress upload, as shown by the admin uploads listing.
+#[derive(Debug, Serialize)]
+pub struct UploadSummary {
+    pub uuid: String,
+    pub repo: String,
+    pub offset: i64,
+    /// Seconds since the upload was started
+    pub age_secs: Option<u64>,
+    pub owner: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct BlobUploadService {
     repos: Arc<Repositories>,
-    storage: Arc<FileStorage>,
+    storage: Arc<dyn StorageBackend>,
 }
 
 impl BlobUploadService {
-    pub fn new(repos: Arc<Repositories>, storage: Arc<FileStorage>) -> Self {
+    pub fn new(repos: Arc<Repositories>, storage: Arc<dyn StorageBackend>) -> Self {
         Self { repos, storage }
     }
 
     pub async fn start_upload(
         &self,
         repo_name: String,
+        owner: &str,
         digest: Option<Digest>,
         data: Body,
     ) -> Result<Upload, Error> {
@@ -42,12 +59,12 @@ impl BlobUploadService {
         let upload_uuid = Uuid::new_v4().to_string();
         self.repos
             .blob_upload
-            .create(&upload_uuid, &repo_name)
+            .create(&upload_uuid, &repo_name, owner)
             .await?;
 
         if let Some(digest) = digest {
             let accepted = self
-                .complete_upload(&repo_name, &upload_uuid, &digest, data, None)
+                .complete_upload(&repo_name, owner, &upload_uuid, &digest, data, None)
                 .await?;
             return Ok(Upload::Accepted(accepted));
         }
@@ -59,9 +76,52 @@ impl BlobUploadService {
         )))
     }
 
+    /// Mounts a blob of the `from` repository into `repo_name`, without
+    /// transferring any data.
+    ///
+    /// Returns `None` if the blob cannot be mounted (unknown to `from`, or no
+    /// longer in storage), in which case the caller should start a regular upload.
+    pub async fn mount_blob(
+        &self,
+        repo_name: &str,
+        digest: &str,
+        from: &str,
+    ) -> Result<Option<MountedBlob>, Error> {
+        if repo_name.starts_with(PROXY_DIR) {
+            return Err(Error::UnsupportedForProxiedRepo);
+        }
+        let Ok(digest) = Digest::try_from_raw(digest) else {
+            return Ok(None);
+        };
+        if !self
+            .repos
+            .repo_blob_assoc
+            .blob_belongs_to_repo(digest.as_str(), from)
+            .await?
+        {
+            return Ok(None);
+        }
+        match self.storage.blob_size(digest.as_str()).await {
+            Ok(_) => {}
+            Err(StorageBackendError::BlobNotFound(_)) => {
+                tracing::warn!("Blob {digest} is associated with {from} but missing from storage");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        self.repos
+            .repo_blob_assoc
+            .insert_blob_assoc(repo_name, digest.as_str())
+            .await?;
+        tracing::debug!("Mounted blob {digest} from {from} into {repo_name}");
+        Ok(Some(MountedBlob::new(digest, repo_name.to_string())))
+    }
+
     pub async fn patch_upload(
         &self,
         repo_name: String,
+        user: &str,
         uuid: Uuid,
         range: Option<RangeInclusive<u64>>,
         data: Body,
@@ -70,16 +130,17 @@ impl BlobUploadService {
             return Err(Error::UnsupportedForProxiedRepo);
         }
         let uuid_str = uuid.to_string();
-        self.repos.blob_upload.exists(&uuid_str).await?;
+        let upload_row = self.repos.blob_upload.find(&uuid_str).await?;
+        check_owner(&upload_row, user)?;
 
-        let size = self
-            .storage
-            .write_blob_part_stream(&uuid, data.into_data_stream(), range)
+        let (size, hasher) = self
+            .write_chunk(&upload_row, &uuid, "sha256", data, range)
             .await?;
         let total_stored = size.total_stored as i64;
+        let hash_state = hasher.map(|h| h.state());
         self.repos
             .blob_upload
-            .update_offset(&uuid_str, total_stored)
+            .update_progress(&uuid_str, total_stored, hash_state.as_deref())
             .await?;
 
         Ok(UploadInfo::new(
@@ -92,6 +153,7 @@ impl BlobUploadService {
     pub async fn complete_upload(
         &self,
         repo_name: &str,
+        user: &str,
         uuid_str: &str,
         digest: &Digest,
         data: Body,
@@ -101,17 +163,43 @@ impl BlobUploadService {
         if upload_row.repo != repo_name {
             return Err(Error::Invalid("Repository mismatch".to_string()));
         }
+        check_owner(&upload_row, user)?;
         let upload_id_bin = Uuid::parse_str(uuid_str).unwrap();
 
-        let size = self
-            .storage
-            .write_blob_part_stream(&upload_id_bin, data.into_data_stream(), range)
+        let (size, hasher) = self
+            .write_chunk(&upload_row, &upload_id_bin, digest.algo_str(), data, range)
             .await?;
 
+        // Chunks are hashed with SHA-256 until the final digest is known,
+        // other algorithms are checked by reading the blob back.
+        let read_back = match hasher {
+            Some(hasher) if hasher.algo_str() == digest.algo_str() => {
+                let computed = hasher.finalize();
+                if &computed != digest {
+                    self.storage.delete_upload(uuid_str).await?;
+                    return self.reject_upload(uuid_str, digest, &computed).await;
+                }
+                false
+            }
+            Some(_) => true,
+            None => {
+                tracing::warn!("Upload {uuid_str} predates digest verification, not verified");
+                false
+            }
+        };
+
         self.storage
             .complete_blob_write(&upload_id_bin, digest.as_str())
             .await?;
 
+        if read_back {
+            let computed = self.digest_stored_blob(repo_name, digest).await?;
+            if &computed != digest {
+                self.storage.delete_blob(digest.as_str()).await?;
+                return self.reject_upload(uuid_str, digest, &computed).await;
+            }
+        }
+
         self.repos.blob_upload.delete(&upload_row.uuid).await?;
 
         let digest_str = digest.as_str();
@@ -134,38 +222,160 @@ impl BlobUploadService {
         ))
     }
 
+    async fn reject_upload<T>(
+        &self,
+        uuid_str: &str,
+        digest: &Digest,
+        computed: &Digest,
+    ) -> Result<T, Error> {
+        tracing::warn!(
+            "Upload {uuid_str} did not match given digest. Was given {digest} but got {computed}"
+        );
+        self.repos.blob_upload.delete(uuid_str).await?;
+        Err(Error::Digest(DigestError::InvalidDigest(format!(
+            "expected {digest}, got {computed}"
+        ))))
+    }
+
+    /// Hashes a stored blob with the algorithm of `digest`.
+    async fn digest_stored_blob(&self, repo_name: &str, digest: &Digest) -> Result<Digest, Error> {
+        let mut reader = self
+            .storage
+            .get_blob_stream(repo_name, digest.as_str(), None)
+            .await?
+            .reader();
+        let computed = match digest.algo_str() {
+            "sha512" => Digest::digest::<Sha512, _>(&mut reader).await,
+            _ => Digest::digest::<Sha256, _>(&mut reader).await,
+        };
+        computed.map_err(|e| Error::Storage(e.into()))
+    }
+
+    /// Appends `data` to the upload, hashing it on the fly.
+    /// Uploads starting from scratch are hashed with `algo`, the others carry
+    /// on with the algorithm of their saved hasher state.
+    /// The returned hasher is `None` for legacy uploads whose hash state is unknown.
+    async fn write_chunk(
+        &self,
+        upload_row: &BlobUpload,
+        uuid: &Uuid,
+        algo: &str,
+        data: Body,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<(Stored, Option<IncrementalDigest>), Error> {
+        let mut hasher = match (&upload_row.hash_state, upload_row.offset) {
+            (_, 0) => Some(IncrementalDigest::for_algo(algo)?),
+            (Some(state), _) => Some(IncrementalDigest::from_state(state)?),
+            (None, _) => None,
+        };
+        let stream = data.into_data_stream().map(|chunk| {
+            if let (Ok(bytes), Some(hasher)) = (&chunk, hasher.as_mut()) {
+                hasher.update(bytes);
+            }
+            chunk
+        });
+        let size = self
+            .storage
+            .write_blob_part_stream(uuid, into_blob_stream(stream), range)
+            .await?;
+        Ok((size, hasher))
+    }
+
+    /// Cancels an upload, discarding the data received so far.
+    pub async fn cancel_upload(
+        &self,
+        repo_name: &str,
+        user: &str,
+        uuid: Uuid,
+    ) -> Result<(), Error> {
+        if repo_name.starts_with(PROXY_DIR) {
+            return Err(Error::UnsupportedForProxiedRepo);
+        }
+        let uuid_str = uuid.to_string();
+        let upload_row = match self.repos.blob_upload.find(&uuid_str).await {
+            Ok(row) if row.repo == repo_name => row,
+            Ok(_) | Err(sqlx::Error::RowNotFound) => return Err(Error::BlobUploadUnknown),
+            Err(e) => return Err(e.into()),
+        };
+        check_owner(&upload_row, user)?;
+        self.storage.delete_upload(&upload_row.uuid).await?;
+        self.repos.blob_upload.delete(&upload_row.uuid).await?;
+        tracing::info!("Cancelled upload {uuid_str} to {repo_name}");
+        Ok(())
+    }
+
+    /// Lists in-progress uploads, optionally restricted to one repository.
+    pub async fn list_uploads(&self, repo_name: Option<&str>) -> Result<Vec<UploadSummary>, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let uploads = self.repos.blob_upload.list(repo_name).await?;
+        Ok(uploads
+            .into_iter()
+            .map(|u| UploadSummary {
+                uuid: u.uuid,
+                repo: u.repo,
+                offset: u.offset,
+                age_secs: u
+                    .created_at
+                    .map(|created| now.saturating_sub(u64::try_from(created).unwrap_or(0))),
+                owner: u.owner,
+            })
+            .collect())
+    }
+
     pub async fn get_upload_status(
         &self,
         repo_name: String,
+        user: &str,
         uuid: Uuid,
     ) -> Result<UploadStatus, Error> {
         if repo_name.starts_with(PROXY_DIR) {
             return Err(Error::UnsupportedForProxiedRepo);
         }
         let uuid_str = uuid.to_string();
-        let offset = self
+        let upload_row = self
             .repos
             .blob_upload
-            .find_offset_in_repo(&uuid_str, &repo_name)
+            .find_in_repo(&uuid_str, &repo_name)
             .await?;
+        check_owner(&upload_row, user)?;
         Ok(UploadStatus {
             uuid,
             repo: repo_name,
-            offset,
+            offset: upload_row.offset,
         })
     }
 }
 
+/// Uploads can only be used by the user that started them.
+/// Uploads started before owners were recorded are usable by anyone.
+fn check_owner(upload: &BlobUpload, user: &str) -> Result<(), Error> {
+    match &upload.owner {
+        Some(owner) if owner != user => {
+            tracing::warn!(
+                "User {user} tried to use upload {} started by {owner}",
+                upload.uuid
+            );
+            // Same as an unknown upload, to not disclose other users' sessions
+            Err(Error::BlobUploadUnknown)
+        }
+        _ => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
 
     use uuid::Uuid;
 
-    use crate::file_storage::FileStorage;
     use crate::services::blob_upload_service::BlobUploadService;
     use crate::services::error::Error;
+    use crate::storage::{FileStorage, StorageBackendError};
     use crate::test_utilities::repos_in_memory;
+    use crate::types::Upload;
+    use crate::utils::digest::{Digest, IncrementalDigest};
 
     fn setup_storage(dir: &test_temp_dir::TestTempDir) -> Arc<FileStorage> {
         Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap())
@@ -181,6 +391,7 @@ mod tests {
         let result = svc
             .start_upload(
                 "f/docker.io/library/alpine".to_string(),
+                "user",
                 None,
                 axum::body::Body::empty(),
             )
@@ -196,7 +407,12 @@ mod tests {
         let svc = BlobUploadService::new(repos.clone(), storage);
 
         let result = svc
-            .start_upload("myrepo".to_string(), None, axum::body::Body::empty())
+            .start_upload(
+                "myrepo".to_string(),
+                "user",
+                None,
+                axum::body::Body::empty(),
+            )
             .await
             .unwrap();
         // Returns Upload::Info when no digest provided
@@ -219,7 +435,7 @@ mod tests {
 
         let uuid = Uuid::new_v4();
         let result = svc
-            .get_upload_status("f/docker.io/library/alpine".to_string(), uuid)
+            .get_upload_status("f/docker.io/library/alpine".to_string(), "user", uuid)
             .await;
         assert!(matches!(result, Err(Error::UnsupportedForProxiedRepo)));
     }
@@ -244,7 +460,7 @@ mod tests {
         .unwrap();
 
         let result = svc
-            .get_upload_status("myrepo".to_string(), uuid)
+            .get_upload_status("myrepo".to_string(), "user", uuid)
             .await
             .unwrap();
         assert_eq!(result.uuid, uuid);
@@ -263,6 +479,7 @@ mod tests {
         let result = svc
             .patch_upload(
                 "f/docker.io/library/alpine".to_string(),
+                "user",
                 uuid,
                 None,
                 axum::body::Body::empty(),
@@ -270,4 +487,287 @@ mod tests {
             .await;
         assert!(matches!(result, Err(Error::UnsupportedForProxiedRepo)));
     }
+
+    #[tokio::test]
+    async fn mount_blob_from_other_repo() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let digest = Digest::digest_sha256_slice(b"shared layer");
+        svc.start_upload(
+            "base".to_string(),
+            "user",
+            Some(digest.clone()),
+            "shared layer".into(),
+        )
+        .await
+        .unwrap();
+
+        let unknown = Digest::digest_sha256_slice(b"unknown");
+        for (digest, from) in [
+            (unknown.as_str(), "base"),
+            (digest.as_str(), "other"),
+            ("not-a-digest", "base"),
+        ] {
+            let mounted = svc.mount_blob("app", digest, from).await.unwrap();
+            assert!(
+                mounted.is_none(),
+                "{digest} should not be mountable from {from}"
+            );
+        }
+
+        let mounted = svc
+            .mount_blob("app", digest.as_str(), "base")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mounted.digest(), &digest);
+        assert_eq!(mounted.repo_name(), "app");
+        assert!(
+            repos
+                .repo_blob_assoc
+                .blob_belongs_to_repo(digest.as_str(), "app")
+                .await
+                .unwrap()
+        );
+
+        let result = svc
+            .mount_blob("f/docker.io/library/alpine", digest.as_str(), "base")
+            .await;
+        assert!(matches!(result, Err(Error::UnsupportedForProxiedRepo)));
+    }
+
+    #[tokio::test]
+    async fn cancel_and_list_uploads() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let uuid = start(&svc, "myrepo").await;
+        start(&svc, "otherrepo").await;
+        svc.patch_upload("myrepo".to_string(), "user", uuid, None, "chunk1".into())
+            .await
+            .unwrap();
+        let upload_file = dir
+            .as_path_untracked()
+            .join("uploads")
+            .join(uuid.to_string());
+        assert!(upload_file.exists());
+
+        assert_eq!(svc.list_uploads(None).await.unwrap().len(), 2);
+        let uploads = svc.list_uploads(Some("myrepo")).await.unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].uuid, uuid.to_string());
+        assert_eq!(uploads[0].offset, 6);
+        assert_eq!(uploads[0].owner.as_deref(), Some("user"));
+        assert!(uploads[0].age_secs.is_some());
+
+        let result = svc.cancel_upload("otherrepo", "user", uuid).await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+        svc.cancel_upload("myrepo", "user", uuid).await.unwrap();
+        assert!(!upload_file.exists());
+        assert!(svc.list_uploads(Some("myrepo")).await.unwrap().is_empty());
+        let result = svc.cancel_upload("myrepo", "user", uuid).await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_other_user() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let uuid = start(&svc, "myrepo").await;
+        let uuid_str = uuid.to_string();
+        let digest = Digest::digest_sha256_slice(b"chunk1");
+
+        let result = svc
+            .patch_upload("myrepo".to_string(), "mallory", uuid, None, "chunk1".into())
+            .await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+        let result = svc
+            .get_upload_status("myrepo".to_string(), "mallory", uuid)
+            .await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+        let result = svc
+            .complete_upload(
+                "myrepo",
+                "mallory",
+                &uuid_str,
+                &digest,
+                "chunk1".into(),
+                None,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+        let result = svc.cancel_upload("myrepo", "mallory", uuid).await;
+        assert!(matches!(result, Err(Error::BlobUploadUnknown)));
+
+        // Nothing was written by the rejected requests
+        let status = svc
+            .get_upload_status("myrepo".to_string(), "user", uuid)
+            .await
+            .unwrap();
+        assert_eq!(status.offset, 0);
+        svc.complete_upload("myrepo", "user", &uuid_str, &digest, "chunk1".into(), None)
+            .await
+            .unwrap();
+    }
+
+    async fn start(svc: &BlobUploadService, repo: &str) -> Uuid {
+        match svc
+            .start_upload(repo.to_string(), "user", None, axum::body::Body::empty())
+            .await
+            .unwrap()
+        {
+            Upload::Info(info) => Uuid::parse_str(info.uuid()).unwrap(),
+            _ => panic!("Upload should not be complete"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_verifies_digest_across_patches() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let uuid = start(&svc, "myrepo").await;
+
+        svc.patch_upload(
+            "myrepo".to_string(),
+            "user",
+            uuid,
+            Some(0..=5),
+            "chunk1".into(),
+        )
+        .await
+        .unwrap();
+        let hash_state = repos
+            .blob_upload
+            .find(&uuid.to_string())
+            .await
+            .unwrap()
+            .hash_state;
+        assert!(hash_state.is_some());
+
+        // Hasher state is persisted, not kept in memory
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        svc.patch_upload(
+            "myrepo".to_string(),
+            "user",
+            uuid,
+            Some(6..=11),
+            "chunk2".into(),
+        )
+        .await
+        .unwrap();
+        let digest = Digest::digest_sha256_slice(b"chunk1chunk2chunk3");
+        let accepted = svc
+            .complete_upload(
+                "myrepo",
+                "user",
+                &uuid.to_string(),
+                &digest,
+                "chunk3".into(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(accepted.digest(), &digest);
+        assert!(repos.blob.exists(digest.as_str()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn complete_upload_rejects_digest_mismatch() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let uuid = start(&svc, "myrepo").await;
+
+        svc.patch_upload("myrepo".to_string(), "user", uuid, None, "garbage".into())
+            .await
+            .unwrap();
+        let digest = Digest::digest_sha256_slice(b"not garbage");
+        let result = svc
+            .complete_upload(
+                "myrepo",
+                "user",
+                &uuid.to_string(),
+                &digest,
+                axum::body::Body::empty(),
+                None,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::Digest(_))));
+        assert!(!repos.blob.exists(digest.as_str()).await.unwrap());
+        assert!(repos.blob_upload.find(&uuid.to_string()).await.is_err());
+        assert!(
+            !dir.as_path_untracked()
+                .join("uploads")
+                .join(uuid.to_string())
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn sha512_uploads_are_verified() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = BlobUploadService::new(repos.clone(), setup_storage(&dir));
+        let sha512 = |data: &[u8]| {
+            let mut hasher = IncrementalDigest::for_algo("sha512").unwrap();
+            hasher.update(data);
+            hasher.finalize()
+        };
+
+        // Monolithic upload, hashed with SHA-512 right away
+        let digest = sha512(b"monolithic");
+        let upload = svc
+            .start_upload(
+                "myrepo".to_string(),
+                "user",
+                Some(digest.clone()),
+                "monolithic".into(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(upload, Upload::Accepted(_)));
+
+        // Chunked upload, hashed with SHA-256 until the digest is known
+        let uuid = start(&svc, "myrepo").await;
+        svc.patch_upload("myrepo".to_string(), "user", uuid, None, "chunk1".into())
+            .await
+            .unwrap();
+        let digest = sha512(b"chunk1chunk2");
+        svc.complete_upload(
+            "myrepo",
+            "user",
+            &uuid.to_string(),
+            &digest,
+            "chunk2".into(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(repos.blob.exists(digest.as_str()).await.unwrap());
+
+        let uuid = start(&svc, "myrepo").await;
+        svc.patch_upload("myrepo".to_string(), "user", uuid, None, "chunk1".into())
+            .await
+            .unwrap();
+        let digest = sha512(b"something else");
+        let result = svc
+            .complete_upload(
+                "myrepo",
+                "user",
+                &uuid.to_string(),
+                &digest,
+                "chunk2".into(),
+                None,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::Digest(_))));
+        assert!(!repos.blob.exists(digest.as_str()).await.unwrap());
+        assert!(matches!(
+            svc.storage.blob_size(digest.as_str()).await,
+            Err(StorageBackendError::BlobNotFound(_))
+        ));
+    }
 }
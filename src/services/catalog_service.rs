@@ -4,15 +4,28 @@ use oci_spec::distribution::{RepositoryList, RepositoryListBuilder, TagList, Tag
 
 use crate::repositories::Repositories;
 use crate::services::Error;
+use crate::services::proxy_service::{ProxyService, proxied_repo_name};
+use crate::utils::resolve_reference::parse_reference;
+use crate::{PROXY_DIR, TrowConfig};
 
 #[derive(Debug)]
 pub struct CatalogService {
     repos: Arc<Repositories>,
+    config: Arc<TrowConfig>,
+    proxy: Arc<ProxyService>,
 }
 
 impl CatalogService {
-    pub fn new(repos: Arc<Repositories>) -> Self {
-        Self { repos }
+    pub fn new(
+        repos: Arc<Repositories>,
+        config: Arc<TrowConfig>,
+        proxy: Arc<ProxyService>,
+    ) -> Self {
+        Self {
+            repos,
+            config,
+            proxy,
+        }
     }
 
     pub async fn list_repositories(
@@ -29,15 +42,47 @@ impl CatalogService {
             .unwrap())
     }
 
+    /// Lists the tags of a repo. Proxied repos list the upstream tags, or the
+    /// cached ones if the upstream can't be reached.
     pub async fn list_tags(
         &self,
         repo_name: &str,
         last: Option<&str>,
         limit: Option<u64>,
     ) -> Result<TagList, Error> {
+        let mirrored = self
+            .proxy
+            .mirrored_repo(&self.config.config_file.registry_proxies, repo_name, None)
+            .await?;
+        let mut local_repo = mirrored.unwrap_or_else(|| repo_name.to_string());
+        if local_repo.starts_with(PROXY_DIR) {
+            let image = parse_reference(&local_repo, "latest", None)?;
+            let proxy_config = self
+                .config
+                .config_file
+                .registry_proxies
+                .registries
+                .get_for(image.registry(), image.repository());
+            match self
+                .proxy
+                .list_remote_tags(&image, proxy_config, last, limit.map(|l| l as usize))
+                .await
+            {
+                Ok(tags) => {
+                    return Ok(TagListBuilder::default()
+                        .name(repo_name.to_string())
+                        .tags(tags)
+                        .build()
+                        .unwrap());
+                }
+                Err(e) => tracing::warn!("Could not list tags of {image}, using cache: {e}"),
+            }
+            local_repo = proxied_repo_name(&image);
+        }
+
         let last = last.unwrap_or("");
         let limit = limit.unwrap_or(i64::MAX as u64) as i64;
-        let tags = self.repos.tag.list(repo_name, last, limit).await?;
+        let tags = self.repos.tag.list(&local_repo, last, limit).await?;
         Ok(TagListBuilder::default()
             .name(repo_name.to_string())
             .tags(tags)
@@ -48,8 +93,27 @@ impl CatalogService {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::TrowConfig;
+    use crate::configuration::SingleRegistryProxyConfig;
+    use crate::repositories::Repositories;
     use crate::services::catalog_service::CatalogService;
-    use crate::test_utilities::repos_in_memory;
+    use crate::services::proxy_service::ProxyService;
+    use crate::storage::FileStorage;
+    use crate::test_utilities::{fake_upstream, repos_in_memory};
+
+    fn setup_service(repos: Arc<Repositories>) -> CatalogService {
+        setup_service_with_config(repos, TrowConfig::new())
+    }
+
+    fn setup_service_with_config(repos: Arc<Repositories>, config: TrowConfig) -> CatalogService {
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage));
+        CatalogService::new(repos, Arc::new(config), proxy)
+    }
 
     fn fake_digest() -> &'static str {
         "sha256:0000000000000000000000000000000000000000000000000000000000000000"
@@ -58,7 +122,7 @@ mod tests {
     #[tokio::test]
     async fn list_repositories_empty() {
         let repos = repos_in_memory().await;
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc.list_repositories(None, None).await.unwrap();
         assert!(result.repositories().is_empty());
     }
@@ -84,7 +148,7 @@ mod tests {
         .await
         .unwrap();
 
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc.list_repositories(None, None).await.unwrap();
         assert_eq!(result.repositories(), &["a/repo", "z/repo"]);
     }
@@ -109,7 +173,7 @@ mod tests {
         .await
         .unwrap();
 
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc.list_repositories(Some("alpha"), Some(1)).await.unwrap();
         assert_eq!(result.repositories(), &["beta"]);
     }
@@ -117,7 +181,7 @@ mod tests {
     #[tokio::test]
     async fn list_tags_empty_repo() {
         let repos = repos_in_memory().await;
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc.list_tags("nonexistent", None, None).await.unwrap();
         assert_eq!(result.name(), "nonexistent");
         assert!(result.tags().is_empty());
@@ -151,7 +215,7 @@ mod tests {
         .await
         .unwrap();
 
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc.list_tags("myrepo", None, None).await.unwrap();
         assert_eq!(result.tags(), &["v1.0", "v2.0"]);
     }
@@ -186,11 +250,95 @@ mod tests {
         .await
         .unwrap();
 
-        let svc = CatalogService::new(repos);
+        let svc = setup_service(repos);
         let result = svc
             .list_tags("myrepo", Some("v1.0"), Some(1))
             .await
             .unwrap();
         assert_eq!(result.tags(), &["v2.0"]);
     }
+
+    #[tokio::test]
+    async fn list_tags_proxied_falls_back_to_cache() {
+        let repos = repos_in_memory().await;
+        let digest = fake_digest();
+        let json_bytes: &[u8] = b"{}";
+        sqlx::query!(
+            "INSERT INTO manifest (digest, json, blob) VALUES (?, ?, ?)",
+            digest,
+            json_bytes,
+            json_bytes
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+        // Nothing listens on port 1, so the upstream is unreachable
+        sqlx::query!(
+            "INSERT INTO tag (tag, repo, manifest_digest) VALUES (?, ?, ?)",
+            "cached",
+            "f/127.0.0.1:1/foo",
+            digest
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+
+        let svc = setup_service(repos);
+        let result = svc
+            .list_tags("f/127.0.0.1:1/foo", None, None)
+            .await
+            .unwrap();
+        assert_eq!(result.name(), "f/127.0.0.1:1/foo");
+        assert_eq!(result.tags(), &["cached"]);
+    }
+
+    #[tokio::test]
+    async fn list_tags_proxied_lists_upstream_tags() {
+        let manifest = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let manifests = ["v1.0", "v2.0", "v3.0"]
+            .map(|tag| (tag.to_string(), manifest.to_string()))
+            .into();
+        let addr = fake_upstream("foo", manifests, HashMap::new()).await;
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: addr.clone(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
+
+        let repos = repos_in_memory().await;
+        let digest = fake_digest();
+        let json_bytes: &[u8] = b"{}";
+        sqlx::query!(
+            "INSERT INTO manifest (digest, json, blob) VALUES (?, ?, ?)",
+            digest,
+            json_bytes,
+            json_bytes
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+        let proxied_repo = format!("f/{addr}/foo");
+        sqlx::query!(
+            "INSERT INTO tag (tag, repo, manifest_digest) VALUES (?, ?, ?)",
+            "cached",
+            proxied_repo,
+            digest
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+
+        let svc = setup_service_with_config(repos, config);
+        let result = svc.list_tags(&proxied_repo, None, None).await.unwrap();
+        assert_eq!(result.name(), &proxied_repo);
+        assert_eq!(result.tags(), &["v1.0", "v2.0", "v3.0"]);
+
+        let result = svc
+            .list_tags(&proxied_repo, Some("v1.0"), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(result.tags(), &["v2.0"]);
+    }
 }
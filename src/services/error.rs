@@ -1,5 +1,5 @@
-use crate::file_storage::StorageBackendError;
 use crate::services::proxy_service::errors::DownloadRemoteImageError;
+use crate::storage::StorageBackendError;
 use crate::utils::digest::DigestError;
 
 #[derive(Debug, thiserror::Error)]
@@ -12,10 +12,20 @@ pub enum Error {
     UnsupportedForProxiedRepo,
     #[error("manifest invalid: {0}")]
     ManifestInvalid(String),
+    #[error("manifest references unknown blob: {0}")]
+    ManifestBlobUnknown(String),
+    #[error("size invalid: {0}")]
+    SizeInvalid(String),
     #[error("manifest unknown: {0}")]
     ManifestUnknown(String),
+    #[error("blob unknown")]
+    BlobUnknown,
+    #[error("blob in use by manifests: {0:?}")]
+    BlobInUse(Vec<String>),
     #[error("blob upload unknown")]
     BlobUploadUnknown,
+    #[error("range not satisfiable for blob of size {0}")]
+    UnsatisfiableRange(u64),
     #[error("db error: {0}")]
     Db(#[from] sqlx::Error),
     #[error("storage error: {0}")]
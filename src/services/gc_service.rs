@@ -3,21 +3,23 @@ use std::sync::Arc;
 use tokio::time::{self, Duration};
 
 use crate::TrowConfig;
-use crate::file_storage::FileStorage;
 use crate::repositories::Repositories;
 use crate::services::Error;
+use crate::storage::StorageBackend;
+use crate::utils::manifest::{OCIManifest, platform_matches};
+use crate::utils::resolve_reference::parse_reference;
 
 #[derive(Debug)]
 pub struct GcService {
     repos: Arc<Repositories>,
-    storage: Arc<FileStorage>,
+    storage: Arc<dyn StorageBackend>,
     config: Arc<TrowConfig>,
 }
 
 impl GcService {
     pub fn new(
         repos: Arc<Repositories>,
-        storage: Arc<FileStorage>,
+        storage: Arc<dyn StorageBackend>,
         config: Arc<TrowConfig>,
     ) -> Self {
         Self {
@@ -44,6 +46,7 @@ impl GcService {
 
         let mut space_reclaimed = 0;
         space_reclaimed += self.delete_stale_uploads().await?;
+        self.delete_unwanted_platforms().await?;
         space_reclaimed += self.delete_orphan_blobs().await?;
         if let Some(space_required) = space_to_reclaim {
             space_reclaimed += self
@@ -94,6 +97,55 @@ impl GcService {
         Ok(bytes_reclaimed)
     }
 
+    /// Removes the manifests of platforms not listed in the `platforms` of
+    /// their registry from proxied indexes. Their blobs are then orphans.
+    pub async fn delete_unwanted_platforms(&self) -> Result<(), Error> {
+        let indexes = self.repos.manifest.list_proxied_indexes().await?;
+        for index in indexes {
+            let OCIManifest::List(content) = &index.content.0 else {
+                continue;
+            };
+            let Ok(image) = parse_reference(&index.repo_name, "latest", None) else {
+                continue;
+            };
+            let proxy_config = self
+                .config
+                .config_file
+                .registry_proxies
+                .registries
+                .get_for(image.registry(), image.repository());
+            let Some(platforms) = proxy_config.map(|c| &c.platforms) else {
+                continue;
+            };
+            if platforms.is_empty() {
+                continue;
+            }
+            let unwanted = content.manifests().iter().filter(|m| {
+                m.platform()
+                    .as_ref()
+                    .is_some_and(|p| !platforms.iter().any(|w| platform_matches(p, w)))
+            });
+            for child in unwanted {
+                let digest = child.digest().as_ref();
+                self.repos
+                    .repo_blob_assoc
+                    .delete_manifest_assoc(&index.repo_name, digest)
+                    .await?;
+                if self
+                    .repos
+                    .repo_blob_assoc
+                    .count_manifest_assoc(digest)
+                    .await?
+                    == 0
+                {
+                    tracing::debug!("Deleting unwanted platform manifest {digest}");
+                    self.repos.manifest.delete(digest).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn delete_orphan_blobs(&self) -> Result<usize, Error> {
         let mut bytes_reclaimed = 0;
         let blobs = self.repos.blob.list_orphaned_older_than_days().await?;
@@ -148,6 +200,7 @@ fn bytes_humanstring(bytes: usize) -> String {
 
 #[cfg(test)]
 mod tests {
+    use crate::configuration::SingleRegistryProxyConfig;
     use crate::test_utilities;
     use crate::test_utilities::test_temp_dir;
 
@@ -216,6 +269,64 @@ mod tests {
         assert!(manifests.is_empty());
     }
 
+    #[tracing_test::traced_test]
+    #[tokio::test]
+    async fn test_delete_unwanted_platforms() {
+        let dir = test_temp_dir!();
+        let (state, _router) = test_utilities::trow_router(
+            |cfg| {
+                cfg.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+                    host: "registry.example.com".to_string(),
+                    platforms: vec!["linux/amd64".to_string()],
+                    ..Default::default()
+                }]
+                .into();
+            },
+            &dir,
+        )
+        .await;
+        let repos = state.services.repos();
+
+        let amd64 = format!("sha256:{}", "a".repeat(64));
+        let arm64 = format!("sha256:{}", "b".repeat(64));
+        let manifest = |config: &str| {
+            format!(
+                r#"{{"schemaVersion":2,"config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:{}","size":2}},"layers":[]}}"#,
+                config.repeat(64)
+            )
+        };
+        let index = format!(
+            r#"{{"schemaVersion":2,"manifests":[
+                {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{amd64}","size":1,"platform":{{"os":"linux","architecture":"amd64"}}}},
+                {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{arm64}","size":1,"platform":{{"os":"linux","architecture":"arm64"}}}}
+            ]}}"#
+        );
+        for (digest, content) in [
+            ("sha256:index", index),
+            (&amd64, manifest("c")),
+            (&arm64, manifest("d")),
+        ] {
+            repos
+                .manifest
+                .insert_or_ignore(digest, content.as_bytes())
+                .await
+                .unwrap();
+            repos
+                .repo_blob_assoc
+                .insert_manifest_assoc_safe("f/registry.example.com/app", digest)
+                .await
+                .unwrap();
+        }
+
+        state.services.gc.delete_unwanted_platforms().await.unwrap();
+
+        let manifests = sqlx::query_scalar!(r#"SELECT digest FROM manifest ORDER BY digest"#)
+            .fetch_all(repos.db_ro())
+            .await
+            .unwrap();
+        assert_eq!(manifests, [amd64, "sha256:index".to_string()]);
+    }
+
     #[tracing_test::traced_test]
     #[tokio::test]
     async fn test_delete_orphan_blobs() {
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use serde_derive::{Deserialize, Serialize};
 
-use crate::file_storage::FileStorage;
+use crate::storage::StorageBackend;
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct HealthStatus {
@@ -18,11 +18,11 @@ pub struct ReadyStatus {
 
 #[derive(Debug)]
 pub struct HealthService {
-    storage: Arc<FileStorage>,
+    storage: Arc<dyn StorageBackend>,
 }
 
 impl HealthService {
-    pub fn new(storage: Arc<FileStorage>) -> Self {
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
         Self { storage }
     }
 
@@ -51,8 +51,8 @@ impl HealthService {
 mod tests {
     use std::sync::Arc;
 
-    use crate::file_storage::FileStorage;
     use crate::services::health_service::HealthService;
+    use crate::storage::FileStorage;
     use crate::test_utilities::test_temp_dir;
 
     #[test]
@@ -1,16 +1,26 @@
 use std::sync::Arc;
 
 use axum::body::Body;
+use oci_client::Reference;
+use oci_spec::image::{Descriptor, ImageIndex};
 
 use crate::repositories::Repositories;
 use crate::services::error::Error;
 use crate::services::proxy_service::ProxyService;
+use crate::services::referrers_service::{ReferrersService, referrers_tag};
+use crate::services::replication_service::ReplicationService;
 use crate::types::{ManifestDeleted, VerifiedManifest};
 use crate::utils::digest::Digest;
-use crate::utils::manifest::{OCIManifest, REGEX_TAG, layer_is_distributable, manifest_media_type};
+use crate::utils::manifest::{
+    OCIManifest, REGEX_MEDIA_TYPE, REGEX_TAG, layer_is_distributable, manifest_media_type,
+    platform_matches,
+};
 use crate::utils::resolve_reference::parse_reference;
 use crate::{PROXY_DIR, TrowConfig};
 
+/// Platform served to clients that don't accept image indexes, if not configured
+const DEFAULT_PLATFORM: &str = "linux/amd64";
+
 pub struct ManifestPayload {
     pub bytes: bytes::Bytes,
     pub digest: String,
@@ -22,6 +32,8 @@ pub struct ManifestService {
     repos: Arc<Repositories>,
     config: Arc<TrowConfig>,
     proxy: Arc<ProxyService>,
+    referrers: Arc<ReferrersService>,
+    replication: Arc<ReplicationService>,
 }
 
 impl ManifestService {
@@ -29,60 +41,116 @@ impl ManifestService {
         repos: Arc<Repositories>,
         config: Arc<TrowConfig>,
         proxy: Arc<ProxyService>,
+        referrers: Arc<ReferrersService>,
+        replication: Arc<ReplicationService>,
     ) -> Self {
         Self {
             repos,
             config,
             proxy,
+            referrers,
+            replication,
         }
     }
 
+    /// Gets a manifest in one of the `accept`ed media types (anything if empty).
+    ///
+    /// If a tag points to an index that the client doesn't accept, the child
+    /// manifest for the configured default platform is returned instead.
+    /// Digest references are only ever answered with the manifest they name.
     pub async fn get_manifest(
         &self,
         repo: String,
         raw_reference: String,
         namespace: Option<&str>,
+        accept: &[String],
     ) -> Result<ManifestPayload, Error> {
+        let registry_proxies = &self.config.config_file.registry_proxies;
+        let repo = self
+            .proxy
+            .mirrored_repo(registry_proxies, &repo, namespace)
+            .await?
+            .unwrap_or(repo);
         let image = parse_reference(&repo, &raw_reference, namespace)?;
+        let digest = self.resolve_manifest_digest(&repo, &image).await?;
+        let payload = self.load_manifest(digest).await?;
+        if media_type_accepted(accept, &payload.content_type) {
+            return Ok(payload);
+        }
 
-        let digest = if image.registry() != "localhost" {
+        if image.digest().is_none()
+            && let Ok(OCIManifest::List(index)) = serde_json::from_slice(&payload.bytes)
+        {
+            let platform = self
+                .config
+                .config_file
+                .default_platform
+                .as_deref()
+                .unwrap_or(DEFAULT_PLATFORM);
+            if let Some(child) = find_platform_manifest(&index, platform) {
+                tracing::debug!(
+                    "Index {} not accepted by client, serving {platform} manifest {child}",
+                    payload.digest
+                );
+                let child_image = image.clone_with_digest(child);
+                let digest = self.resolve_manifest_digest(&repo, &child_image).await?;
+                let child_payload = self.load_manifest(digest).await?;
+                if media_type_accepted(accept, &child_payload.content_type) {
+                    return Ok(child_payload);
+                }
+            }
+        }
+
+        Err(Error::ManifestUnknown(format!(
+            "{raw_reference} is not available in the accepted media types: {}",
+            accept.join(", ")
+        )))
+    }
+
+    /// Finds the digest of the manifest `image` refers to, downloading it for proxied repos
+    async fn resolve_manifest_digest(
+        &self,
+        repo: &str,
+        image: &Reference,
+    ) -> Result<String, Error> {
+        if image.registry() != "localhost" {
             let proxy_config = self
                 .config
                 .config_file
                 .registry_proxies
                 .registries
                 .get_for(image.registry(), image.repository());
-            self.proxy.download_image(&image, proxy_config).await?
-        } else {
-            let digest = if let Some(tag) = image.tag() {
-                let tdigest = self.repos.tag.find_manifest_digest(&repo, tag).await?;
-                match tdigest {
-                    Some(d) => d,
-                    None => {
-                        return Err(Error::ManifestUnknown(format!(
-                            "Unknown tag: {raw_reference}"
-                        )));
-                    }
+            return self.proxy.download_image(image, proxy_config).await;
+        }
+
+        let digest = if let Some(tag) = image.tag() {
+            let tdigest = self.repos.tag.find_manifest_digest(repo, tag).await?;
+            match tdigest {
+                Some(d) => d,
+                None => {
+                    return Err(Error::ManifestUnknown(format!("Unknown tag: {tag}")));
                 }
-            } else if let Some(digest) = image.digest() {
-                digest.to_string()
-            } else {
-                return Err(Error::ManifestUnknown(format!(
-                    "Invalid reference: {raw_reference}"
-                )));
-            };
-
-            if !self
-                .repos
-                .repo_blob_assoc
-                .manifest_belongs_to_repo(&repo, &digest)
-                .await?
-            {
-                return Err(Error::ManifestUnknown(format!("Unknown digest {digest}")));
             }
-            digest
+        } else if let Some(digest) = image.digest() {
+            digest.to_string()
+        } else {
+            return Err(Error::ManifestUnknown(format!(
+                "Invalid reference: {image}"
+            )));
         };
 
+        if !self
+            .repos
+            .repo_blob_assoc
+            .manifest_belongs_to_repo(repo, &digest)
+            .await?
+        {
+            return Err(Error::ManifestUnknown(format!("Unknown digest {digest}")));
+        }
+        Ok(digest)
+    }
+
+    async fn load_manifest(&self, digest: String) -> Result<ManifestPayload, Error> {
         let res = self.repos.manifest.find(&digest).await?;
         let content_type = match res.media_type.as_ref() {
             Some(mt) => mt.clone(),
@@ -119,46 +187,8 @@ impl ManifestService {
         let manifest_parsed = serde_json::from_slice::<'_, OCIManifest>(&manifest_bytes)
             .map_err(|e| Error::ManifestInvalid(format!("{e}")))?;
 
-        match &manifest_parsed {
-            OCIManifest::List(m) => {
-                let assets = m
-                    .manifests()
-                    .iter()
-                    .filter(|l| layer_is_distributable(l.media_type()))
-                    .map(|m| m.digest().as_ref());
-                for digest in assets {
-                    if !self
-                        .repos
-                        .repo_blob_assoc
-                        .manifest_belongs_to_repo(&repo_name, digest)
-                        .await?
-                    {
-                        return Err(Error::ManifestInvalid(format!(
-                            "Manifest asset not found: {digest}"
-                        )));
-                    }
-                }
-            }
-            OCIManifest::V2(m) => {
-                let assets = m
-                    .layers()
-                    .iter()
-                    .filter(|l| layer_is_distributable(l.media_type()))
-                    .map(|l| l.digest().as_ref());
-                for digest in assets {
-                    if !self
-                        .repos
-                        .repo_blob_assoc
-                        .blob_belongs_to_repo(digest, &repo_name)
-                        .await?
-                    {
-                        return Err(Error::ManifestInvalid(format!(
-                            "Blob asset not found: {digest}"
-                        )));
-                    }
-                }
-            }
-        }
+        self.validate_references(&repo_name, &manifest_parsed)
+            .await?;
         let computed_digest = Digest::digest_sha256_slice(&manifest_bytes);
         let computed_digest_str = computed_digest.as_str();
         if !is_tag && computed_digest_str != reference {
@@ -184,6 +214,25 @@ impl ManifestService {
         }
 
         let subject = manifest_parsed.subject().map(|s| s.digest().to_string());
+        // Keep the tag schema fallback in sync for clients without referrers API
+        // support, unless the client is the one pushing the referrers tag.
+        if let Some(subject_digest) = subject
+            .as_deref()
+            .and_then(|s| Digest::try_from_raw(s).ok())
+            && reference != referrers_tag(&subject_digest)
+        {
+            self.referrers
+                .update_referrers_tag(&repo_name, &subject_digest)
+                .await?;
+        }
+        // The push succeeded, failing to queue its replication doesn't undo it
+        if let Err(e) = self
+            .replication
+            .enqueue(&repo_name, &reference, computed_digest_str)
+            .await
+        {
+            tracing::error!("Could not queue the replication of {repo_name}:{reference}: {e}");
+        }
 
         Ok(VerifiedManifest::new(
             Some(host),
@@ -194,6 +243,80 @@ impl ManifestService {
         ))
     }
 
+    /// Checks the media types of a pushed manifest and that everything it
+    /// references was pushed to the repo, with the advertised sizes.
+    async fn validate_references(
+        &self,
+        repo_name: &str,
+        manifest: &OCIManifest,
+    ) -> Result<(), Error> {
+        let (allowed_types, declared_type) = match manifest {
+            OCIManifest::List(m) => (
+                [
+                    manifest_media_type::OCI_INDEX,
+                    manifest_media_type::DOCKER_LIST,
+                ],
+                m.media_type(),
+            ),
+            OCIManifest::V2(m) => (
+                [manifest_media_type::OCI_V1, manifest_media_type::DOCKER_V2],
+                m.media_type(),
+            ),
+        };
+        if let Some(mt) = declared_type
+            && !allowed_types.contains(&mt.to_string().as_str())
+        {
+            return Err(Error::ManifestInvalid(format!("Unexpected mediaType {mt}")));
+        }
+
+        match manifest {
+            OCIManifest::List(m) => {
+                for child in m.manifests() {
+                    // Unknown media types are tolerated, as for blobs
+                    let media_type = child.media_type().to_string();
+                    if !REGEX_MEDIA_TYPE.is_match(&media_type) {
+                        return Err(Error::ManifestInvalid(format!(
+                            "{} has an invalid mediaType: {media_type}",
+                            child.digest()
+                        )));
+                    }
+                    let digest = child.digest().as_ref();
+                    if !self
+                        .repos
+                        .repo_blob_assoc
+                        .manifest_belongs_to_repo(repo_name, digest)
+                        .await?
+                    {
+                        return Err(Error::ManifestBlobUnknown(digest.to_string()));
+                    }
+                }
+            }
+            OCIManifest::V2(m) => {
+                for descriptor in std::iter::once(m.config()).chain(m.layers()) {
+                    check_blob_media_type(descriptor)?;
+                }
+                let distributable_layers = m
+                    .layers()
+                    .iter()
+                    .filter(|l| layer_is_distributable(l.media_type()));
+                for descriptor in std::iter::once(m.config()).chain(distributable_layers) {
+                    let digest = descriptor.digest().as_ref();
+                    let Some(size) = self.repos.blob.find_size_in_repo(digest, repo_name).await?
+                    else {
+                        return Err(Error::ManifestBlobUnknown(digest.to_string()));
+                    };
+                    if u64::try_from(size).ok() != Some(descriptor.size()) {
+                        return Err(Error::SizeInvalid(format!(
+                            "{digest} has size {size}, manifest says {}",
+                            descriptor.size()
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn delete_manifest(
         &self,
         repo: String,
@@ -207,6 +330,15 @@ impl ManifestService {
         } else {
             let digest = Digest::try_from_raw(&reference)?;
             let digest_str = digest.as_str();
+            let subject = self
+                .repos
+                .manifest
+                .find(digest_str)
+                .await
+                .ok()
+                .and_then(|m| serde_json::from_slice::<OCIManifest>(&m.blob).ok())
+                .and_then(|m| m.subject())
+                .and_then(|s| Digest::try_from_raw(s.digest().as_ref()).ok());
             self.repos
                 .repo_blob_assoc
                 .delete_manifest_assoc(&repo, digest_str)
@@ -219,12 +351,75 @@ impl ManifestService {
             if num_repo_assoc == 0 {
                 self.repos.manifest.delete(digest_str).await?;
             }
+            if let Some(subject) = subject {
+                self.referrers.update_referrers_tag(&repo, &subject).await?;
+            }
         }
 
         Ok(ManifestDeleted {})
     }
 }
 
+/// Whether `media_type` matches one of the `accept`ed media types (anything if empty)
+fn media_type_accepted(accept: &[String], media_type: &str) -> bool {
+    accept.is_empty()
+        || accept.iter().any(|a| match a.strip_suffix("/*") {
+            Some(main_type) => media_type
+                .split_once('/')
+                .is_some_and(|(mt, _)| mt.eq_ignore_ascii_case(main_type)),
+            None => a.eq_ignore_ascii_case(media_type),
+        })
+}
+
+/// Finds the manifest of an index matching `platform` (`os/arch[/variant]`).
+/// The variant is only compared if `platform` specifies one.
+fn find_platform_manifest(index: &ImageIndex, platform: &str) -> Option<String> {
+    index
+        .manifests()
+        .iter()
+        .find(|m| {
+            m.platform()
+                .as_ref()
+                .is_some_and(|p| platform_matches(p, platform))
+        })
+        .map(|m| m.digest().to_string())
+}
+
+/// Checks the media type of a config or layer descriptor.
+///
+/// Types unknown to Trow are accepted: the image spec requires registries to
+/// tolerate them, and artifacts use their own (Helm chart configs, cosign
+/// signatures, SBOMs, BuildKit attestations with `application/vnd.in-toto+json`
+/// layers on an image config...). Only malformed media types, and manifest
+/// types that can't describe a blob, are rejected.
+fn check_blob_media_type(descriptor: &Descriptor) -> Result<(), Error> {
+    let media_type = descriptor.media_type().to_string();
+    if !REGEX_MEDIA_TYPE.is_match(&media_type) {
+        return Err(Error::ManifestInvalid(format!(
+            "{} has an invalid mediaType: {media_type}",
+            descriptor.digest()
+        )));
+    }
+    if is_manifest_media_type(&media_type) {
+        return Err(Error::ManifestInvalid(format!(
+            "{} is a manifest, not a blob: {media_type}",
+            descriptor.digest()
+        )));
+    }
+    Ok(())
+}
+
+fn is_manifest_media_type(media_type: &str) -> bool {
+    [
+        manifest_media_type::OCI_V1,
+        manifest_media_type::OCI_INDEX,
+        manifest_media_type::DOCKER_V2,
+        manifest_media_type::DOCKER_LIST,
+        manifest_media_type::DOCKER_V1,
+    ]
+    .contains(&media_type)
+}
+
 pub(crate) fn determine_content_type(manifest_bytes: &[u8]) -> Result<String, Error> {
     let manifest: OCIManifest = serde_json::from_slice(manifest_bytes)
         .map_err(|e| Error::ManifestInvalid(format!("Invalid manifest JSON: {}", e)))?;
@@ -241,21 +436,39 @@ pub(crate) fn determine_content_type(manifest_bytes: &[u8]) -> Result<String, Er
 mod tests {
     use std::sync::Arc;
 
+    use axum::body::Body;
+    use oci_spec::image::ImageIndex;
+
     use crate::TrowConfig;
-    use crate::file_storage::FileStorage;
+    use crate::repositories::Repositories;
     use crate::services::error::Error;
-    use crate::services::manifest_service::{ManifestService, determine_content_type};
+    use crate::services::manifest_service::{
+        ManifestService, determine_content_type, media_type_accepted,
+    };
     use crate::services::proxy_service::ProxyService;
+    use crate::services::referrers_service::ReferrersService;
+    use crate::services::replication_service::ReplicationService;
+    use crate::storage::FileStorage;
     use crate::test_utilities::repos_in_memory;
+    use crate::utils::digest::Digest;
+    use crate::utils::manifest::manifest_media_type;
 
-    fn setup_service(
-        repos: Arc<super::super::super::repositories::Repositories>,
-    ) -> ManifestService {
+    fn setup_service(repos: Arc<Repositories>) -> ManifestService {
         let dir = test_temp_dir::test_temp_dir!();
         let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
-        let proxy = Arc::new(ProxyService::new(repos.clone(), storage));
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
         let config = Arc::new(TrowConfig::new());
-        ManifestService::new(repos, config, proxy)
+        let referrers = Arc::new(ReferrersService::new(
+            repos.clone(),
+            config.clone(),
+            proxy.clone(),
+        ));
+        let replication = Arc::new(ReplicationService::new(
+            repos.clone(),
+            storage,
+            config.clone(),
+        ));
+        ManifestService::new(repos, config, proxy, referrers, replication)
     }
 
     fn minimal_v2_manifest_json() -> &'static str {
@@ -271,6 +484,17 @@ mod tests {
         }"#
     }
 
+    /// Pushes the config blob of [`minimal_v2_manifest_json`] to `repo`
+    async fn insert_config_blob(repos: &Repositories, repo: &str) {
+        let digest = "sha256:4d3c246dfef2edb11eccb051b47d896d0db8f1c4563c0cce9f6274b9abd9ac74";
+        repos.blob.insert_or_ignore(digest, 702).await.unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_blob_assoc(repo, digest)
+            .await
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn put_manifest_rejects_proxied_repo() {
         let repos = repos_in_memory().await;
@@ -307,6 +531,7 @@ mod tests {
     async fn put_manifest_with_tag_succeeds() {
         let repos = repos_in_memory().await;
         let svc = setup_service(repos.clone());
+        insert_config_blob(&repos, "myrepo").await;
 
         let manifest_json = minimal_v2_manifest_json();
         let result = svc
@@ -337,7 +562,8 @@ mod tests {
     #[tokio::test]
     async fn put_manifest_with_digest_requires_matching() {
         let repos = repos_in_memory().await;
-        let svc = setup_service(repos);
+        let svc = setup_service(repos.clone());
+        insert_config_blob(&repos, "myrepo").await;
 
         let manifest_json = minimal_v2_manifest_json();
         let result = svc
@@ -355,7 +581,8 @@ mod tests {
     #[tokio::test]
     async fn put_manifest_requires_layers_in_repo() {
         let repos = repos_in_memory().await;
-        let svc = setup_service(repos);
+        let svc = setup_service(repos.clone());
+        insert_config_blob(&repos, "myrepo").await;
 
         // Manifest with a layer that doesn't exist in repo
         let manifest_with_layer = r#"{
@@ -381,7 +608,68 @@ mod tests {
                 axum::body::Body::from(manifest_with_layer),
             )
             .await;
+        assert!(matches!(result, Err(Error::ManifestBlobUnknown(_))));
+    }
+
+    #[tokio::test]
+    async fn put_manifest_validates_descriptors() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+        let put = |manifest: String| {
+            svc.put_manifest(
+                "myrepo".to_string(),
+                "latest".to_string(),
+                "localhost".to_string(),
+                Body::from(manifest),
+            )
+        };
+
+        // Config blob not pushed
+        let result = put(minimal_v2_manifest_json().to_string()).await;
+        assert!(matches!(result, Err(Error::ManifestBlobUnknown(_))));
+
+        insert_config_blob(&repos, "myrepo").await;
+        let wrong_size = minimal_v2_manifest_json().replace("702", "703");
+        let result = put(wrong_size).await;
+        assert!(matches!(result, Err(Error::SizeInvalid(_))));
+
+        let wrong_type = minimal_v2_manifest_json()
+            .replace(manifest_media_type::OCI_V1, manifest_media_type::OCI_INDEX);
+        let result = put(wrong_type).await;
+        assert!(matches!(result, Err(Error::ManifestInvalid(_))));
+
+        let layer = |media_type: &str| {
+            minimal_v2_manifest_json().replace(
+                "\"layers\": []",
+                &format!(
+                    r#""layers": [{{"mediaType": "{media_type}", "digest": "sha256:4d3c246dfef2edb11eccb051b47d896d0db8f1c4563c0cce9f6274b9abd9ac74", "size": 702}}]"#
+                ),
+            )
+        };
+        let result = put(layer("not a media type")).await;
+        assert!(matches!(result, Err(Error::ManifestInvalid(_))));
+        let result = put(layer(manifest_media_type::OCI_V1)).await;
+        assert!(matches!(result, Err(Error::ManifestInvalid(_))));
+        // Artifact types are not known in advance
+        put(layer("application/vnd.in-toto+json")).await.unwrap();
+
+        let index_of_blob = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:4d3c246dfef2edb11eccb051b47d896d0db8f1c4563c0cce9f6274b9abd9ac74","size":702}]}"#;
+        let result = put(index_of_blob.to_string()).await;
+        assert!(matches!(result, Err(Error::ManifestBlobUnknown(_))));
+
+        put(minimal_v2_manifest_json().to_string()).await.unwrap();
+
+        let index = |media_type: &str| {
+            let child = Digest::digest_sha256_slice(minimal_v2_manifest_json().as_bytes());
+            format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{{"mediaType":"{media_type}","digest":"{child}","size":1}}]}}"#
+            )
+        };
+        let result = put(index("not a media type")).await;
         assert!(matches!(result, Err(Error::ManifestInvalid(_))));
+        put(index("application/vnd.example.manifest.v1+json"))
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -490,6 +778,85 @@ mod tests {
         assert_eq!(manifest_count, 0);
     }
 
+    #[tokio::test]
+    async fn put_manifest_maintains_referrers_tag() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+        insert_config_blob(&repos, "myrepo").await;
+
+        let subject = svc
+            .put_manifest(
+                "myrepo".to_string(),
+                "latest".to_string(),
+                "localhost".to_string(),
+                axum::body::Body::from(minimal_v2_manifest_json()),
+            )
+            .await
+            .unwrap();
+        let referrer_json = format!(
+            r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.example.sbom",
+            "config": {{
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:4d3c246dfef2edb11eccb051b47d896d0db8f1c4563c0cce9f6274b9abd9ac74",
+                "size": 702
+            }},
+            "layers": [],
+            "subject": {{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "{}",
+                "size": {}
+            }}
+        }}"#,
+            subject.digest(),
+            minimal_v2_manifest_json().len()
+        );
+        let referrer_digest = Digest::digest_sha256_slice(referrer_json.as_bytes());
+        svc.put_manifest(
+            "myrepo".to_string(),
+            referrer_digest.to_string(),
+            "localhost".to_string(),
+            axum::body::Body::from(referrer_json),
+        )
+        .await
+        .unwrap();
+
+        let fallback_tag = subject.digest().replacen(':', "-", 1);
+        let index_digest = repos
+            .tag
+            .find_manifest_digest("myrepo", &fallback_tag)
+            .await
+            .unwrap()
+            .unwrap();
+        let index: ImageIndex =
+            serde_json::from_slice(&repos.manifest.find(&index_digest).await.unwrap().blob)
+                .unwrap();
+        assert_eq!(index.manifests().len(), 1);
+        assert_eq!(
+            index.manifests()[0].digest().as_ref(),
+            referrer_digest.as_str()
+        );
+        assert_eq!(
+            index.manifests()[0]
+                .artifact_type()
+                .as_ref()
+                .map(|t| t.to_string()),
+            Some("application/vnd.example.sbom".to_string())
+        );
+
+        svc.delete_manifest("myrepo".to_string(), referrer_digest.to_string())
+            .await
+            .unwrap();
+        let index_digest = repos
+            .tag
+            .find_manifest_digest("myrepo", &fallback_tag)
+            .await
+            .unwrap();
+        assert!(index_digest.is_none());
+    }
+
     #[tokio::test]
     async fn get_manifest_by_tag() {
         let repos = repos_in_memory().await;
@@ -526,7 +893,7 @@ mod tests {
         .unwrap();
 
         let result = svc
-            .get_manifest("myrepo".to_string(), "latest".to_string(), None)
+            .get_manifest("myrepo".to_string(), "latest".to_string(), None, &[])
             .await
             .unwrap();
         assert_eq!(result.digest, digest);
@@ -542,7 +909,7 @@ mod tests {
         let svc = setup_service(repos);
 
         let result = svc
-            .get_manifest("myrepo".to_string(), "nonexistent".to_string(), None)
+            .get_manifest("myrepo".to_string(), "nonexistent".to_string(), None, &[])
             .await;
         assert!(matches!(result, Err(Error::ManifestUnknown(_))));
     }
@@ -558,11 +925,128 @@ mod tests {
                 "sha256:0000000000000000000000000000000000000000000000000000000000000000"
                     .to_string(),
                 None,
+                &[],
             )
             .await;
         assert!(matches!(result, Err(Error::ManifestUnknown(_))));
     }
 
+    #[tokio::test]
+    async fn get_manifest_negotiates_media_type() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+        insert_config_blob(&repos, "myrepo").await;
+        let push = |reference: &str, body: String| {
+            svc.put_manifest(
+                "myrepo".to_string(),
+                reference.to_string(),
+                String::new(),
+                Body::from(body),
+            )
+        };
+
+        let mut children = Vec::new();
+        let mut child_digests = Vec::new();
+        for arch in ["amd64", "arm64"] {
+            let manifest = minimal_v2_manifest_json().replace(
+                "\"layers\": []",
+                &format!("\"layers\": [], \"annotations\": {{\"arch\": \"{arch}\"}}"),
+            );
+            let digest = Digest::digest_sha256_slice(manifest.as_bytes()).to_string();
+            push(&digest, manifest).await.unwrap();
+            child_digests.push(digest.clone());
+            children.push(format!(
+                r#"{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{digest}","size":1,"platform":{{"os":"linux","architecture":"{arch}"}}}}"#
+            ));
+        }
+        let index = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{}]}}"#,
+            children.join(",")
+        );
+        let index_digest = Digest::digest_sha256_slice(index.as_bytes()).to_string();
+        push("latest", index).await.unwrap();
+
+        let get = |accept: &'static [&'static str]| {
+            let accept: Vec<String> = accept.iter().map(|a| a.to_string()).collect();
+            let svc = &svc;
+            async move {
+                svc.get_manifest("myrepo".to_string(), "latest".to_string(), None, &accept)
+                    .await
+            }
+        };
+
+        let res = get(&[]).await.unwrap();
+        assert_eq!(res.content_type, manifest_media_type::OCI_INDEX);
+        let res = get(&[manifest_media_type::OCI_INDEX, manifest_media_type::OCI_V1])
+            .await
+            .unwrap();
+        assert_eq!(res.content_type, manifest_media_type::OCI_INDEX);
+        let res = get(&["application/*"]).await.unwrap();
+        assert_eq!(res.content_type, manifest_media_type::OCI_INDEX);
+
+        // Index not accepted: default platform manifest
+        let res = get(&[manifest_media_type::OCI_V1]).await.unwrap();
+        assert_eq!(res.content_type, manifest_media_type::OCI_V1);
+        assert_eq!(res.digest, child_digests[0]);
+
+        // Neither index nor child accepted
+        let res = get(&[manifest_media_type::DOCKER_V2]).await;
+        assert!(matches!(res, Err(Error::ManifestUnknown(_))));
+
+        // The index itself was asked for, no platform manifest instead
+        let res = svc
+            .get_manifest(
+                "myrepo".to_string(),
+                index_digest,
+                None,
+                &[manifest_media_type::OCI_V1.to_string()],
+            )
+            .await;
+        assert!(matches!(res, Err(Error::ManifestUnknown(_))));
+
+        let mut config = TrowConfig::new();
+        config.config_file.default_platform = Some("linux/arm64".to_string());
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        let config = Arc::new(config);
+        let referrers = Arc::new(ReferrersService::new(
+            repos.clone(),
+            config.clone(),
+            proxy.clone(),
+        ));
+        let replication = Arc::new(ReplicationService::new(
+            repos.clone(),
+            storage,
+            config.clone(),
+        ));
+        let svc = ManifestService::new(repos, config, proxy, referrers, replication);
+        let res = svc
+            .get_manifest(
+                "myrepo".to_string(),
+                "latest".to_string(),
+                None,
+                &[manifest_media_type::OCI_V1.to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.digest, child_digests[1]);
+    }
+
+    #[test]
+    fn media_type_accepted_wildcards() {
+        let accept = |a: &[&str]| a.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(media_type_accepted(&[], manifest_media_type::OCI_V1));
+        assert!(media_type_accepted(
+            &accept(&["application/*"]),
+            manifest_media_type::OCI_V1
+        ));
+        assert!(!media_type_accepted(
+            &accept(&["text/*", manifest_media_type::DOCKER_V2]),
+            manifest_media_type::OCI_V1
+        ));
+    }
+
     #[test]
     fn determine_content_type_v2_manifest() {
         let manifest = r#"{
@@ -1,6 +1,6 @@
 //! Service layer: orchestration and business rules.
 //!
-//! Services own Repositories and FileStorage handles. Controllers call
+//! Services own Repositories and StorageBackend handles. Controllers call
 //! services; services never call controllers.
 
 pub mod admission_service;
@@ -11,8 +11,11 @@ pub mod error;
 pub mod gc_service;
 pub mod health_service;
 pub mod manifest_service;
+pub mod prefetch_service;
 pub mod proxy_service;
 pub mod referrers_service;
+pub mod replication_service;
+pub mod repo_mirror_service;
 
 use std::sync::Arc;
 
@@ -24,11 +27,14 @@ pub use self::error::Error;
 use self::gc_service::GcService;
 use self::health_service::HealthService;
 use self::manifest_service::ManifestService;
+use self::prefetch_service::PrefetchService;
 use self::proxy_service::ProxyService;
 use self::referrers_service::ReferrersService;
+use self::replication_service::ReplicationService;
+use self::repo_mirror_service::RepoMirrorService;
 use crate::TrowConfig;
-use crate::file_storage::FileStorage;
 use crate::repositories::Repositories;
+use crate::storage::StorageBackend;
 
 impl std::fmt::Debug for Services {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -41,30 +47,65 @@ pub struct Services {
     pub blob_upload: BlobUploadService,
     pub manifest: ManifestService,
     pub catalog: CatalogService,
-    pub referrers: ReferrersService,
+    pub referrers: Arc<ReferrersService>,
     pub proxy: Arc<ProxyService>,
     pub gc: Arc<GcService>,
+    pub prefetch: Arc<PrefetchService>,
+    pub repo_mirror: Arc<RepoMirrorService>,
+    pub replication: Arc<ReplicationService>,
     pub admission: AdmissionService,
     pub health: HealthService,
     #[doc(hidden)]
     repos_shared: Arc<Repositories>,
     #[doc(hidden)]
-    storage_shared: Arc<FileStorage>,
+    storage_shared: Arc<dyn StorageBackend>,
 }
 
 impl Services {
     pub fn new(
         repos: Arc<Repositories>,
-        storage: Arc<FileStorage>,
+        storage: Arc<dyn StorageBackend>,
         config: Arc<TrowConfig>,
     ) -> Self {
-        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        let proxy = Arc::new(
+            ProxyService::new(repos.clone(), storage.clone())
+                .with_download_dir(config.data_dir.join("downloads"))
+                .with_egress_proxy(config.config_file.registry_proxies.egress_proxy.clone()),
+        );
+        let referrers = Arc::new(ReferrersService::new(
+            repos.clone(),
+            config.clone(),
+            proxy.clone(),
+        ));
+        let replication = Arc::new(ReplicationService::new(
+            repos.clone(),
+            storage.clone(),
+            config.clone(),
+        ));
         Self {
-            blob: BlobService::new(repos.clone(), storage.clone()),
+            blob: BlobService::new(
+                repos.clone(),
+                storage.clone(),
+                config.clone(),
+                proxy.clone(),
+            ),
             blob_upload: BlobUploadService::new(repos.clone(), storage.clone()),
-            manifest: ManifestService::new(repos.clone(), config.clone(), proxy.clone()),
-            catalog: CatalogService::new(repos.clone()),
-            referrers: ReferrersService::new(repos.clone()),
+            manifest: ManifestService::new(
+                repos.clone(),
+                config.clone(),
+                proxy.clone(),
+                referrers.clone(),
+                replication.clone(),
+            ),
+            catalog: CatalogService::new(repos.clone(), config.clone(), proxy.clone()),
+            referrers,
+            prefetch: Arc::new(PrefetchService::new(config.clone(), proxy.clone())),
+            repo_mirror: Arc::new(RepoMirrorService::new(
+                repos.clone(),
+                config.clone(),
+                proxy.clone(),
+            )),
+            replication,
             proxy,
             gc: Arc::new(GcService::new(
                 repos.clone(),
@@ -84,7 +125,7 @@ impl Services {
     }
 
     #[doc(hidden)]
-    pub fn storage(&self) -> &Arc<FileStorage> {
+    pub fn storage(&self) -> &Arc<dyn StorageBackend> {
         &self.storage_shared
     }
 }
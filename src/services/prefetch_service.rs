@@ -0,0 +1,228 @@
+//! Downloads the proxied images listed in the `prefetch` config ahead of the
+//! first pull, so pods scheduled on new nodes don't wait for upstream.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use oci_spec::distribution::Reference;
+use regex::Regex;
+use serde::Serialize;
+use tokio::time::{self, Duration};
+
+use crate::configuration::{PrefetchImage, SingleRegistryProxyConfig};
+use crate::services::Error;
+use crate::services::proxy_service::ProxyService;
+use crate::utils::resolve_reference::parse_reference;
+use crate::{PROXY_DIR, TrowConfig};
+
+/// Outcome of the last prefetch of an image, as shown by the admin prefetch listing.
+#[derive(Debug, Clone, Serialize)]
+pub struct PrefetchStatus {
+    /// Upstream image, e.g. `docker.io/library/alpine:3.20`
+    pub image: String,
+    pub digest: Option<String>,
+    pub error: Option<String>,
+    /// Unix timestamp of the last attempt
+    pub last_attempt: i64,
+}
+
+#[derive(Debug)]
+pub struct PrefetchService {
+    config: Arc<TrowConfig>,
+    proxy: Arc<ProxyService>,
+    status: Mutex<BTreeMap<String, PrefetchStatus>>,
+}
+
+impl PrefetchService {
+    pub fn new(config: Arc<TrowConfig>, proxy: Arc<ProxyService>) -> Self {
+        Self {
+            config,
+            proxy,
+            status: Mutex::default(),
+        }
+    }
+
+    /// Blocks forever, prefetching images on the configured interval.
+    /// Returns immediately if prefetch isn't configured.
+    pub async fn watchdog(self: Arc<Self>) {
+        let Some(prefetch) = &self.config.config_file.prefetch else {
+            return;
+        };
+        let mut interval = time::interval(Duration::from_secs(prefetch.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            self.run_once().await;
+        }
+    }
+
+    /// Runs one prefetch pass; safe to call manually (used in tests).
+    pub async fn run_once(&self) {
+        let Some(prefetch) = &self.config.config_file.prefetch else {
+            return;
+        };
+        for entry in &prefetch.images {
+            match self.images_of(entry).await {
+                Ok(images) => {
+                    for image in images {
+                        self.prefetch(&image).await;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Could not prefetch {}: {e}", entry.repository);
+                    self.record(entry.repository.clone(), Err(e.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Last outcome of each prefetched image
+    pub fn status(&self) -> Vec<PrefetchStatus> {
+        self.status.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The images of the listed tags, and of the upstream tags matching `tag_regex`
+    async fn images_of(&self, entry: &PrefetchImage) -> Result<Vec<Reference>, Error> {
+        let repository = entry.repository.trim_matches('/');
+        if !repository.contains('/') {
+            return Err(Error::Invalid(format!(
+                "{repository} is not a repository of an upstream registry"
+            )));
+        }
+        let repo = format!("{PROXY_DIR}{repository}");
+        let mut tags = entry.tags.clone();
+        if let Some(tag_regex) = &entry.tag_regex {
+            let regex = Regex::new(tag_regex)
+                .map_err(|e| Error::Invalid(format!("invalid tag_regex: {e}")))?;
+            let image = parse_reference(&repo, "latest", None)?;
+            let remote_tags = self
+                .proxy
+                .list_all_remote_tags(&image, self.proxy_config(&image))
+                .await?;
+            tags.extend(remote_tags.tags.into_iter().filter(|t| regex.is_match(t)));
+        }
+        tags.sort();
+        tags.dedup();
+        tags.iter()
+            .map(|tag| parse_reference(&repo, tag, None))
+            .collect()
+    }
+
+    /// Downloads `image`, with the manifests of the registry's `platforms`
+    /// for an index, or of the `default_platform`, or all of them
+    async fn prefetch(&self, image: &Reference) {
+        let proxy_config = self.proxy_config(image);
+        let default_platform = self.config.config_file.default_platform.clone();
+        let platforms = match proxy_config {
+            Some(c) if !c.platforms.is_empty() => c.platforms.clone(),
+            _ => default_platform.into_iter().collect(),
+        };
+        let result = self
+            .proxy
+            .prefetch_image(image, proxy_config, &platforms)
+            .await;
+        match &result {
+            Ok(digest) => tracing::debug!("Prefetched {image} ({digest})"),
+            Err(e) => tracing::warn!("Could not prefetch {image}: {e}"),
+        }
+        self.record(image.to_string(), result.map_err(|e| e.to_string()));
+    }
+
+    fn proxy_config(&self, image: &Reference) -> Option<&SingleRegistryProxyConfig> {
+        self.config
+            .config_file
+            .registry_proxies
+            .registries
+            .get_for(image.registry(), image.repository())
+    }
+
+    fn record(&self, image: String, result: Result<String, String>) {
+        let (digest, error) = match result {
+            Ok(digest) => (Some(digest), None),
+            Err(e) => (None, Some(e)),
+        };
+        let status = PrefetchStatus {
+            image: image.clone(),
+            digest,
+            error,
+            last_attempt: chrono::Utc::now().timestamp(),
+        };
+        self.status.lock().unwrap().insert(image, status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::PrefetchService;
+    use crate::TrowConfig;
+    use crate::configuration::{PrefetchConfig, PrefetchImage, SingleRegistryProxyConfig};
+    use crate::services::proxy_service::ProxyService;
+    use crate::storage::FileStorage;
+    use crate::test_utilities::{fake_upstream, repos_in_memory};
+    use crate::utils::digest::Digest;
+
+    #[tokio::test]
+    async fn run_once_prefetches_listed_and_matching_tags() {
+        let index = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let digest = Digest::digest_sha256_slice(index.as_bytes()).to_string();
+        let manifests = ["latest", "v1.0", "v1.1", "v2.0"]
+            .map(|tag| (tag.to_string(), index.to_string()))
+            .into();
+        let addr = fake_upstream("foo", manifests, HashMap::new()).await;
+
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: addr.clone(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
+        config.config_file.prefetch = Some(PrefetchConfig {
+            interval_secs: 3600,
+            images: vec![
+                // v1.1 is on the second page of the upstream tags
+                PrefetchImage {
+                    repository: format!("{addr}/foo"),
+                    tags: vec!["latest".to_string()],
+                    tag_regex: Some(r"^v1\.".to_string()),
+                },
+                PrefetchImage {
+                    repository: "not-a-repo".to_string(),
+                    ..Default::default()
+                },
+            ],
+        });
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage));
+        let svc = PrefetchService::new(Arc::new(config), proxy);
+
+        svc.run_once().await;
+        let status = svc.status();
+        let images: Vec<_> = status.iter().map(|s| s.image.as_str()).collect();
+        assert_eq!(
+            images,
+            [
+                format!("{addr}/foo:latest"),
+                format!("{addr}/foo:v1.0"),
+                format!("{addr}/foo:v1.1"),
+                "not-a-repo".to_string(),
+            ]
+        );
+        assert!(
+            status[..3]
+                .iter()
+                .all(|s| s.digest.as_ref() == Some(&digest))
+        );
+        assert!(status[3].error.is_some());
+        let cached = repos
+            .tag
+            .find_manifest_digest(&format!("f/{addr}/foo"), "v1.1")
+            .await
+            .unwrap();
+        assert_eq!(cached, Some(digest));
+    }
+}
@@ -0,0 +1,303 @@
+//! Upstream credentials from Docker `config.json` files, including Kubernetes
+//! `dockerconfigjson` pull secrets and credential helpers.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use ::oci_client::secrets::RegistryAuth;
+use base64::Engine;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use super::errors::DownloadRemoteImageError;
+
+/// Files looked up when the configured path is a directory, e.g. where a
+/// Kubernetes secret is mounted
+const CONFIG_FILE_NAMES: &[&str] = &[".dockerconfigjson", "config.json"];
+/// Credential helpers taking longer than this are killed
+const HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Credentials of helpers are asked again after this, as helpers like
+/// `ecr-login` hand out short-lived tokens
+const HELPER_CREDENTIALS_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    creds_store: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthEntry {
+    /// base64 of `username:password`
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Credentials for `host` in the Docker config at `path` (a file, or a
+/// directory containing `.dockerconfigjson` or `config.json`).
+/// As with the Docker CLI, `credHelpers` win over `auths`, and `credsStore`
+/// is used for registries without other credentials.
+/// Credentials of helpers come with how long they can be reused.
+pub async fn docker_config_auth(
+    path: &Path,
+    host: &str,
+) -> Result<Option<(RegistryAuth, Option<Duration>)>, DownloadRemoteImageError> {
+    let config = read_docker_config(path).await?;
+    let host = normalize_registry(host);
+    // Name under which the Docker CLI stores Docker Hub credentials in helpers
+    let server_url = match host.as_str() {
+        "docker.io" => "https://index.docker.io/v1/",
+        host => host,
+    };
+
+    let helper = config
+        .cred_helpers
+        .iter()
+        .find(|(registry, _)| normalize_registry(registry) == host)
+        .map(|(_, helper)| helper);
+    if let Some(helper) = helper {
+        return helper_auth(&format!("docker-credential-{helper}"), server_url).await;
+    }
+    let entry = config
+        .auths
+        .iter()
+        .find(|(registry, _)| normalize_registry(registry) == host)
+        .map(|(_, entry)| entry);
+    let auth = entry.map(AuthEntry::to_auth).transpose();
+    if let Some(auth) = auth
+        .map_err(DownloadRemoteImageError::DockerConfig)?
+        .flatten()
+    {
+        return Ok(Some((auth, None)));
+    }
+    match &config.creds_store {
+        Some(store) => helper_auth(&format!("docker-credential-{store}"), server_url).await,
+        None => Ok(None),
+    }
+}
+
+async fn helper_auth(
+    program: &str,
+    host: &str,
+) -> Result<Option<(RegistryAuth, Option<Duration>)>, DownloadRemoteImageError> {
+    let auth = run_credential_helper(program, host, HELPER_TIMEOUT).await?;
+    Ok(auth.map(|auth| (auth, Some(HELPER_CREDENTIALS_TTL))))
+}
+
+async fn read_docker_config(path: &Path) -> Result<DockerConfig, DownloadRemoteImageError> {
+    let mut file = path.to_path_buf();
+    if tokio::fs::metadata(path).await.is_ok_and(|m| m.is_dir()) {
+        for name in CONFIG_FILE_NAMES {
+            file = path.join(name);
+            if tokio::fs::try_exists(&file).await.unwrap_or(false) {
+                break;
+            }
+        }
+    }
+    let content = tokio::fs::read(&file).await.map_err(|e| {
+        DownloadRemoteImageError::DockerConfig(format!("could not read {}: {e}", file.display()))
+    })?;
+    serde_json::from_slice(&content).map_err(|e| {
+        DownloadRemoteImageError::DockerConfig(format!("invalid {}: {e}", file.display()))
+    })
+}
+
+impl AuthEntry {
+    fn to_auth(&self) -> Result<Option<RegistryAuth>, String> {
+        if let Some(encoded) = &self.auth {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()
+                .and_then(|d| String::from_utf8(d).ok())
+                .ok_or("invalid base64 auth")?;
+            let Some((username, password)) = decoded.split_once(':') else {
+                return Err("auth is not username:password".to_string());
+            };
+            return Ok(Some(RegistryAuth::Basic(
+                username.to_string(),
+                password.to_string(),
+            )));
+        }
+        Ok(self.username.as_ref().map(|username| {
+            RegistryAuth::Basic(username.clone(), self.password.clone().unwrap_or_default())
+        }))
+    }
+}
+
+/// Gets credentials from a Docker credential helper, `None` if it has none
+async fn run_credential_helper(
+    program: &str,
+    host: &str,
+    timeout: Duration,
+) -> Result<Option<RegistryAuth>, DownloadRemoteImageError> {
+    let helper_err = |e: String| DownloadRemoteImageError::DockerConfig(format!("{program}: {e}"));
+    let mut child = tokio::process::Command::new(program)
+        .arg("get")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| helper_err(e.to_string()))?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(host.as_bytes())
+        .await
+        .map_err(|e| helper_err(e.to_string()))?;
+    drop(stdin);
+    // The helper is killed when the timeout drops it
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| helper_err(format!("no answer after {timeout:?}")))?
+        .map_err(|e| helper_err(e.to_string()))?;
+
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(helper_err(format!(
+            "{} {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let creds: HelperCredentials =
+        serde_json::from_slice(&output.stdout).map_err(|e| helper_err(e.to_string()))?;
+    Ok(Some(RegistryAuth::Basic(creds.username, creds.secret)))
+}
+
+/// Registry host of a Docker config key, which can be a URL
+/// (`https://index.docker.io/v1/`) or a bare host
+fn normalize_registry(key: &str) -> String {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io".to_string(),
+        _ => host.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::oci_client::secrets::RegistryAuth;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_auths_of_pull_secret() {
+        let dir = test_temp_dir::test_temp_dir!();
+        // Layout of a mounted kubernetes.io/dockerconfigjson secret
+        let secret_dir = dir.as_path_untracked();
+        std::fs::write(
+            secret_dir.join(".dockerconfigjson"),
+            r#"{"auths": {
+                "https://index.docker.io/v1/": {"auth": "amFja3k6aHVudGVyMg=="},
+                "ghcr.io": {"username": "bot", "password": "ghp_xxx"}
+            }}"#,
+        )
+        .unwrap();
+
+        let auth = docker_config_auth(secret_dir, "docker.io").await.unwrap();
+        assert_eq!(
+            auth,
+            Some((
+                RegistryAuth::Basic("jacky".to_string(), "hunter2".to_string()),
+                None
+            ))
+        );
+        let auth = docker_config_auth(&secret_dir.join(".dockerconfigjson"), "ghcr.io")
+            .await
+            .unwrap();
+        assert_eq!(
+            auth,
+            Some((
+                RegistryAuth::Basic("bot".to_string(), "ghp_xxx".to_string()),
+                None
+            ))
+        );
+        let auth = docker_config_auth(secret_dir, "quay.io").await.unwrap();
+        assert_eq!(auth, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_credential_helper() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_temp_dir::test_temp_dir!();
+        let helper = dir.as_path_untracked().join("docker-credential-test");
+        std::fs::write(
+            &helper,
+            "#!/bin/sh\nread host\n[ \"$host\" = registry.example.com ] || { echo credentials not found in native keychain; exit 1; }\necho '{\"ServerURL\":\"registry.example.com\",\"Username\":\"AWS\",\"Secret\":\"s3cr3t\"}'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let program = helper.to_str().unwrap();
+
+        let auth = run_credential_helper(program, "registry.example.com", HELPER_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(
+            auth,
+            Some(RegistryAuth::Basic("AWS".to_string(), "s3cr3t".to_string()))
+        );
+        // Helper credentials are short-lived, unlike those of `auths`
+        let auth = helper_auth(program, "registry.example.com").await.unwrap();
+        assert_eq!(auth.unwrap().1, Some(HELPER_CREDENTIALS_TTL));
+        let auth = run_credential_helper(program, "other.example.com", HELPER_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(auth, None);
+        assert!(
+            run_credential_helper(
+                "docker-credential-missing",
+                "registry.example.com",
+                HELPER_TIMEOUT
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn kills_hanging_credential_helper() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_temp_dir::test_temp_dir!();
+        let helper = dir.as_path_untracked().join("docker-credential-hang");
+        std::fs::write(&helper, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let started = std::time::Instant::now();
+        let err = run_credential_helper(
+            helper.to_str().unwrap(),
+            "registry.example.com",
+            Duration::from_millis(200),
+        )
+        .await;
+        assert!(matches!(
+            err,
+            Err(DownloadRemoteImageError::DockerConfig(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
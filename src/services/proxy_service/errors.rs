@@ -18,9 +18,17 @@ pub enum DownloadRemoteImageError {
     #[error("OCI client error: {0}")]
     OciClientError(#[from] ::oci_client::errors::OciDistributionError),
     #[error("Storage backend error: {0}")]
-    StorageError(#[from] crate::file_storage::StorageBackendError),
+    StorageError(#[from] crate::storage::StorageBackendError),
     #[error("Could not deserialize manifest: {0}")]
     ManifestDeserializationError(#[from] serde_json::Error),
+    #[error("Could not get credentials from Docker config: {0}")]
+    DockerConfig(String),
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfig(String),
+    #[error("Could not download blob {digest}: {error}")]
+    BlobDownload { digest: String, error: String },
+    #[error("Upstream {host} is unavailable, retry in {retry_after_secs}s")]
+    UpstreamUnavailable { host: String, retry_after_secs: u64 },
     #[error("Could not get AWS ECR password: {0}")]
     EcrLoginError(#[from] EcrPasswordError),
 }
@@ -30,6 +38,8 @@ pub enum DownloadRemoteImageError {
 pub enum EcrPasswordError {
     #[error("Could not parse region from ECR URL")]
     InvalidRegion,
+    #[error("Invalid egress proxy: {0}")]
+    EgressProxy(String),
     #[error("Could not decode ECR token: {0}")]
     Base64DecodeError(#[from] base64::DecodeError),
     #[error("Could not convert ECR token to UTF8: {0}")]
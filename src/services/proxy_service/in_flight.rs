@@ -0,0 +1,294 @@
+//! Proxied blobs being downloaded, readable while the download is in progress.
+//!
+//! The downloaded bytes are spooled to a file that readers follow, so a blob
+//! is never held in memory, whenever readers arrive and whatever part of the
+//! blob they ask for.
+
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use futures::{StreamExt, TryStreamExt};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use crate::storage::BlobStream;
+
+/// Size of the reads from the spool file
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Default)]
+struct Progress {
+    /// Number of bytes written to the spool file so far
+    written: u64,
+    /// Set once the download is over
+    result: Option<Result<(), String>>,
+}
+
+/// A blob being downloaded from upstream.
+/// Any number of readers can follow the download, whenever they arrive.
+#[derive(Debug)]
+pub(crate) struct InFlightBlob {
+    size: u64,
+    /// Copy of the bytes downloaded so far, removed with the last reader
+    spool: PathBuf,
+    progress: watch::Sender<Progress>,
+}
+
+impl InFlightBlob {
+    fn new(size: u64, spool: PathBuf) -> Self {
+        Self {
+            size,
+            spool,
+            progress: watch::Sender::new(Progress::default()),
+        }
+    }
+
+    /// Size announced by the manifest referencing the blob
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads the blob, or only the given byte range of it, waiting for the
+    /// parts not downloaded yet.
+    /// The range must be within the blob, see [`InFlightBlob::size`].
+    pub fn reader(
+        self: &Arc<Self>,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Pin<Box<dyn AsyncRead + Send>> {
+        let (start, end) = match range {
+            Some(range) => (*range.start(), range.end() + 1),
+            None => (0, self.size),
+        };
+        let state = (self.clone(), self.progress.subscribe(), start, None::<File>);
+        let stream = futures::stream::try_unfold(state, move |(this, mut rx, offset, file)| {
+            async move {
+                if offset >= end {
+                    // Failures such as a digest mismatch are only known at the end
+                    if end == this.size {
+                        this.wait().await.map_err(io::Error::other)?;
+                    }
+                    return Ok(None);
+                }
+                let written = loop {
+                    let (written, result) = {
+                        let progress = rx.borrow_and_update();
+                        (progress.written, progress.result.clone())
+                    };
+                    match result {
+                        _ if written > offset => break written,
+                        Some(Ok(())) => {
+                            let msg = "Download ended before the announced size";
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+                        }
+                        Some(Err(e)) => return Err(io::Error::other(e)),
+                        None => {
+                            if rx.changed().await.is_err() {
+                                return Err(io::Error::other("Download was abandoned"));
+                            }
+                        }
+                    }
+                };
+                let mut file = match file {
+                    Some(file) => file,
+                    None => {
+                        let mut file = File::open(&this.spool).await?;
+                        file.seek(SeekFrom::Start(offset)).await?;
+                        file
+                    }
+                };
+                let len = (written.min(end) - offset).min(READ_CHUNK_SIZE);
+                let mut chunk = BytesMut::zeroed(len as usize);
+                file.read_exact(&mut chunk).await?;
+                Ok(Some((chunk.freeze(), (this, rx, offset + len, Some(file)))))
+            }
+        });
+        Box::pin(stream.boxed().into_async_read().compat())
+    }
+
+    /// Spools the chunks of `upstream` as they are read from the returned stream
+    pub fn tee<'a>(self: &Arc<Self>, upstream: BlobStream<'a>) -> BlobStream<'a> {
+        let state = (self.clone(), upstream, None::<File>);
+        futures::stream::try_unfold(state, |(this, mut upstream, file)| async move {
+            let Some(chunk) = upstream.try_next().await? else {
+                return Ok(None);
+            };
+            let mut file = match file {
+                Some(file) => file,
+                None => {
+                    if let Some(dir) = this.spool.parent() {
+                        tokio::fs::create_dir_all(dir).await?;
+                    }
+                    File::create(&this.spool).await?
+                }
+            };
+            file.write_all(&chunk).await?;
+            // Readers open the file on their own, it must be written through
+            file.flush().await?;
+            let len = chunk.len() as u64;
+            this.progress.send_modify(|p| p.written += len);
+            Ok(Some((chunk, (this, upstream, Some(file)))))
+        })
+        .boxed()
+    }
+
+    /// Waits for the end of the download
+    pub async fn wait(&self) -> Result<(), String> {
+        let mut rx = self.progress.subscribe();
+        let progress = rx
+            .wait_for(|p| p.result.is_some())
+            .await
+            .map_err(|_| "Download was abandoned".to_string())?;
+        progress.result.clone().unwrap()
+    }
+
+    /// Marks the download as over, readers get an error if it failed
+    pub fn finish(&self, result: Result<(), String>) {
+        self.progress.send_modify(|p| p.result = Some(result));
+    }
+}
+
+impl Drop for InFlightBlob {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.spool)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            tracing::warn!("Could not remove {}: {e}", self.spool.display());
+        }
+    }
+}
+
+/// Downloads in progress, by blob digest
+#[derive(Debug)]
+pub(crate) struct InFlightDownloads {
+    /// Where the downloads are spooled
+    dir: PathBuf,
+    blobs: Mutex<HashMap<String, Arc<InFlightBlob>>>,
+}
+
+impl Default for InFlightDownloads {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir())
+    }
+}
+
+impl InFlightDownloads {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            blobs: Mutex::default(),
+        }
+    }
+
+    pub fn get(&self, digest: &str) -> Option<Arc<InFlightBlob>> {
+        self.blobs.lock().unwrap().get(digest).cloned()
+    }
+
+    /// Returns the download of `digest` in progress, or registers a new one.
+    /// The boolean is true if the caller is in charge of a new download.
+    pub fn get_or_insert(&self, digest: &str, size: u64) -> (Arc<InFlightBlob>, bool) {
+        let mut downloads = self.blobs.lock().unwrap();
+        if let Some(blob) = downloads.get(digest) {
+            return (blob.clone(), false);
+        }
+        let spool = self.dir.join(format!("{digest}.{}", uuid::Uuid::new_v4()));
+        let blob = Arc::new(InFlightBlob::new(size, spool));
+        downloads.insert(digest.to_string(), blob.clone());
+        (blob, true)
+    }
+
+    pub fn remove(&self, digest: &str) {
+        self.blobs.lock().unwrap().remove(digest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::InFlightDownloads;
+
+    #[tokio::test]
+    async fn readers_share_download() {
+        let dir = test_temp_dir::test_temp_dir!();
+        let downloads = InFlightDownloads::new(dir.as_path_untracked().to_owned());
+        let (blob, is_new) = downloads.get_or_insert("sha256:aaa", 6);
+        assert!(is_new);
+        let (same_blob, is_new) = downloads.get_or_insert("sha256:aaa", 6);
+        assert!(!is_new);
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<std::io::Result<Bytes>>();
+        let mut teed = blob.tee(tokio_stream_from(rx));
+
+        let mut early_reader = blob.reader(None);
+        tx.send(Ok(Bytes::from_static(b"abc"))).unwrap();
+        teed.next().await.unwrap().unwrap();
+        // Joins after the first chunk went through
+        let mut late_reader = same_blob.reader(None);
+        let mut range_reader = same_blob.reader(Some(2..=4));
+        tx.send(Ok(Bytes::from_static(b"def"))).unwrap();
+        teed.next().await.unwrap().unwrap();
+        blob.finish(Ok(()));
+
+        for reader in [&mut early_reader, &mut late_reader] {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"abcdef");
+        }
+        let mut buf = Vec::new();
+        range_reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"cde");
+
+        downloads.remove("sha256:aaa");
+        assert!(downloads.get("sha256:aaa").is_none());
+        drop((
+            blob,
+            same_blob,
+            teed,
+            early_reader,
+            late_reader,
+            range_reader,
+        ));
+        let spooled = std::fs::read_dir(dir.as_path_untracked()).unwrap().count();
+        assert_eq!(spooled, 0);
+    }
+
+    #[tokio::test]
+    async fn readers_see_failed_download() {
+        let dir = test_temp_dir::test_temp_dir!();
+        let downloads = InFlightDownloads::new(dir.as_path_untracked().to_owned());
+        let (blob, _) = downloads.get_or_insert("sha256:aaa", 6);
+        let mut reader = blob.reader(None);
+        blob.finish(Err("upstream went away".to_string()));
+
+        let mut buf = Vec::new();
+        assert!(reader.read_to_end(&mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn readers_see_truncated_download() {
+        let dir = test_temp_dir::test_temp_dir!();
+        let downloads = InFlightDownloads::new(dir.as_path_untracked().to_owned());
+        let (blob, _) = downloads.get_or_insert("sha256:aaa", 6);
+        let mut teed = blob.tee(futures::stream::iter([Ok(Bytes::from_static(b"abc"))]).boxed());
+        while teed.next().await.is_some() {}
+        blob.finish(Ok(()));
+
+        let mut buf = Vec::new();
+        assert!(blob.reader(None).read_to_end(&mut buf).await.is_err());
+    }
+
+    fn tokio_stream_from(
+        mut rx: tokio::sync::mpsc::UnboundedReceiver<std::io::Result<Bytes>>,
+    ) -> crate::storage::BlobStream<'static> {
+        futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+    }
+}
@@ -1,32 +1,163 @@
 //! Proxy service: downloads proxied images from remote registries.
 
+pub(crate) mod docker_config;
 pub(crate) mod errors;
+pub(crate) mod in_flight;
 pub(crate) mod oci_client;
+pub(crate) mod upstreams;
 
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use ::oci_client::Reference;
+use ::oci_client::errors::OciDistributionError;
+use ::oci_client::manifest::ImageIndexEntry;
 use ::oci_client::secrets::RegistryAuth;
-use futures::future::try_join_all;
+use oci_spec::image::Platform;
 
 use self::errors::DownloadRemoteImageError;
-use self::oci_client::{MIME_TYPES_DISTRIBUTION_MANIFEST, get_oci_client};
-use crate::configuration::SingleRegistryProxyConfig;
-use crate::file_storage::FileStorage;
+use self::in_flight::{InFlightBlob, InFlightDownloads};
+use self::oci_client::{MIME_TYPES_DISTRIBUTION_MANIFEST, OciClients};
+use self::upstreams::{UpstreamStatus, Upstreams, is_unavailable, rate_limited_url};
+use crate::PROXY_DIR;
+use crate::configuration::{
+    EgressProxyConfig, RegistryProxiesConfig, ServeStalePolicy, SingleRegistryProxyConfig,
+};
 use crate::repositories::Repositories;
 use crate::services::Error;
-use crate::utils::digest::DigestError;
-use crate::utils::manifest::OCIManifest;
+use crate::services::referrers_service::referrers_tag;
+use crate::storage::{BlobStream, StorageBackend};
+use crate::utils::digest::{Digest, DigestError};
+use crate::utils::manifest::{OCIManifest, layer_is_distributable, platform_matches};
+
+/// Tags asked per page when listing all the tags of an upstream repository
+const TAGS_PAGE_SIZE: usize = 1000;
+/// Listing all the tags of an upstream repository stops after this many pages
+const MAX_TAGS_PAGES: usize = 1000;
+
+/// Name of the local repository caching a proxied image
+pub fn proxied_repo_name(image: &Reference) -> String {
+    format!("f/{}/{}", image.registry(), image.repository())
+}
+
+/// Tags of an upstream repository, see [`ProxyService::list_all_remote_tags`]
+#[derive(Debug, Default)]
+pub struct RemoteTags {
+    pub tags: Vec<String>,
+    /// Whether every page was listed
+    pub complete: bool,
+}
 
 #[derive(Debug)]
 pub struct ProxyService {
     repos: Arc<Repositories>,
-    storage: Arc<FileStorage>,
+    storage: Arc<dyn StorageBackend>,
+    downloads: Arc<InFlightDownloads>,
+    upstreams: Arc<Upstreams>,
+    clients: Arc<OciClients>,
 }
 
 impl ProxyService {
-    pub fn new(repos: Arc<Repositories>, storage: Arc<FileStorage>) -> Self {
-        Self { repos, storage }
+    pub fn new(repos: Arc<Repositories>, storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            repos,
+            storage,
+            downloads: Arc::default(),
+            upstreams: Arc::default(),
+            clients: Arc::default(),
+        }
+    }
+
+    /// Sends the requests to upstream registries through `egress_proxy`,
+    /// unless their config sets another one
+    pub fn with_egress_proxy(mut self, egress_proxy: Option<EgressProxyConfig>) -> Self {
+        self.clients = Arc::new(OciClients::new(egress_proxy));
+        self
+    }
+
+    /// In mirror mode, the proxied repository serving a pull of `repo`:
+    /// only for repos that aren't proxied already, qualified by a namespace,
+    /// or stored locally.
+    pub async fn mirrored_repo(
+        &self,
+        config: &RegistryProxiesConfig,
+        repo: &str,
+        namespace: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        if namespace.is_some() || repo.starts_with(PROXY_DIR) {
+            return Ok(None);
+        }
+        let Some(mirrored) = config.mirror_repo(repo) else {
+            return Ok(None);
+        };
+        if self.repos.repo_blob_assoc.repo_exists(repo).await? {
+            return Ok(None);
+        }
+        Ok(Some(mirrored))
+    }
+
+    /// Health of the upstream registries that had failures
+    pub fn upstream_status(&self) -> Vec<UpstreamStatus> {
+        self.upstreams.status()
+    }
+
+    /// Tracks the health of `host`, and drops its cached client if the
+    /// credentials were refused
+    fn record<T>(
+        &self,
+        host: &str,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        result: &Result<T, OciDistributionError>,
+    ) {
+        self.upstreams.record(host, result);
+        if let Err(e) = result
+            && let Some(url) = rate_limited_url(e)
+        {
+            let clients = self.clients.clone();
+            let upstreams = self.upstreams.clone();
+            let host = host.to_string();
+            let proxy_config = proxy_config.cloned();
+            let url = url.to_string();
+            tokio::spawn(async move {
+                if let Some(retry_after) = clients
+                    .retry_after(&host, proxy_config.as_ref(), &url)
+                    .await
+                {
+                    upstreams.record_retry_after(&host, retry_after);
+                }
+            });
+        }
+        if let Err(
+            OciDistributionError::UnauthorizedError { .. }
+            | OciDistributionError::AuthenticationFailure(_),
+        ) = result
+        {
+            self.clients.invalidate(host, proxy_config);
+        }
+    }
+
+    /// Fails without calling `host` if it is backed off or its circuit is
+    /// open. Otherwise the caller is expected to call it: while the circuit
+    /// is half-open, that is the single trial request.
+    fn ensure_available(&self, host: &str) -> Result<(), Error> {
+        match self.upstreams.admit(host) {
+            Some(left) => Err(upstream_unavailable(host, left)),
+            None => Ok(()),
+        }
+    }
+
+    /// Spools the blobs being downloaded in `dir` rather than the temp dir
+    pub fn with_download_dir(mut self, dir: PathBuf) -> Self {
+        self.downloads = Arc::new(InFlightDownloads::new(dir));
+        self
+    }
+
+    /// The blob `digest` if it is currently being downloaded
+    pub(crate) fn in_flight_blob(&self, digest: &str) -> Option<Arc<InFlightBlob>> {
+        self.downloads.get(digest)
     }
 
     /// Returns the manifest digest that was resolved/downloaded.
@@ -35,105 +166,537 @@ impl ProxyService {
         image: &Reference,
         proxy_config: Option<&SingleRegistryProxyConfig>,
     ) -> Result<String, Error> {
-        let repo_name = format!("f/{}/{}", image.registry(), image.repository());
+        let platforms = proxy_config.map_or(&[][..], |c| &c.platforms);
+        self.download_image_with(image, proxy_config, IndexPlatforms::Only(platforms))
+            .await
+    }
+
+    /// Like [`Self::download_image`], also downloading the manifests of
+    /// `platforms` (all if empty) for an index
+    pub async fn prefetch_image(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        platforms: &[String],
+    ) -> Result<String, Error> {
+        let platforms = match platforms {
+            [] => IndexPlatforms::All,
+            platforms => IndexPlatforms::Only(platforms),
+        };
+        self.download_image_with(image, proxy_config, platforms)
+            .await
+    }
+
+    async fn download_image_with(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        platforms: IndexPlatforms<'_>,
+    ) -> Result<String, Error> {
+        let repo_name = proxied_repo_name(image);
         tracing::debug!("Downloading proxied image {}", repo_name);
 
-        let try_cl = match get_oci_client(image.registry(), proxy_config).await {
-            Ok(cl) => Some(cl),
-            Err(e) => {
-                tracing::warn!("Could not get an OCI client: {e}");
+        if let Some(digest) = self
+            .fresh_cached_digest(image, &repo_name, proxy_config)
+            .await?
+        {
+            return Ok(digest);
+        }
+
+        let local_digest = match (image.digest(), image.tag()) {
+            (Some(digest), _) => {
+                let has_manifest = self
+                    .repos
+                    .repo_blob_assoc
+                    .manifest_exists_in_repo(digest, &repo_name)
+                    .await?;
+                if has_manifest {
+                    return Ok(digest.to_string());
+                }
                 None
             }
+            (None, Some(tag)) => self.repos.tag.find_manifest_digest(&repo_name, tag).await?,
+            (None, None) => return Err(Error::Digest(DigestError::InvalidDigest(String::new()))),
         };
 
-        let digests = self
-            .collect_candidate_digests(image, &repo_name, try_cl.as_ref())
-            .await?;
+        // The registry itself comes last: its answer decides whether the
+        // cached tag can be served
+        let mut origin_failure = None;
+        for endpoint in endpoints(image, proxy_config) {
+            match self
+                .download_from(
+                    image,
+                    &endpoint,
+                    &repo_name,
+                    local_digest.as_deref(),
+                    platforms,
+                )
+                .await?
+            {
+                Ok(digest) => return Ok(digest),
+                Err(failure) => {
+                    tracing::warn!(
+                        "Could not get {image} from {}: {failure}",
+                        endpoint.reference.registry()
+                    );
+                    origin_failure = Some(failure);
+                }
+            }
+        }
 
-        for mani_digest in digests {
+        let policy = proxy_config.map(|c| c.serve_stale).unwrap_or_default();
+        let serve_local = match policy {
+            ServeStalePolicy::Always => true,
+            ServeStalePolicy::WhenUnavailable => match &origin_failure {
+                Some(EndpointFailure::Check(e)) => is_unavailable(e),
+                _ => true,
+            },
+            ServeStalePolicy::Never => false,
+        };
+        if serve_local && let Some(local_digest) = local_digest {
             let has_manifest = self
                 .repos
                 .repo_blob_assoc
-                .manifest_exists_in_repo(&mani_digest, &repo_name)
+                .manifest_exists_in_repo(&local_digest, &repo_name)
                 .await?;
             if has_manifest {
-                return Ok(mani_digest);
+                return Ok(local_digest);
             }
-            if let Some((cl, auth)) = &try_cl {
-                let ref_to_dl = image.clone_with_digest(mani_digest.clone());
-                match self
-                    .download_manifest_and_layers(cl, auth, &ref_to_dl, &repo_name)
-                    .await
-                {
-                    Err(e) => tracing::warn!("Failed to download proxied image: {}", e),
-                    Ok(()) => {
-                        if let Some(tag) = image.tag() {
-                            self.repos.tag.upsert(tag, &repo_name, &mani_digest).await?;
+        }
+
+        // Tell clients when to come back rather than failing
+        if let Some(left) = self.upstreams.blocked_for(image.registry()) {
+            return Err(upstream_unavailable(image.registry(), left));
+        }
+        match origin_failure {
+            Some(EndpointFailure::Check(e)) => Err(DownloadRemoteImageError::from(e).into()),
+            _ => Err(DownloadRemoteImageError::DownloadAttemptsFailed.into()),
+        }
+    }
+
+    /// Gets `image` from one of its endpoints: checks the tag, then downloads
+    /// the manifest unless the cached one is up to date.
+    /// The outer error is a local failure, the inner one the endpoint's.
+    async fn download_from(
+        &self,
+        image: &Reference,
+        endpoint: &Endpoint<'_>,
+        repo_name: &str,
+        local_digest: Option<&str>,
+        platforms: IndexPlatforms<'_>,
+    ) -> Result<Result<String, EndpointFailure>, Error> {
+        let host = endpoint.reference.registry();
+        if let Err(e) = self.ensure_available(host) {
+            return Ok(Err(EndpointFailure::Unavailable(e.to_string())));
+        }
+        let (cl, auth) = match self.clients.get(host, endpoint.config.as_deref()).await {
+            Ok(cl) => cl,
+            Err(e) => {
+                let msg = format!("could not get an OCI client: {e}");
+                return Ok(Err(EndpointFailure::Unavailable(msg)));
+            }
+        };
+
+        let digest = match image.digest() {
+            Some(digest) => digest.to_string(),
+            None => {
+                let remote = cl.fetch_manifest_digest(&endpoint.reference, &auth).await;
+                self.record(host, endpoint.config.as_deref(), &remote);
+                match remote {
+                    Ok(digest) => digest,
+                    Err(e) => return Ok(Err(EndpointFailure::Check(e))),
+                }
+            }
+        };
+        let up_to_date = local_digest == Some(digest.as_str())
+            && self
+                .repos
+                .repo_blob_assoc
+                .manifest_exists_in_repo(&digest, repo_name)
+                .await?;
+        if !up_to_date {
+            let ref_to_dl = endpoint.reference.clone_with_digest(digest.clone());
+            if let Err(e) = self
+                .download_manifest_and_layers(
+                    &cl,
+                    &auth,
+                    &ref_to_dl,
+                    repo_name,
+                    endpoint.config.as_deref(),
+                    platforms,
+                )
+                .await
+            {
+                return Ok(Err(EndpointFailure::Download(e)));
+            }
+        }
+        if let Some(tag) = image.tag() {
+            self.repos.tag.upsert(tag, repo_name, &digest).await?;
+            self.repos.tag.mark_checked(repo_name, tag).await?;
+        }
+        Ok(Ok(digest))
+    }
+
+    /// Copies the tag `image` into the local repo `target` with the manifests
+    /// of `platforms` (all if empty) and all their blobs. Unlike proxied
+    /// repos, nothing is left to download: the tag is only moved once the
+    /// whole image is stored.
+    pub async fn copy_image(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        target: &str,
+        platforms: &[String],
+    ) -> Result<String, Error> {
+        let Some(tag) = image.tag() else {
+            return Err(Error::Digest(DigestError::InvalidDigest(image.to_string())));
+        };
+        let mut last_err = None;
+        for endpoint in endpoints(image, proxy_config) {
+            let host = endpoint.reference.registry();
+            let copied = async {
+                self.ensure_available(host)?;
+                let (cl, auth) = self.clients.get(host, endpoint.config.as_deref()).await?;
+                let remote = cl.fetch_manifest_digest(&endpoint.reference, &auth).await;
+                self.record(host, endpoint.config.as_deref(), &remote);
+                let digest = remote.map_err(DownloadRemoteImageError::from)?;
+                let local = self.repos.tag.find_manifest_digest(target, tag).await?;
+                if local.as_deref() != Some(digest.as_str()) {
+                    let ref_ = endpoint.reference.clone_with_digest(digest.clone());
+                    self.copy_manifest(
+                        &cl,
+                        &auth,
+                        &ref_,
+                        target,
+                        endpoint.config.as_deref(),
+                        platforms,
+                    )
+                    .await?;
+                    self.repos.tag.upsert(tag, target, &digest).await?;
+                }
+                Ok::<_, Error>(digest)
+            };
+            match copied.await {
+                Ok(digest) => return Ok(digest),
+                Err(e) => {
+                    tracing::debug!("Could not copy {} into {target}: {e}", endpoint.reference);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or(DownloadRemoteImageError::DownloadAttemptsFailed.into()))
+    }
+
+    /// Stores the manifest `ref_` in `target` once its blobs, or the
+    /// manifests of `platforms` for an index, are stored
+    async fn copy_manifest(
+        &self,
+        cl: &::oci_client::Client,
+        auth: &RegistryAuth,
+        ref_: &Reference,
+        target: &str,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        platforms: &[String],
+    ) -> Result<(), Error> {
+        let pulled = cl
+            .pull_manifest_raw(ref_, auth, MIME_TYPES_DISTRIBUTION_MANIFEST)
+            .await;
+        self.record(ref_.registry(), proxy_config, &pulled);
+        let (raw_manifest, digest) = pulled.map_err(DownloadRemoteImageError::from)?;
+        let manifest: OCIManifest =
+            serde_json::from_slice(&raw_manifest).map_err(DownloadRemoteImageError::from)?;
+
+        let mut blobs = Vec::new();
+        match &manifest {
+            OCIManifest::V2(m) => {
+                let distributable_layers = m
+                    .layers()
+                    .iter()
+                    .filter(|l| layer_is_distributable(l.media_type()));
+                let mut downloads = Vec::new();
+                for descriptor in std::iter::once(m.config()).chain(distributable_layers) {
+                    let blob_digest = descriptor.digest().to_string();
+                    if !self.repos.blob.exists(&blob_digest).await? {
+                        let download = self.start_blob_download(
+                            cl,
+                            ref_,
+                            &blob_digest,
+                            descriptor.size(),
+                            target,
+                            None,
+                        );
+                        downloads.push((blob_digest.clone(), download));
+                    }
+                    blobs.push(blob_digest);
+                }
+                for (blob_digest, download) in downloads {
+                    download.wait().await.map_err(|error| {
+                        DownloadRemoteImageError::BlobDownload {
+                            digest: blob_digest,
+                            error,
                         }
-                        return Ok(mani_digest);
+                    })?;
+                }
+            }
+            OCIManifest::List(index) => {
+                let wanted = index.manifests().iter().filter(|m| {
+                    platforms.is_empty()
+                        || m.platform()
+                            .as_ref()
+                            .is_some_and(|p| platforms.iter().any(|w| platform_matches(p, w)))
+                });
+                for child in wanted {
+                    let child_digest = child.digest().to_string();
+                    let has_manifest = self
+                        .repos
+                        .repo_blob_assoc
+                        .manifest_exists_in_repo(&child_digest, target)
+                        .await?;
+                    if !has_manifest {
+                        let child_ref = ref_.clone_with_digest(child_digest);
+                        let copy =
+                            self.copy_manifest(cl, auth, &child_ref, target, proxy_config, &[]);
+                        Box::pin(copy).await?;
                     }
                 }
             }
         }
 
-        Err(Error::Proxy(Box::new(
-            DownloadRemoteImageError::DownloadAttemptsFailed,
-        )))
+        self.repos
+            .manifest
+            .insert_or_ignore(&digest, &raw_manifest)
+            .await?;
+        self.repos
+            .repo_blob_assoc
+            .insert_manifest_assoc_safe(target, &digest)
+            .await?;
+        for blob_digest in blobs {
+            link_blob(&self.repos, &blob_digest, target).await?;
+        }
+        Ok(())
     }
 
-    async fn collect_candidate_digests(
+    /// Caches the referrers (signatures, SBOMs...) that the upstream registry
+    /// lists for `subject`, which must be a digest reference. Falls back to the
+    /// referrers tag schema for upstreams without referrers API support.
+    pub async fn download_referrers(
         &self,
-        image: &Reference,
-        repo_name: &str,
-        cl: Option<&(::oci_client::Client, RegistryAuth)>,
-    ) -> Result<Vec<String>, Error> {
-        if let Some(d) = image.digest() {
-            return Ok(vec![d.to_string()]);
+        subject: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+    ) -> Result<(), Error> {
+        let Some(subject_digest) = subject.digest() else {
+            return Err(Error::Digest(DigestError::InvalidDigest(
+                subject.to_string(),
+            )));
+        };
+        let repo_name = proxied_repo_name(subject);
+
+        let mut last_err = None;
+        for endpoint in endpoints(subject, proxy_config) {
+            match self.referrers_from(&endpoint, subject_digest).await {
+                Ok((cl, auth, descriptors)) => {
+                    for descriptor in descriptors {
+                        let has_manifest = self
+                            .repos
+                            .repo_blob_assoc
+                            .manifest_exists_in_repo(&descriptor.digest, &repo_name)
+                            .await?;
+                        if has_manifest {
+                            continue;
+                        }
+                        let ref_to_dl = endpoint.reference.clone_with_digest(descriptor.digest);
+                        if let Err(e) = self
+                            .download_manifest_and_layers(
+                                &cl,
+                                &auth,
+                                &ref_to_dl,
+                                &repo_name,
+                                endpoint.config.as_deref(),
+                                IndexPlatforms::Only(&[]),
+                            )
+                            .await
+                        {
+                            tracing::warn!("Failed to download referrer {ref_to_dl}: {e}");
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::debug!("No referrers from {}: {e}", endpoint.reference);
+                    last_err = Some(e);
+                }
+            }
         }
-        let Some(tag) = image.tag() else {
-            return Err(Error::Digest(DigestError::InvalidDigest(String::new())));
+        Err(last_err.unwrap_or(DownloadRemoteImageError::DownloadAttemptsFailed.into()))
+    }
+
+    /// Referrers listed by one endpoint, with the referrers API or the
+    /// referrers tag
+    async fn referrers_from(
+        &self,
+        endpoint: &Endpoint<'_>,
+        subject_digest: &str,
+    ) -> Result<(::oci_client::Client, RegistryAuth, Vec<ImageIndexEntry>), Error> {
+        let subject = &endpoint.reference;
+        self.ensure_available(subject.registry())?;
+        let (cl, auth) = self
+            .clients
+            .get(subject.registry(), endpoint.config.as_deref())
+            .await?;
+        cl.store_auth_if_needed(subject.resolve_registry(), &auth)
+            .await;
+
+        let referrers = cl.pull_referrers(subject, None).await;
+        self.record(subject.registry(), endpoint.config.as_deref(), &referrers);
+        let descriptors = match referrers {
+            Ok(index) => index.manifests,
+            Err(e) => {
+                tracing::debug!("Referrers API failed for {subject} ({e}), trying referrers tag");
+                let tag = referrers_tag(&Digest::try_from_raw(subject_digest)?);
+                let tag_ref = Reference::with_tag(
+                    subject.registry().to_string(),
+                    subject.repository().to_string(),
+                    tag,
+                );
+                let raw_index = cl
+                    .pull_manifest_raw(&tag_ref, &auth, MIME_TYPES_DISTRIBUTION_MANIFEST)
+                    .await;
+                self.record(subject.registry(), endpoint.config.as_deref(), &raw_index);
+                let (raw_index, _) = raw_index.map_err(DownloadRemoteImageError::from)?;
+                serde_json::from_slice::<::oci_client::manifest::OciImageIndex>(&raw_index)
+                    .map_err(DownloadRemoteImageError::from)?
+                    .manifests
+            }
         };
+        Ok((cl, auth, descriptors))
+    }
 
-        let mut digests = Vec::new();
-        let local_digest = self.repos.tag.find_manifest_digest(repo_name, tag).await?;
+    /// Lists the tags of a proxied repository on its upstream registry
+    pub async fn list_remote_tags(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        last: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<String>, Error> {
+        let mut last_err = None;
+        for endpoint in endpoints(image, proxy_config) {
+            let host = endpoint.reference.registry();
+            let tags = async {
+                self.ensure_available(host)?;
+                let (cl, auth) = self.clients.get(host, endpoint.config.as_deref()).await?;
+                let tags = cl.list_tags(&endpoint.reference, &auth, limit, last).await;
+                self.record(host, endpoint.config.as_deref(), &tags);
+                Ok::<_, Error>(tags.map_err(DownloadRemoteImageError::from)?.tags)
+            };
+            match tags.await {
+                Ok(tags) => return Ok(tags),
+                Err(e) => {
+                    tracing::debug!("Could not list tags of {}: {e}", endpoint.reference);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or(DownloadRemoteImageError::DownloadAttemptsFailed.into()))
+    }
 
-        if let Some((cl, auth)) = cl {
-            if let Ok(remote) = cl.fetch_manifest_digest(image, auth).await {
-                if Some(&remote) != local_digest.as_ref() {
-                    digests.push(remote);
+    /// Lists all the tags of a proxied repository on its upstream registry,
+    /// page after page. Tags listed before a page fails are returned, as an
+    /// incomplete listing.
+    pub async fn list_all_remote_tags(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+    ) -> Result<RemoteTags, Error> {
+        let mut listed = RemoteTags::default();
+        let mut seen = HashSet::new();
+        let mut last: Option<String> = None;
+        for _ in 0..MAX_TAGS_PAGES {
+            // Registries can send less than asked, only an empty page ends the list
+            let page = self
+                .list_remote_tags(image, proxy_config, last.as_deref(), Some(TAGS_PAGE_SIZE))
+                .await;
+            let page = match page {
+                Ok(page) => page,
+                Err(e) if last.is_none() => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Could not list all tags of {image}: {e}");
+                    return Ok(listed);
                 }
-            } else {
-                tracing::warn!("Failed to fetch remote tag digest");
+            };
+            if page.is_empty() {
+                listed.complete = true;
+                return Ok(listed);
+            }
+            last = page.last().cloned();
+            let before = listed.tags.len();
+            listed
+                .tags
+                .extend(page.into_iter().filter(|t| seen.insert(t.clone())));
+            if listed.tags.len() == before {
+                // Registries ignoring `last` send the same page again
+                tracing::warn!("Could not list all tags of {image}: pagination is not supported");
+                return Ok(listed);
             }
         }
-        if let Some(local_digest) = local_digest {
-            digests.push(local_digest);
+        tracing::warn!("Could not list all tags of {image}: more than {MAX_TAGS_PAGES} pages");
+        Ok(listed)
+    }
+
+    /// The cached digest of a tag checked against upstream less than
+    /// `tag_ttl_secs` ago, if its manifest is still cached
+    async fn fresh_cached_digest(
+        &self,
+        image: &Reference,
+        repo_name: &str,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+    ) -> Result<Option<String>, Error> {
+        let (Some(tag), Some(ttl)) = (image.tag(), proxy_config.and_then(|c| c.tag_ttl_secs))
+        else {
+            return Ok(None);
+        };
+        let Some(cached) = self.repos.tag.find(repo_name, tag).await? else {
+            return Ok(None);
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let fresh = cached
+            .last_checked
+            .is_some_and(|checked| now.saturating_sub(checked) < ttl as i64);
+        if !fresh {
+            return Ok(None);
         }
-        Ok(digests)
+        let has_manifest = self
+            .repos
+            .repo_blob_assoc
+            .manifest_exists_in_repo(&cached.manifest_digest, repo_name)
+            .await?;
+        Ok(has_manifest.then_some(cached.manifest_digest))
     }
 
+    /// Stores the manifest `ref_` and starts downloading its blobs in the
+    /// background; they are served from the download while it's in progress.
+    /// For an index, the manifests of `platforms` are downloaded as well.
     async fn download_manifest_and_layers(
         &self,
         cl: &::oci_client::Client,
         auth: &RegistryAuth,
         ref_: &Reference,
         local_repo_name: &str,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+        platforms: IndexPlatforms<'_>,
     ) -> Result<(), Error> {
         tracing::debug!("Downloading manifest + layers for {}", ref_);
 
-        let (raw_manifest, digest) = cl
+        let pulled = cl
             .pull_manifest_raw(ref_, auth, MIME_TYPES_DISTRIBUTION_MANIFEST)
-            .await
-            .map_err(DownloadRemoteImageError::from)?;
+            .await;
+        self.record(ref_.registry(), proxy_config, &pulled);
+        let (raw_manifest, digest) = pulled.map_err(DownloadRemoteImageError::from)?;
         let manifest: OCIManifest =
             serde_json::from_slice(&raw_manifest).map_err(DownloadRemoteImageError::from)?;
 
-        let blobs = manifest.get_local_blob_digests();
-        let futures = blobs
-            .iter()
-            .map(|l| self.download_blob(cl, ref_, l, local_repo_name));
-        try_join_all(futures).await?;
-
         self.repos
             .manifest
             .insert_or_ignore(&digest, &raw_manifest)
@@ -142,50 +705,352 @@ impl ProxyService {
             .repo_blob_assoc
             .insert_manifest_assoc_safe(local_repo_name, &digest)
             .await?;
+
+        match &manifest {
+            OCIManifest::V2(m) => {
+                let distributable_layers = m
+                    .layers()
+                    .iter()
+                    .filter(|l| layer_is_distributable(l.media_type()));
+                for descriptor in std::iter::once(m.config()).chain(distributable_layers) {
+                    let blob_digest = descriptor.digest().as_ref();
+                    if self.repos.blob.exists(blob_digest).await? {
+                        self.repos
+                            .repo_blob_assoc
+                            .insert_blob_assoc_safe(local_repo_name, blob_digest)
+                            .await?;
+                    } else {
+                        self.start_blob_download(
+                            cl,
+                            ref_,
+                            blob_digest,
+                            descriptor.size(),
+                            local_repo_name,
+                            Some(&digest),
+                        );
+                    }
+                }
+            }
+            OCIManifest::List(index) => {
+                let wanted = index
+                    .manifests()
+                    .iter()
+                    .filter(|m| platforms.wants(m.platform().as_ref()));
+                for child in wanted {
+                    let child_digest = child.digest().to_string();
+                    let has_manifest = self
+                        .repos
+                        .repo_blob_assoc
+                        .manifest_exists_in_repo(&child_digest, local_repo_name)
+                        .await?;
+                    if has_manifest {
+                        continue;
+                    }
+                    let child_ref = ref_.clone_with_digest(child_digest);
+                    let download = self.download_manifest_and_layers(
+                        cl,
+                        auth,
+                        &child_ref,
+                        local_repo_name,
+                        proxy_config,
+                        IndexPlatforms::Only(&[]),
+                    );
+                    if let Err(e) = Box::pin(download).await {
+                        tracing::warn!("Failed to prefetch {child_ref}: {e}");
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    async fn download_blob(
+    /// Downloads a blob in the background, unless it is already being downloaded.
+    /// `manifest` is the manifest stored before the blob, see [`Self::spawn_blob_write`].
+    fn start_blob_download(
         &self,
         cl: &::oci_client::Client,
         ref_: &Reference,
-        layer_digest: &str,
+        digest: &str,
+        size: u64,
         local_repo_name: &str,
-    ) -> Result<(), Error> {
-        tracing::trace!("Downloading blob {}", layer_digest);
-        let already_has_blob = self.repos.blob.exists(layer_digest).await?;
+        manifest: Option<&str>,
+    ) -> Arc<InFlightBlob> {
+        let (blob, is_new) = self.downloads.get_or_insert(digest, size);
+        if !is_new {
+            self.link_when_downloaded(blob.clone(), digest, local_repo_name, manifest);
+            return blob;
+        }
 
-        if !already_has_blob {
-            let stream = cl
-                .pull_blob_stream(ref_, layer_digest)
-                .await
-                .map_err(DownloadRemoteImageError::from)?;
-            let path = self
+        let cl = cl.clone();
+        let ref_ = ref_.clone();
+        let layer = digest.to_string();
+        let upstreams = self.upstreams.clone();
+        let stream = async move {
+            let sized = cl.pull_blob_stream(&ref_, layer.as_str()).await;
+            upstreams.record(ref_.registry(), &sized);
+            Ok(sized.map_err(DownloadRemoteImageError::from)?.stream)
+        };
+        self.spawn_blob_write(blob.clone(), stream, digest, local_repo_name, manifest);
+        blob
+    }
+
+    /// Downloads again a proxied blob that is referenced but not stored (e.g.
+    /// evicted by the GC). Returns the download in progress, or `None` if the
+    /// upstream didn't announce the blob size, in which case the blob has been
+    /// stored when this returns.
+    pub(crate) async fn refetch_blob(
+        &self,
+        image: &Reference,
+        proxy_config: Option<&SingleRegistryProxyConfig>,
+    ) -> Result<Option<Arc<InFlightBlob>>, Error> {
+        let Some(digest) = image.digest() else {
+            return Err(Error::Digest(DigestError::InvalidDigest(image.to_string())));
+        };
+        if let Some(blob) = self.downloads.get(digest) {
+            return Ok(Some(blob));
+        }
+        let repo_name = proxied_repo_name(image);
+        tracing::debug!("Blob {digest} of {repo_name} is missing, downloading it again");
+
+        let mut pulled = Err(DownloadRemoteImageError::DownloadAttemptsFailed.into());
+        for endpoint in endpoints(image, proxy_config) {
+            let host = endpoint.reference.registry();
+            pulled = async {
+                self.ensure_available(host)?;
+                let (cl, auth) = self.clients.get(host, endpoint.config.as_deref()).await?;
+                cl.store_auth_if_needed(endpoint.reference.resolve_registry(), &auth)
+                    .await;
+                let sized = cl.pull_blob_stream(&endpoint.reference, digest).await;
+                self.record(host, endpoint.config.as_deref(), &sized);
+                Ok::<_, Error>(sized.map_err(DownloadRemoteImageError::from)?)
+            }
+            .await;
+            match &pulled {
+                Ok(_) => break,
+                Err(e) => tracing::debug!("Could not get blob {digest} from {host}: {e}"),
+            }
+        }
+        let sized = pulled?;
+
+        let Some(size) = sized.content_length else {
+            let size = self
                 .storage
-                .write_blob_stream(layer_digest, stream, true)
+                .write_blob_stream(digest, sized.stream, true)
+                .await?;
+            self.repos
+                .blob
+                .insert_or_ignore(digest, size as i64)
                 .await?;
-            let size = path.metadata().map_err(|e| Error::Storage(e.into()))?.len() as i64;
-            self.repos.blob.insert_or_ignore(layer_digest, size).await?;
+            link_blob(&self.repos, digest, &repo_name).await?;
+            return Ok(None);
+        };
+        let (blob, is_new) = self.downloads.get_or_insert(digest, size);
+        if is_new {
+            let stream = async move { Ok(sized.stream) };
+            self.spawn_blob_write(blob.clone(), stream, digest, &repo_name, None);
+        } else {
+            self.link_when_downloaded(blob.clone(), digest, &repo_name, None);
         }
-        self.repos
-            .repo_blob_assoc
-            .insert_blob_assoc_safe(local_repo_name, layer_digest)
-            .await?;
-        Ok(())
+        Ok(Some(blob))
+    }
+
+    /// Stores the blob from `stream` in the background, with its progress
+    /// recorded in `in_flight`.
+    /// If the download fails, `manifest` (stored before its blobs) is removed
+    /// from the repo so that the next pull downloads it again.
+    fn spawn_blob_write(
+        &self,
+        in_flight: Arc<InFlightBlob>,
+        stream: impl Future<Output = Result<BlobStream<'static>, Error>> + Send + 'static,
+        digest: &str,
+        local_repo_name: &str,
+        manifest: Option<&str>,
+    ) {
+        tracing::trace!("Downloading blob {}", digest);
+        let repos = self.repos.clone();
+        let storage = self.storage.clone();
+        let downloads = self.downloads.clone();
+        let digest = digest.to_string();
+        let local_repo_name = local_repo_name.to_string();
+        let manifest = manifest.map(str::to_string);
+        tokio::spawn(async move {
+            let result = async {
+                let stream = stream.await?;
+                let size = storage
+                    .write_blob_stream(&digest, in_flight.tee(stream), true)
+                    .await?;
+                repos.blob.insert_or_ignore(&digest, size as i64).await?;
+                link_blob(&repos, &digest, &local_repo_name).await
+            }
+            .await;
+            if let Err(e) = &result {
+                tracing::warn!("Failed to download proxied blob {digest}: {e}");
+                if let Some(manifest) = &manifest {
+                    drop_manifest(&repos, manifest, &local_repo_name).await;
+                }
+            }
+            in_flight.finish(result.map_err(|e| e.to_string()));
+            downloads.remove(&digest);
+        });
+    }
+
+    /// Another repo may have started the download, ours still needs the blob
+    fn link_when_downloaded(
+        &self,
+        in_flight: Arc<InFlightBlob>,
+        digest: &str,
+        local_repo_name: &str,
+        manifest: Option<&str>,
+    ) {
+        let repos = self.repos.clone();
+        let digest = digest.to_string();
+        let local_repo_name = local_repo_name.to_string();
+        let manifest = manifest.map(str::to_string);
+        tokio::spawn(async move {
+            if in_flight.wait().await.is_err() {
+                if let Some(manifest) = &manifest {
+                    drop_manifest(&repos, manifest, &local_repo_name).await;
+                }
+            } else if let Err(e) = link_blob(&repos, &digest, &local_repo_name).await {
+                tracing::warn!("Could not link blob {digest} to {local_repo_name}: {e}");
+            }
+        });
+    }
+}
+
+fn upstream_unavailable(host: &str, left: Duration) -> Error {
+    DownloadRemoteImageError::UpstreamUnavailable {
+        host: host.to_string(),
+        retry_after_secs: left.as_secs().max(1),
+    }
+    .into()
+}
+
+/// A registry serving a proxied image: one of its mirrors, or the registry itself
+struct Endpoint<'a> {
+    /// The image on this endpoint
+    reference: Reference,
+    config: Option<Cow<'a, SingleRegistryProxyConfig>>,
+}
+
+/// Manifests of an index downloaded along with it
+#[derive(Clone, Copy, Debug)]
+enum IndexPlatforms<'a> {
+    /// Those of the listed platforms, none if empty
+    Only(&'a [String]),
+    All,
+}
+
+impl IndexPlatforms<'_> {
+    fn wants(self, platform: Option<&Platform>) -> bool {
+        match self {
+            IndexPlatforms::Only(platforms) => {
+                platform.is_some_and(|p| platforms.iter().any(|w| platform_matches(p, w)))
+            }
+            IndexPlatforms::All => true,
+        }
+    }
+}
+
+/// Where to get `image` from, in order: the mirrors, then the registry
+fn endpoints<'a>(
+    image: &Reference,
+    proxy_config: Option<&'a SingleRegistryProxyConfig>,
+) -> Vec<Endpoint<'a>> {
+    let mirrors = proxy_config.into_iter().flat_map(|c| &c.mirrors);
+    let mut endpoints: Vec<_> = mirrors
+        .map(|mirror| {
+            let repository = match mirror.repository_prefix.as_deref() {
+                Some(prefix) => format!("{prefix}/{}", image.repository()),
+                None => image.repository().to_string(),
+            };
+            let reference = match (image.digest(), image.tag()) {
+                (Some(digest), _) => {
+                    Reference::with_digest(mirror.host.clone(), repository, digest.to_string())
+                }
+                (None, tag) => Reference::with_tag(
+                    mirror.host.clone(),
+                    repository,
+                    tag.unwrap_or("latest").to_string(),
+                ),
+            };
+            Endpoint {
+                reference,
+                config: Some(Cow::Owned(mirror.registry_config())),
+            }
+        })
+        .collect();
+    endpoints.push(Endpoint {
+        reference: image.clone(),
+        config: proxy_config.map(Cow::Borrowed),
+    });
+    endpoints
+}
+
+/// Why an image couldn't be downloaded from an endpoint
+#[derive(Debug, thiserror::Error)]
+enum EndpointFailure {
+    /// Backed off, circuit open or no client
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("tag check failed: {0}")]
+    Check(OciDistributionError),
+    /// The tag was checked, the manifest couldn't be downloaded
+    #[error("download failed: {0}")]
+    Download(Error),
+}
+
+/// Associates a freshly stored blob with a proxied repo and its manifests
+async fn link_blob(repos: &Repositories, digest: &str, local_repo_name: &str) -> Result<(), Error> {
+    repos
+        .repo_blob_assoc
+        .insert_blob_assoc_safe(local_repo_name, digest)
+        .await?;
+    repos
+        .manifest
+        .link_blob_in_repo(digest, local_repo_name)
+        .await?;
+    Ok(())
+}
+
+/// Removes a proxied manifest missing one of its blobs from its repo, the
+/// next pull then downloads it again instead of serving it from the cache
+async fn drop_manifest(repos: &Repositories, manifest: &str, local_repo_name: &str) {
+    tracing::info!("Dropping {manifest} from {local_repo_name}, a blob could not be downloaded");
+    if let Err(e) = repos
+        .repo_blob_assoc
+        .delete_manifest_assoc(local_repo_name, manifest)
+        .await
+    {
+        tracing::warn!("Could not drop {manifest} from {local_repo_name}: {e}");
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
 
     use ::oci_client::Reference;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::io::AsyncReadExt;
 
-    use crate::file_storage::FileStorage;
+    use crate::TrowConfig;
+    use crate::configuration::{RegistryEndpoint, ServeStalePolicy, SingleRegistryProxyConfig};
+    use crate::repositories::Repositories;
+    use crate::services::blob_service::{BlobService, ByteRange};
+    use crate::services::error::Error;
     use crate::services::proxy_service::ProxyService;
-    use crate::test_utilities::{repos_in_memory, test_temp_dir};
+    use crate::services::proxy_service::errors::DownloadRemoteImageError;
+    use crate::services::proxy_service::upstreams::CircuitState;
+    use crate::storage::FileStorage;
+    use crate::test_utilities::{fake_upstream, repos_in_memory, test_temp_dir};
+    use crate::utils::digest::Digest;
 
-    fn setup_service(repos: Arc<super::super::super::repositories::Repositories>) -> ProxyService {
+    fn setup_service(repos: Arc<Repositories>) -> ProxyService {
         let dir = test_temp_dir!();
         let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
         ProxyService::new(repos, storage)
@@ -228,4 +1093,549 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), digest);
     }
+
+    #[tokio::test]
+    async fn get_blob_follows_in_flight_download() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage.clone()));
+        let blob_svc = BlobService::new(repos, storage, Arc::new(TrowConfig::new()), proxy.clone());
+
+        let content = b"layer content";
+        let digest = Digest::digest_sha256_slice(content);
+        let (in_flight, _) = proxy.downloads.get_or_insert(digest.as_str(), 13);
+        let upstream = futures::stream::iter([
+            Ok(Bytes::from_static(&content[..5])),
+            Ok(Bytes::from_static(&content[5..])),
+        ])
+        .boxed();
+        let mut teed = in_flight.tee(upstream);
+        teed.next().await.unwrap().unwrap();
+
+        let reader = blob_svc
+            .get_blob(
+                "f/docker.io/library/alpine".to_string(),
+                digest.clone(),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(reader.blob_size(), 13);
+
+        let range_reader = blob_svc
+            .get_blob(
+                "f/docker.io/library/alpine".to_string(),
+                digest.clone(),
+                None,
+                Some(ByteRange::From(6, Some(8))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(range_reader.range(), Some(&(6..=8, 13)));
+
+        teed.next().await.unwrap().unwrap();
+        in_flight.finish(Ok(()));
+        let mut buf = Vec::new();
+        reader.get_reader().read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, content);
+        let mut buf = Vec::new();
+        range_reader
+            .get_reader()
+            .read_to_end(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"con");
+
+        let result = blob_svc
+            .get_blob(
+                "f/docker.io/library/alpine".to_string(),
+                digest.clone(),
+                None,
+                Some(ByteRange::From(13, None)),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::UnsatisfiableRange(13))));
+    }
+
+    /// Caches `repo:latest`, checked against upstream `checked_secs_ago`
+    async fn insert_cached_tag(repos: &Repositories, repo: &str, checked_secs_ago: i64) -> String {
+        let manifest_bytes = br#"{"schemaVersion": 2, "manifests": []}"#;
+        let digest = Digest::digest_sha256_slice(manifest_bytes).to_string();
+        repos
+            .manifest
+            .insert_or_ignore(&digest, manifest_bytes)
+            .await
+            .unwrap();
+        repos
+            .repo_blob_assoc
+            .insert_manifest_assoc(repo, &digest)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO tag (tag, repo, manifest_digest, last_checked) VALUES ('latest', $1, $2, unixepoch() - $3)",
+            repo,
+            digest,
+            checked_secs_ago
+        )
+        .execute(repos.db_rw())
+        .await
+        .unwrap();
+        digest
+    }
+
+    fn proxy_config(
+        host: &str,
+        ttl: Option<u64>,
+        policy: ServeStalePolicy,
+    ) -> SingleRegistryProxyConfig {
+        SingleRegistryProxyConfig {
+            host: host.to_string(),
+            insecure: true,
+            tag_ttl_secs: ttl,
+            serve_stale: policy,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn download_image_trusts_fresh_tag() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+        // Nothing listens on port 1: any upstream check would fail
+        let digest = insert_cached_tag(&repos, "f/127.0.0.1:1/foo", 10).await;
+        let image = Reference::with_tag(
+            "127.0.0.1:1".to_string(),
+            "foo".to_string(),
+            "latest".to_string(),
+        );
+
+        let cfg = proxy_config("127.0.0.1:1", Some(60), ServeStalePolicy::Never);
+        assert_eq!(
+            svc.download_image(&image, Some(&cfg)).await.unwrap(),
+            digest
+        );
+
+        // Expired: upstream has to be checked, and stale tags are refused
+        let cfg = proxy_config("127.0.0.1:1", Some(5), ServeStalePolicy::Never);
+        assert!(svc.download_image(&image, Some(&cfg)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn download_image_serve_stale_policy() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        // Upstream that knows no image
+        let app = axum::Router::new().fallback(|| async {
+            (
+                axum::http::StatusCode::NOT_FOUND,
+                r#"{"errors":[{"code":"MANIFEST_UNKNOWN","message":"unknown"}]}"#,
+            )
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        for host in ["127.0.0.1:1", addr.as_str()] {
+            let digest = insert_cached_tag(&repos, &format!("f/{host}/foo"), 3600).await;
+            let image =
+                Reference::with_tag(host.to_string(), "foo".to_string(), "latest".to_string());
+            let unreachable = host == "127.0.0.1:1";
+
+            let cfg = proxy_config(host, None, ServeStalePolicy::Always);
+            assert_eq!(
+                svc.download_image(&image, Some(&cfg)).await.unwrap(),
+                digest
+            );
+
+            let cfg = proxy_config(host, None, ServeStalePolicy::WhenUnavailable);
+            let res = svc.download_image(&image, Some(&cfg)).await;
+            if unreachable {
+                assert_eq!(res.unwrap(), digest);
+            } else {
+                assert!(matches!(res, Err(Error::Proxy(_))), "{res:?}");
+            }
+
+            let cfg = proxy_config(host, None, ServeStalePolicy::Never);
+            assert!(svc.download_image(&image, Some(&cfg)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn download_image_stops_calling_failing_upstream() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = axum::Router::new().fallback(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            axum::http::StatusCode::SERVICE_UNAVAILABLE
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let digest = insert_cached_tag(&repos, &format!("f/{host}/foo"), 3600).await;
+        let cached = Reference::with_tag(host.clone(), "foo".to_string(), "latest".to_string());
+        let uncached = Reference::with_tag(host.clone(), "bar".to_string(), "latest".to_string());
+        let cfg = proxy_config(&host, None, ServeStalePolicy::Always);
+
+        while svc.upstreams.blocked_for(&host).is_none() {
+            assert!(calls.load(Ordering::SeqCst) < 20, "circuit never opened");
+            assert_eq!(
+                svc.download_image(&cached, Some(&cfg)).await.unwrap(),
+                digest
+            );
+        }
+        let calls_before = calls.load(Ordering::SeqCst);
+
+        // Circuit open: served from the cache, or failing fast
+        assert_eq!(
+            svc.download_image(&cached, Some(&cfg)).await.unwrap(),
+            digest
+        );
+        let err = svc.download_image(&uncached, Some(&cfg)).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::Proxy(e) if matches!(**e, DownloadRemoteImageError::UpstreamUnavailable { .. })),
+            "{err:?}"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), calls_before);
+
+        let status = svc.upstream_status();
+        assert_eq!(status[0].host, host);
+        assert_eq!(status[0].state, CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn download_image_follows_retry_after() {
+        use axum::http::{Method, StatusCode, header};
+        use axum::response::IntoResponse;
+        use axum::routing::{any, get};
+
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos);
+
+        // Token auth registry, rate limiting manifest requests
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let challenge = format!(r#"Bearer realm="http://{host}/token",service="test""#);
+        let unauthorized = move || {
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, challenge.clone())],
+            )
+                .into_response()
+        };
+        let (gets, heads) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (get_counter, head_counter) = (gets.clone(), heads.clone());
+        let unauthorized_manifest = unauthorized.clone();
+        let app = axum::Router::new()
+            .route("/v2/", get(move || async move { unauthorized() }))
+            .route(
+                "/token",
+                get(|| async { axum::Json(serde_json::json!({ "token": "secret" })) }),
+            )
+            .route(
+                "/v2/foo/manifests/{reference}",
+                any(
+                    move |method: Method, headers: axum::http::HeaderMap| async move {
+                        if headers
+                            .get(header::AUTHORIZATION)
+                            .is_none_or(|a| a != "Bearer secret")
+                        {
+                            return unauthorized_manifest();
+                        }
+                        let counter = if method == Method::HEAD {
+                            &head_counter
+                        } else {
+                            &get_counter
+                        };
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "2")])
+                            .into_response()
+                    },
+                ),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let image = Reference::with_tag(host.clone(), "foo".to_string(), "latest".to_string());
+        let cfg = proxy_config(&host, None, ServeStalePolicy::Never);
+        assert!(svc.download_image(&image, Some(&cfg)).await.is_err());
+        let (gets_before, heads_before) =
+            (gets.load(Ordering::SeqCst), heads.load(Ordering::SeqCst));
+        // The default backoff is a minute, until the Retry-After is known
+        for _ in 0..100 {
+            if svc.upstreams.blocked_for(&host).unwrap() <= Duration::from_secs(2) {
+                // Asked with an authenticated HEAD, not another pull
+                assert_eq!(gets.load(Ordering::SeqCst), gets_before);
+                assert!(heads.load(Ordering::SeqCst) > heads_before);
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Retry-After was not honoured");
+    }
+
+    #[tokio::test]
+    async fn upstream_tokens_are_reused_until_refused() {
+        use axum::http::{StatusCode, header};
+        use axum::response::IntoResponse;
+        use axum::routing::get;
+
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let token_requests = Arc::new(AtomicUsize::new(0));
+        let refuse_next = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let challenge = format!(r#"Bearer realm="http://{host}/token",service="test""#);
+        let (counter, refuse) = (token_requests.clone(), refuse_next.clone());
+        let app = axum::Router::new()
+            .route(
+                "/v2/",
+                get(move || async move {
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [(header::WWW_AUTHENTICATE, challenge)],
+                    )
+                }),
+            )
+            .route(
+                "/token",
+                get(move || async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    axum::Json(serde_json::json!({ "token": "secret" }))
+                }),
+            )
+            .route(
+                "/v2/foo/tags/list",
+                get(move || async move {
+                    if refuse.swap(false, Ordering::SeqCst) {
+                        return StatusCode::UNAUTHORIZED.into_response();
+                    }
+                    axum::Json(serde_json::json!({ "name": "foo", "tags": ["latest"] }))
+                        .into_response()
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let image = Reference::with_tag(host.clone(), "foo".to_string(), "latest".to_string());
+        let cfg = proxy_config(&host, None, ServeStalePolicy::Always);
+        for _ in 0..2 {
+            let tags = svc.list_remote_tags(&image, Some(&cfg), None, None).await;
+            assert_eq!(tags.unwrap(), vec!["latest"]);
+        }
+        assert_eq!(token_requests.load(Ordering::SeqCst), 1);
+
+        // Refused: the client and its token are dropped
+        refuse_next.store(true, Ordering::SeqCst);
+        let refused = svc.list_remote_tags(&image, Some(&cfg), None, None).await;
+        assert!(refused.is_err());
+        let tags = svc.list_remote_tags(&image, Some(&cfg), None, None).await;
+        assert_eq!(tags.unwrap(), vec!["latest"]);
+        assert_eq!(token_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn download_image_falls_back_to_next_mirror() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        let index = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let digest = Digest::digest_sha256_slice(index.as_bytes()).to_string();
+        // Mirror serving the repository under a prefix, like Artifactory
+        let manifests = HashMap::from([("latest".to_string(), index.to_string())]);
+        let mirror = fake_upstream("dockerhub-remote/foo", manifests, HashMap::new()).await;
+
+        // The registry itself and the first mirror are unreachable
+        let mut cfg = proxy_config("127.0.0.1:1", None, ServeStalePolicy::Never);
+        cfg.mirrors = vec![
+            RegistryEndpoint {
+                host: "127.0.0.1:2".to_string(),
+                insecure: true,
+                ..Default::default()
+            },
+            RegistryEndpoint {
+                host: mirror,
+                repository_prefix: Some("dockerhub-remote".to_string()),
+                insecure: true,
+                ..Default::default()
+            },
+        ];
+        let image = Reference::with_tag(
+            "127.0.0.1:1".to_string(),
+            "foo".to_string(),
+            "latest".to_string(),
+        );
+        assert_eq!(
+            svc.download_image(&image, Some(&cfg)).await.unwrap(),
+            digest
+        );
+        let cached = repos
+            .tag
+            .find_manifest_digest("f/127.0.0.1:1/foo", "latest")
+            .await
+            .unwrap();
+        assert_eq!(cached, Some(digest));
+    }
+
+    /// Upstream serving `foo:latest`, an index of a linux/amd64 and a
+    /// linux/arm64 image. Returns its host, and the digests of the index and
+    /// of both images.
+    async fn fake_index_upstream() -> (String, String, String, String) {
+        let config = Digest::digest_sha256_slice(b"{}").to_string();
+        let image_manifest = |arch: &str| {
+            format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config}","size":2}},"layers":[],"annotations":{{"arch":"{arch}"}}}}"#
+            )
+        };
+        let amd64 = image_manifest("amd64");
+        let arm64 = image_manifest("arm64");
+        let amd64_digest = Digest::digest_sha256_slice(amd64.as_bytes()).to_string();
+        let arm64_digest = Digest::digest_sha256_slice(arm64.as_bytes()).to_string();
+        let child = |digest: &str, arch: &str| {
+            format!(
+                r#"{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{digest}","size":1,"platform":{{"os":"linux","architecture":"{arch}"}}}}"#
+            )
+        };
+        let index = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{},{}]}}"#,
+            child(&amd64_digest, "amd64"),
+            child(&arm64_digest, "arm64")
+        );
+        let index_digest = Digest::digest_sha256_slice(index.as_bytes()).to_string();
+
+        let manifests = HashMap::from([
+            ("latest".to_string(), index),
+            (amd64_digest.clone(), amd64),
+            (arm64_digest.clone(), arm64),
+        ]);
+        let blobs = HashMap::from([(config, b"{}".to_vec())]);
+        let upstream = fake_upstream("foo", manifests, blobs).await;
+        (upstream, index_digest, amd64_digest, arm64_digest)
+    }
+
+    #[tokio::test]
+    async fn download_index_prefetches_wanted_platforms() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = ProxyService::new(repos.clone(), storage);
+        let (upstream, index_digest, amd64_digest, arm64_digest) = fake_index_upstream().await;
+
+        let cfg = SingleRegistryProxyConfig {
+            platforms: vec!["linux/amd64".to_string()],
+            ..proxy_config(&upstream, None, ServeStalePolicy::Never)
+        };
+        let image = Reference::with_tag(upstream.clone(), "foo".to_string(), "latest".to_string());
+        assert_eq!(
+            svc.download_image(&image, Some(&cfg)).await.unwrap(),
+            index_digest
+        );
+        let repo = format!("f/{upstream}/foo");
+        let assoc = &repos.repo_blob_assoc;
+        assert!(
+            assoc
+                .manifest_exists_in_repo(&amd64_digest, &repo)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !assoc
+                .manifest_exists_in_repo(&arm64_digest, &repo)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn prefetch_image_downloads_all_platforms_by_default() {
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = ProxyService::new(repos.clone(), storage);
+        let (upstream, index_digest, amd64_digest, arm64_digest) = fake_index_upstream().await;
+
+        let cfg = proxy_config(&upstream, None, ServeStalePolicy::Never);
+        let image = Reference::with_tag(upstream.clone(), "foo".to_string(), "latest".to_string());
+        assert_eq!(
+            svc.prefetch_image(&image, Some(&cfg), &[]).await.unwrap(),
+            index_digest
+        );
+        let repo = format!("f/{upstream}/foo");
+        for digest in [&amd64_digest, &arm64_digest] {
+            let cached = repos
+                .repo_blob_assoc
+                .manifest_exists_in_repo(digest, &repo)
+                .await
+                .unwrap();
+            assert!(cached, "{digest} was not prefetched");
+        }
+    }
+
+    #[tokio::test]
+    async fn list_all_remote_tags_pages_through_upstream() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos);
+        let index = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let tags = ["a", "b", "c", "d", "e"];
+        let manifests = tags.map(|t| (t.to_string(), index.to_string())).into();
+        let upstream = fake_upstream("foo", manifests, HashMap::new()).await;
+
+        // Pages of FAKE_UPSTREAM_TAGS_PAGE tags, although more are asked
+        let cfg = proxy_config(&upstream, None, ServeStalePolicy::Never);
+        let image = Reference::with_tag(upstream.clone(), "foo".to_string(), "latest".to_string());
+        let listed = svc.list_all_remote_tags(&image, Some(&cfg)).await.unwrap();
+        assert_eq!(listed.tags, tags);
+        assert!(listed.complete);
+
+        let unreachable = proxy_config("127.0.0.1:1", None, ServeStalePolicy::Never);
+        let image = Reference::with_tag(
+            "127.0.0.1:1".to_string(),
+            "foo".to_string(),
+            "latest".to_string(),
+        );
+        assert!(
+            svc.list_all_remote_tags(&image, Some(&unreachable))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn download_image_drops_manifest_missing_blob() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        // The upstream doesn't have the config blob
+        let config = Digest::digest_sha256_slice(b"{}").to_string();
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config}","size":2}},"layers":[]}}"#
+        );
+        let digest = Digest::digest_sha256_slice(manifest.as_bytes()).to_string();
+        let manifests = HashMap::from([("latest".to_string(), manifest)]);
+        let upstream = fake_upstream("foo", manifests, HashMap::new()).await;
+
+        let cfg = proxy_config(&upstream, None, ServeStalePolicy::Never);
+        let image = Reference::with_tag(upstream.clone(), "foo".to_string(), "latest".to_string());
+        assert_eq!(
+            svc.download_image(&image, Some(&cfg)).await.unwrap(),
+            digest
+        );
+        if let Some(download) = svc.in_flight_blob(&config) {
+            assert!(download.wait().await.is_err());
+        }
+        let repo = format!("f/{upstream}/foo");
+        assert!(
+            !repos
+                .repo_blob_assoc
+                .manifest_exists_in_repo(&digest, &repo)
+                .await
+                .unwrap()
+        );
+    }
 }
@@ -0,0 +1,331 @@
+//! Health of upstream registries: backing off when they rate limit us, and
+//! not calling those that keep failing for a while (circuit breaker).
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::oci_client::errors::{OciDistributionError, OciErrorCode};
+use serde::Serialize;
+
+/// Consecutive failures after which the circuit of an upstream opens
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long the circuit stays open the first time, doubled on each failed retry
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+const MAX_OPEN_DURATION: Duration = Duration::from_secs(600);
+/// Backoff after a 429, until the upstream `Retry-After` is known
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+/// Longest `Retry-After` honoured
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+/// How long the trial request of a half-open circuit has to complete
+/// before another one is let through
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// Requests are not sent upstream until `retry_in_secs`
+    Open,
+    /// The circuit was open, a single trial request tells if the upstream recovered
+    HalfOpen,
+}
+
+/// State of an upstream registry, as shown by the admin upstreams listing.
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    pub host: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// Seconds before requests are sent upstream again
+    pub retry_in_secs: Option<u64>,
+    pub rate_limited: bool,
+    pub last_error: Option<String>,
+    /// Seconds since the last failure
+    pub last_failure_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    consecutive_failures: u32,
+    blocked_until: Option<Instant>,
+    rate_limited: bool,
+    last_error: Option<String>,
+    last_failure: Option<Instant>,
+    /// Set while the trial request of a half-open circuit is in progress
+    trial_until: Option<Instant>,
+}
+
+impl HostState {
+    fn circuit_state(&self, now: Instant) -> CircuitState {
+        match self.blocked_until {
+            Some(until) if until > now => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+}
+
+/// Upstream registries health, by host
+#[derive(Debug, Default)]
+pub(crate) struct Upstreams(Mutex<HashMap<String, HostState>>);
+
+impl Upstreams {
+    /// Time left before requests can be sent to `host` again, if they can't
+    pub fn blocked_for(&self, host: &str) -> Option<Duration> {
+        let hosts = self.0.lock().unwrap();
+        let until = hosts.get(host)?.blocked_until?;
+        let left = until.saturating_duration_since(Instant::now());
+        (!left.is_zero()).then_some(left)
+    }
+
+    /// Lets a request to `host` through, or returns the time left before
+    /// requests can be sent again. When the circuit is half-open, only one
+    /// trial request goes through until its outcome is recorded.
+    pub fn admit(&self, host: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut hosts = self.0.lock().unwrap();
+        let state = hosts.get_mut(host)?;
+        match state.circuit_state(now) {
+            CircuitState::Closed => None,
+            CircuitState::Open => state.blocked_until.map(|until| until - now),
+            CircuitState::HalfOpen => match state.trial_until {
+                Some(until) if until > now => Some(until - now),
+                _ => {
+                    state.trial_until = Some(now + TRIAL_TIMEOUT);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Records the outcome of a request to `host`. Only errors telling that
+    /// the upstream is unavailable count as failures: a missing manifest
+    /// says nothing about the upstream health, except that it answers.
+    pub fn record<T>(&self, host: &str, result: &Result<T, OciDistributionError>) {
+        match result {
+            Err(e) if is_unavailable(e) => self.record_failure(host, e),
+            _ => self.record_success(host),
+        }
+    }
+
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.0.lock().unwrap();
+        if let Some(state) = hosts.get_mut(host) {
+            state.consecutive_failures = 0;
+            state.blocked_until = None;
+            state.rate_limited = false;
+            state.trial_until = None;
+        }
+    }
+
+    /// Backs off from a rate limited `host` for the `Retry-After` it sent,
+    /// instead of the default backoff. An open circuit still lasts as long.
+    pub fn record_retry_after(&self, host: &str, retry_after: Duration) {
+        let now = Instant::now();
+        let mut hosts = self.0.lock().unwrap();
+        let Some(state) = hosts.get_mut(host).filter(|s| s.rate_limited) else {
+            return;
+        };
+        let until = now + retry_after.min(MAX_RETRY_AFTER);
+        state.blocked_until = if state.consecutive_failures < FAILURE_THRESHOLD {
+            Some(until)
+        } else {
+            state.blocked_until.max(Some(until))
+        };
+        tracing::info!(
+            "Upstream {host} asked to retry in {}s",
+            retry_after.as_secs()
+        );
+    }
+
+    pub fn record_failure(&self, host: &str, err: &OciDistributionError) {
+        let now = Instant::now();
+        let mut hosts = self.0.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.consecutive_failures += 1;
+        state.last_error = Some(err.to_string());
+        state.last_failure = Some(now);
+        state.rate_limited = is_rate_limited(err);
+        state.trial_until = None;
+
+        let mut backoff = Duration::ZERO;
+        if state.rate_limited {
+            backoff = RATE_LIMIT_BACKOFF;
+        }
+        if let Some(retries) = state.consecutive_failures.checked_sub(FAILURE_THRESHOLD) {
+            let open_for = OPEN_DURATION
+                .saturating_mul(2u32.saturating_pow(retries))
+                .min(MAX_OPEN_DURATION);
+            backoff = backoff.max(open_for);
+        }
+        if !backoff.is_zero() {
+            tracing::warn!(
+                "Upstream {host} is unavailable, not calling it for {}s: {err}",
+                backoff.as_secs()
+            );
+            // A shorter circuit opening doesn't end a rate limiting backoff
+            state.blocked_until = state.blocked_until.max(Some(now + backoff));
+        }
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        let now = Instant::now();
+        let hosts = self.0.lock().unwrap();
+        let mut statuses: Vec<_> = hosts
+            .iter()
+            .map(|(host, state)| UpstreamStatus {
+                host: host.clone(),
+                state: state.circuit_state(now),
+                consecutive_failures: state.consecutive_failures,
+                retry_in_secs: state
+                    .blocked_until
+                    .filter(|until| *until > now)
+                    .map(|until| until.duration_since(now).as_secs().max(1)),
+                rate_limited: state.rate_limited,
+                last_error: state.last_error.clone(),
+                last_failure_secs: state.last_failure.map(|t| now.duration_since(t).as_secs()),
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.host.cmp(&b.host));
+        statuses
+    }
+}
+
+/// Whether an upstream error is temporary: unreachable, rate limited or failing
+pub(crate) fn is_unavailable(err: &OciDistributionError) -> bool {
+    match err {
+        OciDistributionError::RequestError(_) => true,
+        OciDistributionError::ServerError { code, .. } => *code == 429 || *code >= 500,
+        _ => is_rate_limited(err),
+    }
+}
+
+/// The URL of a rate limited request, to ask for its `Retry-After`
+pub(crate) fn rate_limited_url(err: &OciDistributionError) -> Option<&str> {
+    match err {
+        OciDistributionError::ServerError { url, .. }
+        | OciDistributionError::RegistryError { url, .. }
+            if is_rate_limited(err) =>
+        {
+            Some(url)
+        }
+        _ => None,
+    }
+}
+
+/// Parses a `Retry-After` header: a number of seconds or an HTTP date
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = (date.timestamp() - chrono::Utc::now().timestamp()).max(0);
+    Some(Duration::from_secs(secs as u64))
+}
+
+fn is_rate_limited(err: &OciDistributionError) -> bool {
+    match err {
+        OciDistributionError::ServerError { code, .. } => *code == 429,
+        OciDistributionError::RegistryError { envelope, .. } => envelope
+            .errors
+            .iter()
+            .any(|e| e.code == OciErrorCode::Toomanyrequests),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::oci_client::errors::OciDistributionError;
+
+    use super::*;
+
+    fn server_error(code: u16) -> OciDistributionError {
+        OciDistributionError::ServerError {
+            code,
+            url: "https://example.com/v2/".to_string(),
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn circuit_opens_after_repeated_failures() {
+        let upstreams = Upstreams::default();
+        for _ in 1..FAILURE_THRESHOLD {
+            upstreams.record::<()>("example.com", &Err(server_error(502)));
+            assert!(upstreams.blocked_for("example.com").is_none());
+        }
+        upstreams.record::<()>("example.com", &Err(server_error(502)));
+        let blocked_for = upstreams.blocked_for("example.com").unwrap();
+        assert!(blocked_for <= OPEN_DURATION);
+
+        let status = upstreams.status();
+        assert_eq!(status[0].state, CircuitState::Open);
+        assert_eq!(status[0].consecutive_failures, FAILURE_THRESHOLD);
+
+        upstreams.record("example.com", &Ok(()));
+        assert!(upstreams.blocked_for("example.com").is_none());
+        assert_eq!(upstreams.status()[0].state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn backs_off_when_rate_limited() {
+        let upstreams = Upstreams::default();
+        upstreams.record::<()>("docker.io", &Err(server_error(429)));
+        let blocked_for = upstreams.blocked_for("docker.io").unwrap();
+        assert!(blocked_for > OPEN_DURATION);
+        assert!(upstreams.status()[0].rate_limited);
+
+        // Not found is not an upstream failure
+        upstreams.record::<()>("ghcr.io", &Err(server_error(404)));
+        assert!(upstreams.blocked_for("ghcr.io").is_none());
+        assert_eq!(upstreams.status().len(), 1);
+    }
+
+    #[test]
+    fn honours_retry_after() {
+        let upstreams = Upstreams::default();
+        upstreams.record::<()>("docker.io", &Err(server_error(429)));
+        upstreams.record_retry_after("docker.io", Duration::from_secs(5));
+        let blocked_for = upstreams.blocked_for("docker.io").unwrap();
+        assert!(blocked_for <= Duration::from_secs(5));
+
+        // Only for rate limited upstreams
+        upstreams.record_retry_after("ghcr.io", Duration::from_secs(5));
+        assert!(upstreams.blocked_for("ghcr.io").is_none());
+
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_trial_through() {
+        let upstreams = Upstreams::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            upstreams.record::<()>("example.com", &Err(server_error(502)));
+        }
+        assert!(upstreams.admit("example.com").is_some());
+
+        // The circuit is half-open once the open duration is over
+        upstreams
+            .0
+            .lock()
+            .unwrap()
+            .get_mut("example.com")
+            .unwrap()
+            .blocked_until = Some(Instant::now());
+        assert!(upstreams.admit("example.com").is_none());
+        assert!(upstreams.admit("example.com").is_some());
+
+        upstreams.record("example.com", &Ok(()));
+        assert!(upstreams.admit("example.com").is_none());
+        assert!(upstreams.admit("example.com").is_none());
+    }
+}
@@ -3,31 +3,149 @@ use std::sync::Arc;
 
 use oci_spec::image::{Descriptor, ImageIndex, MediaType};
 
-use crate::PROXY_DIR;
 use crate::repositories::Repositories;
 use crate::services::Error;
+use crate::services::proxy_service::{ProxyService, proxied_repo_name};
 use crate::utils::digest::Digest;
+use crate::utils::manifest::OCIManifest;
+use crate::utils::resolve_reference::parse_reference;
+use crate::{PROXY_DIR, TrowConfig};
 
 #[derive(Debug)]
 pub struct ReferrersService {
     repos: Arc<Repositories>,
+    config: Arc<TrowConfig>,
+    proxy: Arc<ProxyService>,
 }
 
 impl ReferrersService {
-    pub fn new(repos: Arc<Repositories>) -> Self {
-        Self { repos }
+    pub fn new(
+        repos: Arc<Repositories>,
+        config: Arc<TrowConfig>,
+        proxy: Arc<ProxyService>,
+    ) -> Self {
+        Self {
+            repos,
+            config,
+            proxy,
+        }
     }
 
-    pub async fn list_referrers(&self, repo: String, digest: String) -> Result<ImageIndex, Error> {
+    /// Lists the manifests in `repo` whose subject is `digest`, optionally
+    /// only those of the given `artifact_type`.
+    ///
+    /// For proxied repos the referrers are first fetched from upstream; the
+    /// cached ones are listed if that fails.
+    pub async fn list_referrers(
+        &self,
+        repo: String,
+        digest: String,
+        artifact_type: Option<&str>,
+    ) -> Result<ImageIndex, Error> {
+        let _ = Digest::try_from_raw(&digest)?;
         if repo.starts_with(PROXY_DIR) {
-            return Err(Error::UnsupportedForProxiedRepo);
+            let subject = parse_reference(&repo, &digest, None)?;
+            let proxy_config = self
+                .config
+                .config_file
+                .registry_proxies
+                .registries
+                .get_for(subject.registry(), subject.repository());
+            if let Err(e) = self.proxy.download_referrers(&subject, proxy_config).await {
+                tracing::warn!("Could not fetch referrers of {subject}, using cache: {e}");
+            }
+            let repo = proxied_repo_name(&subject);
+            return self.referrers_index(&repo, &digest, artifact_type).await;
         }
-        let _ = Digest::try_from_raw(&digest)?;
-        let rows = self.repos.manifest.list_referrers(&repo, &digest).await?;
+        self.referrers_index(&repo, &digest, artifact_type).await
+    }
+
+    /// Rewrites the `<alg>-<ref>` tag of `subject` in `repo` to an index of
+    /// its current referrers, as done by clients using the referrers tag
+    /// schema. The tag is removed once there are no referrers left.
+    ///
+    /// The index the tag pointed to before is removed from `repo` (and
+    /// deleted once no repo holds it), unless another tag still uses it.
+    pub async fn update_referrers_tag(&self, repo: &str, subject: &Digest) -> Result<(), Error> {
+        let tag = referrers_tag(subject);
+        let previous = self.repos.tag.find_manifest_digest(repo, &tag).await?;
+        let index = self.referrers_index(repo, subject.as_str(), None).await?;
+        let index_digest = if index.manifests().is_empty() {
+            self.repos.tag.delete(repo, &tag).await?;
+            None
+        } else {
+            let index_bytes = serde_json::to_vec(&index).unwrap();
+            let index_digest = Digest::digest_sha256_slice(&index_bytes);
+            self.repos
+                .manifest
+                .insert_or_ignore(index_digest.as_str(), &index_bytes)
+                .await?;
+            self.repos
+                .repo_blob_assoc
+                .insert_manifest_assoc(repo, index_digest.as_str())
+                .await?;
+            self.repos
+                .tag
+                .upsert(&tag, repo, index_digest.as_str())
+                .await?;
+            Some(index_digest)
+        };
+
+        if let Some(previous) = previous
+            && index_digest.as_ref().is_none_or(|d| d.as_str() != previous)
+        {
+            self.remove_replaced_index(repo, &previous).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_replaced_index(&self, repo: &str, digest: &str) -> Result<(), Error> {
+        if self.repos.tag.manifest_is_tagged(repo, digest).await? {
+            return Ok(());
+        }
+        // Only indexes are replaced, a manifest pushed under the tag name is kept
+        let is_index = self
+            .repos
+            .manifest
+            .find(digest)
+            .await
+            .is_ok_and(|m| m.media_type.as_deref() == Some(MediaType::ImageIndex.as_ref()));
+        if !is_index {
+            return Ok(());
+        }
+        self.repos
+            .repo_blob_assoc
+            .delete_manifest_assoc(repo, digest)
+            .await?;
+        if self
+            .repos
+            .repo_blob_assoc
+            .count_manifest_assoc(digest)
+            .await?
+            == 0
+        {
+            self.repos.manifest.delete(digest).await?;
+        }
+        Ok(())
+    }
+
+    async fn referrers_index(
+        &self,
+        repo: &str,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<ImageIndex, Error> {
+        let rows = self.repos.manifest.list_referrers(repo, digest).await?;
 
         let mut descriptors = vec![];
         for row in rows {
             let parsed_manifest = row.content.0;
+            let row_artifact_type = effective_artifact_type(&parsed_manifest);
+            if artifact_type.is_some_and(|wanted| {
+                row_artifact_type.as_ref().map(|t| t.to_string()).as_deref() != Some(wanted)
+            }) {
+                continue;
+            }
 
             let mediatype = parsed_manifest
                 .media_type()
@@ -39,7 +157,7 @@ impl ReferrersService {
                 row.size as u64,
                 oci_spec::image::Digest::from_str(&row.digest).unwrap(),
             );
-            descriptor.set_artifact_type(parsed_manifest.artifact_type());
+            descriptor.set_artifact_type(row_artifact_type);
             descriptor.set_annotations(parsed_manifest.annotations().clone());
             descriptors.push(descriptor);
         }
@@ -51,31 +169,247 @@ impl ReferrersService {
     }
 }
 
+/// The tag under which clients without referrers API support look for the
+/// referrers of `subject`.
+pub fn referrers_tag(subject: &Digest) -> String {
+    let algo: String = subject.algo_str().chars().take(32).collect();
+    let hash: String = subject.hash().chars().take(64).collect();
+    format!("{algo}-{hash}")
+}
+
+/// The artifactType of a referrer, which for image manifests without one is
+/// the media type of their config.
+fn effective_artifact_type(manifest: &OCIManifest) -> Option<MediaType> {
+    match manifest {
+        OCIManifest::V2(m) => m
+            .artifact_type()
+            .clone()
+            .or_else(|| Some(m.config().media_type().clone())),
+        OCIManifest::List(_) => manifest.artifact_type(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::services::error::Error;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::TrowConfig;
+    use crate::configuration::SingleRegistryProxyConfig;
+    use crate::repositories::Repositories;
+    use crate::services::proxy_service::ProxyService;
     use crate::services::referrers_service::ReferrersService;
-    use crate::test_utilities::repos_in_memory;
+    use crate::storage::FileStorage;
+    use crate::test_utilities::{fake_upstream, repos_in_memory};
+    use crate::utils::digest::Digest;
+
+    fn setup_service(repos: Arc<Repositories>) -> ReferrersService {
+        let dir = test_temp_dir::test_temp_dir!();
+        setup_service_with_config(repos, TrowConfig::new(), dir.as_path_untracked())
+    }
+
+    fn setup_service_with_config(
+        repos: Arc<Repositories>,
+        config: TrowConfig,
+        storage_dir: &Path,
+    ) -> ReferrersService {
+        let storage = Arc::new(FileStorage::new(storage_dir.to_owned()).unwrap());
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage));
+        ReferrersService::new(repos, Arc::new(config), proxy)
+    }
+
+    #[tokio::test]
+    async fn proxied_repo_falls_back_to_cache() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        let subject = "sha256:abc123def456789012345678901234567890123456789012345678901234567a";
+        let referrer = format!(
+            r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "artifactType": "application/vnd.dev.cosign.artifact.sig.v1+json",
+            "config": {{
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+            }},
+            "layers": [],
+            "subject": {{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "{subject}",
+                "size": 100
+            }}
+        }}"#
+        );
+        let referrer_digest = Digest::digest_sha256_slice(referrer.as_bytes());
+        repos
+            .manifest
+            .insert_or_ignore(referrer_digest.as_str(), referrer.as_bytes())
+            .await
+            .unwrap();
+        // Nothing listens on port 1, so the upstream is unreachable
+        repos
+            .repo_blob_assoc
+            .insert_manifest_assoc("f/127.0.0.1:1/foo", referrer_digest.as_str())
+            .await
+            .unwrap();
+
+        let result = svc
+            .list_referrers("f/127.0.0.1:1/foo".to_string(), subject.to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(result.manifests().len(), 1);
+        assert_eq!(
+            result.manifests()[0].digest().as_ref(),
+            referrer_digest.as_str()
+        );
+    }
 
     #[tokio::test]
-    async fn rejects_proxied_repo() {
+    async fn proxied_repo_caches_upstream_referrers() {
+        let subject = "sha256:abc123def456789012345678901234567890123456789012345678901234567a";
+        let referrer = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/vnd.dev.cosign.artifact.sig.v1+json","config":{{"mediaType":"application/vnd.oci.empty.v1+json","digest":"sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a","size":2}},"layers":[],"subject":{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{subject}","size":100}}}}"#
+        );
+        let referrer_digest = Digest::digest_sha256_slice(referrer.as_bytes());
+        let manifests = HashMap::from([("sig".to_string(), referrer)]);
+        let blobs = HashMap::from([(
+            Digest::digest_sha256_slice(b"{}").to_string(),
+            b"{}".to_vec(),
+        )]);
+        let addr = fake_upstream("foo", manifests, blobs).await;
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: addr.clone(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
         let repos = repos_in_memory().await;
-        let svc = ReferrersService::new(repos);
+        let dir = test_temp_dir::test_temp_dir!();
+        let svc = setup_service_with_config(repos.clone(), config, dir.as_path_untracked());
+
+        let proxied_repo = format!("f/{addr}/foo");
         let result = svc
-            .list_referrers(
-                "f/docker.io/library/alpine".to_string(),
-                "sha256:abc123".to_string(),
+            .list_referrers(proxied_repo.clone(), subject.to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(result.manifests().len(), 1);
+        assert_eq!(
+            result.manifests()[0].digest().as_ref(),
+            referrer_digest.as_str()
+        );
+        assert_eq!(
+            result.manifests()[0]
+                .artifact_type()
+                .as_ref()
+                .map(|t| t.to_string())
+                .as_deref(),
+            Some("application/vnd.dev.cosign.artifact.sig.v1+json")
+        );
+
+        // The referrer is cached, for when the upstream can't be reached
+        let cached = repos
+            .manifest
+            .list_referrers(&proxied_repo, subject)
+            .await
+            .unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].digest, referrer_digest.as_str());
+    }
+
+    #[tokio::test]
+    async fn update_referrers_tag_removes_replaced_index() {
+        let repos = repos_in_memory().await;
+        let svc = setup_service(repos.clone());
+
+        let subject = Digest::try_from_raw(
+            "sha256:abc123def456789012345678901234567890123456789012345678901234567a",
+        )
+        .unwrap();
+        let referrer = |annotation: &str| {
+            format!(
+                r#"{{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.dev.cosign.artifact.sig.v1+json",
+                "config": {{
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                    "size": 2
+                }},
+                "layers": [],
+                "subject": {{
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "{subject}",
+                    "size": 100
+                }},
+                "annotations": {{"test": "{annotation}"}}
+            }}"#
             )
-            .await;
-        assert!(matches!(result, Err(Error::UnsupportedForProxiedRepo)));
+        };
+        let tag = super::referrers_tag(&subject);
+
+        let mut indexes = vec![];
+        for annotation in ["first", "second"] {
+            let manifest = referrer(annotation);
+            let digest = Digest::digest_sha256_slice(manifest.as_bytes());
+            repos
+                .manifest
+                .insert_or_ignore(digest.as_str(), manifest.as_bytes())
+                .await
+                .unwrap();
+            repos
+                .repo_blob_assoc
+                .insert_manifest_assoc("myrepo", digest.as_str())
+                .await
+                .unwrap();
+            svc.update_referrers_tag("myrepo", &subject).await.unwrap();
+            indexes.push(
+                repos
+                    .tag
+                    .find_manifest_digest("myrepo", &tag)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+        assert_ne!(indexes[0], indexes[1]);
+        assert!(repos.manifest.find(&indexes[0]).await.is_err());
+
+        // Without referrers the tag and its index go away
+        let referrers = repos
+            .manifest
+            .list_referrers("myrepo", subject.as_str())
+            .await
+            .unwrap();
+        for r in referrers {
+            repos
+                .repo_blob_assoc
+                .delete_manifest_assoc("myrepo", &r.digest)
+                .await
+                .unwrap();
+        }
+        svc.update_referrers_tag("myrepo", &subject).await.unwrap();
+        assert!(
+            repos
+                .tag
+                .find_manifest_digest("myrepo", &tag)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(repos.manifest.find(&indexes[1]).await.is_err());
     }
 
     #[tokio::test]
     async fn rejects_invalid_digest() {
         let repos = repos_in_memory().await;
-        let svc = ReferrersService::new(repos);
+        let svc = setup_service(repos);
         let result = svc
-            .list_referrers("myrepo".to_string(), "not-a-digest".to_string())
+            .list_referrers("myrepo".to_string(), "not-a-digest".to_string(), None)
             .await;
         assert!(result.is_err());
     }
@@ -83,12 +417,13 @@ mod tests {
     #[tokio::test]
     async fn returns_empty_when_no_referrers() {
         let repos = repos_in_memory().await;
-        let svc = ReferrersService::new(repos);
+        let svc = setup_service(repos);
         let result = svc
             .list_referrers(
                 "myrepo".to_string(),
                 "sha256:abc123def456789012345678901234567890123456789012345678901234567"
                     .to_string(),
+                None,
             )
             .await
             .unwrap();
@@ -0,0 +1,381 @@
+//! Copies pushed images to the registries of the `replication` config, e.g. a
+//! disaster recovery registry. Pushes queue jobs in the database, so they
+//! survive restarts, and a background worker pushes the blobs and manifests
+//! to the targets, retrying failures with backoff.
+
+use std::sync::Arc;
+
+use ::oci_client::errors::OciDistributionError;
+use ::oci_client::{Reference, RegistryOperation};
+use futures::TryStreamExt;
+use regex::Regex;
+use reqwest::header::HeaderValue;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+use tokio_util::io::ReaderStream;
+
+use crate::TrowConfig;
+use crate::configuration::{RegistryEndpoint, ReplicationRule};
+use crate::repositories::Repositories;
+use crate::repositories::models::ReplicationJob;
+use crate::services::Error;
+use crate::services::manifest_service::determine_content_type;
+use crate::services::proxy_service::oci_client::OciClients;
+use crate::storage::StorageBackend;
+use crate::utils::manifest::{OCIManifest, REGEX_TAG, layer_is_distributable};
+
+/// Jobs pushed per pass of the worker
+const BATCH_SIZE: i64 = 100;
+/// How often the worker looks for jobs due for a retry
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// Delay before the first retry of a job, doubled on each attempt
+const FIRST_RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+#[derive(Debug, thiserror::Error)]
+enum ReplicationError {
+    #[error("no replication rule pushes {repo} to {target}")]
+    NoRule { repo: String, target: String },
+    #[error("{0}")]
+    Local(#[from] Error),
+    #[error("target registry error: {0}")]
+    Target(#[from] OciDistributionError),
+}
+
+impl ReplicationError {
+    /// Whether retrying can't help, e.g. the rule or the image is gone
+    fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            ReplicationError::NoRule { .. }
+                | ReplicationError::Local(Error::Db(sqlx::Error::RowNotFound))
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplicationService {
+    repos: Arc<Repositories>,
+    storage: Arc<dyn StorageBackend>,
+    rules: Vec<(Regex, ReplicationRule)>,
+    max_attempts: u32,
+    clients: OciClients,
+    /// Wakes the worker up when jobs are queued
+    queued: Notify,
+}
+
+impl ReplicationService {
+    pub fn new(
+        repos: Arc<Repositories>,
+        storage: Arc<dyn StorageBackend>,
+        config: Arc<TrowConfig>,
+    ) -> Self {
+        let replication = config.config_file.replication.as_ref();
+        let rules = replication
+            .map(|r| r.rules.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.repositories) {
+                Ok(regex) => Some((regex, rule.clone())),
+                Err(e) => {
+                    tracing::error!(
+                        "Ignoring replication rule to {}, invalid regex: {e}",
+                        rule.target.host
+                    );
+                    None
+                }
+            })
+            .collect();
+        Self {
+            repos,
+            storage,
+            rules,
+            max_attempts: replication.map_or(0, |r| r.max_attempts),
+            clients: OciClients::new(config.config_file.registry_proxies.egress_proxy.clone()),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Queues a job for each target that `repo` is replicated to, after the
+    /// manifest `digest` was pushed as `reference` (a tag or the digest).
+    pub async fn enqueue(&self, repo: &str, reference: &str, digest: &str) -> Result<(), Error> {
+        let mut queued = false;
+        for (regex, rule) in &self.rules {
+            if regex.is_match(repo) {
+                let target = target_repository(&rule.target, repo);
+                self.repos
+                    .replication_job
+                    .insert(repo, reference, digest, &target)
+                    .await?;
+                queued = true;
+            }
+        }
+        if queued {
+            self.queued.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Latest jobs, optionally only those with the given `status`
+    /// (`pending`, `done` or `failed`)
+    pub async fn jobs(
+        &self,
+        status: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ReplicationJob>, Error> {
+        Ok(self.repos.replication_job.list(status, limit).await?)
+    }
+
+    /// Blocks forever, pushing jobs as they are queued or due for a retry.
+    /// Returns immediately if nothing is replicated.
+    pub async fn watchdog(self: Arc<Self>) {
+        if self.rules.is_empty() {
+            return;
+        }
+        loop {
+            if self.run_once().await < BATCH_SIZE as usize {
+                tokio::select! {
+                    _ = self.queued.notified() => {}
+                    _ = time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        }
+    }
+
+    /// Pushes a batch of due jobs, returns how many were tried; safe to call
+    /// manually (used in tests).
+    pub async fn run_once(&self) -> usize {
+        let jobs = match self.repos.replication_job.list_due(BATCH_SIZE).await {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                tracing::error!("Could not list replication jobs: {e}");
+                return 0;
+            }
+        };
+        for job in &jobs {
+            let result = self.replicate(job).await;
+            if let Err(e) = self.record(job, result).await {
+                tracing::error!("Could not update replication job {}: {e}", job.id);
+            }
+        }
+        jobs.len()
+    }
+
+    async fn record(
+        &self,
+        job: &ReplicationJob,
+        result: Result<(), ReplicationError>,
+    ) -> Result<(), sqlx::Error> {
+        let jobs = &self.repos.replication_job;
+        let error = match result {
+            Ok(()) => {
+                tracing::debug!(
+                    "Replicated {}:{} to {}",
+                    job.repo,
+                    job.reference,
+                    job.target
+                );
+                return jobs.mark_done(job.id).await;
+            }
+            Err(e) => e,
+        };
+        let attempts = job.attempts + 1;
+        if error.is_permanent() || attempts >= i64::from(self.max_attempts) {
+            tracing::error!(
+                "Giving up replicating {}:{} to {} after {attempts} attempts: {error}",
+                job.repo,
+                job.reference,
+                job.target
+            );
+            return jobs.mark_failed(job.id, &error.to_string()).await;
+        }
+        let delay = FIRST_RETRY_DELAY_SECS
+            .saturating_mul(1 << job.attempts.min(20))
+            .min(MAX_RETRY_DELAY_SECS);
+        tracing::warn!(
+            "Could not replicate {}:{} to {}, retrying in {delay}s: {error}",
+            job.repo,
+            job.reference,
+            job.target
+        );
+        jobs.retry_later(job.id, &error.to_string(), delay).await
+    }
+
+    async fn replicate(&self, job: &ReplicationJob) -> Result<(), ReplicationError> {
+        let target = self
+            .rules
+            .iter()
+            .map(|(regex, rule)| (regex, &rule.target))
+            .find(|(regex, target)| {
+                regex.is_match(&job.repo) && target_repository(target, &job.repo) == job.target
+            })
+            .map(|(_, target)| target)
+            .ok_or_else(|| ReplicationError::NoRule {
+                repo: job.repo.clone(),
+                target: job.target.clone(),
+            })?;
+        let repository = job.target[target.host.len()..].trim_start_matches('/');
+        let client_config = target.registry_config();
+        let (cl, auth) = self
+            .clients
+            .get(&target.host, Some(&client_config))
+            .await
+            .map_err(Error::from)?;
+
+        // The tag is only moved if it still points at this manifest, so
+        // retries of older jobs don't undo newer pushes
+        let is_tag = REGEX_TAG.is_match(&job.reference);
+        let tagged = is_tag
+            && self
+                .repos
+                .tag
+                .find_manifest_digest(&job.repo, &job.reference)
+                .await
+                .map_err(Error::from)?
+                .as_deref()
+                == Some(job.manifest_digest.as_str());
+        let image = if tagged {
+            Reference::with_tag(
+                target.host.clone(),
+                repository.to_string(),
+                job.reference.clone(),
+            )
+        } else {
+            Reference::with_digest(
+                target.host.clone(),
+                repository.to_string(),
+                job.manifest_digest.clone(),
+            )
+        };
+        let authenticated = cl.auth(&image, &auth, RegistryOperation::Push).await;
+        if authenticated.is_err() {
+            self.clients.invalidate(&target.host, Some(&client_config));
+        }
+        authenticated?;
+        self.push_manifest(&cl, &image, &job.repo, &job.manifest_digest)
+            .await
+    }
+
+    /// Pushes the manifest `digest` of `repo` as `image`, after its blobs or,
+    /// for an index, its child manifests
+    async fn push_manifest(
+        &self,
+        cl: &::oci_client::Client,
+        image: &Reference,
+        repo: &str,
+        digest: &str,
+    ) -> Result<(), ReplicationError> {
+        let stored = self
+            .repos
+            .manifest
+            .find(digest)
+            .await
+            .map_err(Error::from)?;
+        let manifest: OCIManifest = serde_json::from_slice(&stored.blob)
+            .map_err(|e| Error::ManifestInvalid(e.to_string()))?;
+        match &manifest {
+            OCIManifest::V2(m) => {
+                let distributable_layers = m
+                    .layers()
+                    .iter()
+                    .filter(|l| layer_is_distributable(l.media_type()));
+                for descriptor in std::iter::once(m.config()).chain(distributable_layers) {
+                    let blob_digest = descriptor.digest().to_string();
+                    if !cl.blob_exists(image, &blob_digest).await? {
+                        let blob = self
+                            .storage
+                            .get_blob_stream(repo, &blob_digest, None)
+                            .await
+                            .map_err(Error::from)?;
+                        let stream = ReaderStream::new(blob.reader()).map_err(|e| e.into());
+                        cl.push_blob_stream(image, stream, &blob_digest).await?;
+                    }
+                }
+            }
+            OCIManifest::List(index) => {
+                for child in index.manifests() {
+                    let child_digest = child.digest().to_string();
+                    let child_image = image.clone_with_digest(child_digest.clone());
+                    Box::pin(self.push_manifest(cl, &child_image, repo, &child_digest)).await?;
+                }
+            }
+        }
+
+        let content_type = match stored.media_type {
+            Some(mt) => mt,
+            None => determine_content_type(&stored.blob)?,
+        };
+        let content_type = HeaderValue::from_str(&content_type)
+            .map_err(|e| Error::ManifestInvalid(e.to_string()))?;
+        cl.push_manifest_raw(image, stored.blob, content_type)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Repository that `repo` is replicated to, including the registry host
+fn target_repository(target: &RegistryEndpoint, repo: &str) -> String {
+    match target
+        .repository_prefix
+        .as_deref()
+        .map(|p| p.trim_matches('/'))
+    {
+        Some(prefix) if !prefix.is_empty() => format!("{}/{prefix}/{repo}", target.host),
+        _ => format!("{}/{repo}", target.host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::ReplicationService;
+    use crate::TrowConfig;
+    use crate::configuration::{RegistryEndpoint, ReplicationConfig, ReplicationRule};
+    use crate::storage::FileStorage;
+    use crate::test_utilities::repos_in_memory;
+
+    #[tokio::test]
+    async fn failed_jobs_are_retried_with_backoff() {
+        let mut config = TrowConfig::new();
+        config.config_file.replication = Some(ReplicationConfig {
+            max_attempts: 3,
+            rules: vec![ReplicationRule {
+                repositories: "^team/".to_string(),
+                target: RegistryEndpoint {
+                    host: "127.0.0.1:1".to_string(),
+                    insecure: true,
+                    repository_prefix: Some("/dr/".to_string()),
+                    ..Default::default()
+                },
+            }],
+        });
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = ReplicationService::new(repos.clone(), storage, Arc::new(config));
+
+        let index = r#"{"schemaVersion":2,"manifests":[]}"#;
+        repos
+            .manifest
+            .insert_or_ignore("sha256:index", index.as_bytes())
+            .await
+            .unwrap();
+        svc.enqueue("other/app", "v1", "sha256:index")
+            .await
+            .unwrap();
+        svc.enqueue("team/app", "v1", "sha256:index").await.unwrap();
+        assert_eq!(svc.run_once().await, 1);
+
+        let jobs = svc.jobs(None, 10).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        let job = &jobs[0];
+        assert_eq!(job.target, "127.0.0.1:1/dr/team/app");
+        assert_eq!(job.status, "pending");
+        assert_eq!(job.attempts, 1);
+        assert!(job.last_error.is_some());
+        assert!(job.next_attempt_at >= job.updated_at + 30);
+        // Not due again before the delay
+        assert_eq!(svc.run_once().await, 0);
+    }
+}
@@ -0,0 +1,349 @@
+//! Copies the tags of upstream repositories into local repositories, as
+//! configured in `repo_mirrors`. Unlike the `f/` cache, mirrored images are
+//! complete local copies that the GC leaves alone.
+
+use std::sync::Arc;
+
+use regex::Regex;
+use tokio::time::{self, Duration};
+
+use crate::configuration::RepoMirror;
+use crate::repositories::Repositories;
+use crate::services::Error;
+use crate::services::proxy_service::ProxyService;
+use crate::utils::resolve_reference::parse_reference;
+use crate::{PROXY_DIR, TrowConfig};
+
+#[derive(Debug)]
+pub struct RepoMirrorService {
+    repos: Arc<Repositories>,
+    config: Arc<TrowConfig>,
+    proxy: Arc<ProxyService>,
+}
+
+impl RepoMirrorService {
+    pub fn new(
+        repos: Arc<Repositories>,
+        config: Arc<TrowConfig>,
+        proxy: Arc<ProxyService>,
+    ) -> Self {
+        Self {
+            repos,
+            config,
+            proxy,
+        }
+    }
+
+    /// Blocks forever, syncing the mirrored repositories on the configured
+    /// interval. Returns immediately if no repository is mirrored.
+    pub async fn watchdog(self: Arc<Self>) {
+        let Some(repo_mirrors) = &self.config.config_file.repo_mirrors else {
+            return;
+        };
+        let mut interval = time::interval(Duration::from_secs(repo_mirrors.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            self.run_once().await;
+        }
+    }
+
+    /// Syncs every mirrored repository once; safe to call manually (used in tests).
+    pub async fn run_once(&self) {
+        let Some(repo_mirrors) = &self.config.config_file.repo_mirrors else {
+            return;
+        };
+        for mirror in &repo_mirrors.repositories {
+            if let Err(e) = self.sync(mirror).await {
+                tracing::warn!(
+                    "Could not mirror {} into {}: {e}",
+                    mirror.source,
+                    mirror.target
+                );
+            }
+        }
+    }
+
+    /// Copies the new and moved tags of `mirror`, and deletes the removed ones
+    /// if asked to and all upstream tags could be listed. A tag that fails to
+    /// copy doesn't stop the others.
+    pub async fn sync(&self, mirror: &RepoMirror) -> Result<(), Error> {
+        let source = mirror.source.trim_matches('/');
+        if !source.contains('/') {
+            return Err(Error::Invalid(format!(
+                "{source} is not a repository of an upstream registry"
+            )));
+        }
+        if mirror.target.starts_with(PROXY_DIR) {
+            return Err(Error::UnsupportedForProxiedRepo);
+        }
+        let tag_regex = mirror
+            .tag_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| Error::Invalid(format!("invalid tag_regex: {e}")))?;
+        let is_mirrored = |tag: &str| tag_regex.as_ref().is_none_or(|r| r.is_match(tag));
+
+        let source = format!("{PROXY_DIR}{source}");
+        let image = parse_reference(&source, "latest", None)?;
+        let proxy_config = self
+            .config
+            .config_file
+            .registry_proxies
+            .registries
+            .get_for(image.registry(), image.repository());
+        let listed = self
+            .proxy
+            .list_all_remote_tags(&image, proxy_config)
+            .await?;
+        let mut remote_tags = listed.tags;
+        remote_tags.retain(|t| is_mirrored(t));
+
+        let mut failed = 0;
+        for tag in &remote_tags {
+            let image = parse_reference(&source, tag, None)?;
+            match self
+                .proxy
+                .copy_image(&image, proxy_config, &mirror.target, &mirror.platforms)
+                .await
+            {
+                Ok(digest) => tracing::debug!("Mirrored {image} ({digest}) into {}", mirror.target),
+                Err(e) => {
+                    tracing::warn!("Could not mirror {image} into {}: {e}", mirror.target);
+                    failed += 1;
+                }
+            }
+        }
+
+        // Tags missing from an incomplete listing may still be upstream
+        if mirror.delete_removed_tags && !listed.complete {
+            tracing::warn!(
+                "Not deleting tags of {}, the tags of {} could not all be listed",
+                mirror.target,
+                mirror.source
+            );
+        } else if mirror.delete_removed_tags {
+            let local_tags = self.repos.tag.list(&mirror.target, "", i64::MAX).await?;
+            for tag in local_tags {
+                if is_mirrored(&tag) && !remote_tags.contains(&tag) {
+                    tracing::info!(
+                        "Deleting {}:{tag}, removed from {}",
+                        mirror.target,
+                        mirror.source
+                    );
+                    self.repos.tag.delete(&mirror.target, &tag).await?;
+                }
+            }
+        }
+        tracing::info!(
+            "Mirrored {} tags of {} into {} ({failed} failed)",
+            remote_tags.len() - failed,
+            mirror.source,
+            mirror.target
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use super::RepoMirrorService;
+    use crate::TrowConfig;
+    use crate::configuration::{RepoMirror, SingleRegistryProxyConfig};
+    use crate::repositories::Repositories;
+    use crate::services::proxy_service::ProxyService;
+    use crate::storage::{FileStorage, StorageBackend};
+    use crate::test_utilities::{fake_upstream, repos_in_memory};
+    use crate::utils::digest::Digest;
+
+    fn setup_service(
+        upstream: &str,
+        repos: Arc<Repositories>,
+        storage: Arc<FileStorage>,
+    ) -> RepoMirrorService {
+        let mut config = TrowConfig::new();
+        config.config_file.registry_proxies.registries = vec![SingleRegistryProxyConfig {
+            host: upstream.to_string(),
+            insecure: true,
+            ..Default::default()
+        }]
+        .into();
+        let proxy = Arc::new(ProxyService::new(repos.clone(), storage));
+        RepoMirrorService::new(repos, Arc::new(config), proxy)
+    }
+
+    /// A tag `gone` of `mirrors/app`, which isn't upstream
+    async fn insert_removed_tag(repos: &Repositories) {
+        let old = r#"{"schemaVersion":2,"manifests":[]}"#;
+        repos
+            .manifest
+            .insert_or_ignore("sha256:old", old.as_bytes())
+            .await
+            .unwrap();
+        repos
+            .tag
+            .upsert("gone", "mirrors/app", "sha256:old")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_copies_matching_tags_and_deletes_removed_ones() {
+        // One image per tag, each with its own config and layer
+        let mut manifests = HashMap::new();
+        let mut blobs = HashMap::new();
+        for tag in ["v1", "v2", "dev"] {
+            let config = format!(r#"{{"tag":"{tag}"}}"#);
+            let layer = format!("layer of {tag}");
+            let config_digest = Digest::digest_sha256_slice(config.as_bytes()).to_string();
+            let layer_digest = Digest::digest_sha256_slice(layer.as_bytes()).to_string();
+            let manifest = format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config_digest}","size":{}}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{layer_digest}","size":{}}}]}}"#,
+                config.len(),
+                layer.len()
+            );
+            manifests.insert(tag.to_string(), manifest);
+            blobs.insert(config_digest, config.into_bytes());
+            blobs.insert(layer_digest, layer.into_bytes());
+        }
+        let addr = fake_upstream("org/app", manifests, blobs).await;
+
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = setup_service(&addr, repos.clone(), storage.clone());
+
+        // A tag removed upstream, and one that isn't mirrored
+        let old = r#"{"schemaVersion":2,"manifests":[]}"#;
+        repos
+            .manifest
+            .insert_or_ignore("sha256:old", old.as_bytes())
+            .await
+            .unwrap();
+        for tag in ["v0", "local"] {
+            repos
+                .tag
+                .upsert(tag, "mirrors/app", "sha256:old")
+                .await
+                .unwrap();
+        }
+
+        let mirror = RepoMirror {
+            source: format!("{addr}/org/app"),
+            target: "mirrors/app".to_string(),
+            tag_regex: Some(r"^v\d+".to_string()),
+            delete_removed_tags: true,
+            ..Default::default()
+        };
+        svc.sync(&mirror).await.unwrap();
+
+        let tags = repos.tag.list("mirrors/app", "", 10).await.unwrap();
+        assert_eq!(tags, ["local", "v1", "v2"]);
+        let v2 = repos
+            .tag
+            .find_manifest_digest("mirrors/app", "v2")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            repos
+                .repo_blob_assoc
+                .manifest_exists_in_repo(&v2, "mirrors/app")
+                .await
+                .unwrap()
+        );
+        // All blobs are stored when the tag is set
+        let layer = Digest::digest_sha256_slice(b"layer of v2");
+        assert!(
+            repos
+                .repo_blob_assoc
+                .blob_belongs_to_repo(layer.as_str(), "mirrors/app")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            storage.blob_size(layer.as_str()).await.unwrap(),
+            "layer of v2".len() as u64
+        );
+        let manifests = repos
+            .manifest
+            .list_manifests_in_repo_using_blob("mirrors/app", layer.as_str())
+            .await
+            .unwrap();
+        assert_eq!(manifests, std::slice::from_ref(&v2));
+
+        // Up to date tags are left alone
+        svc.sync(&mirror).await.unwrap();
+        assert_eq!(
+            repos
+                .tag
+                .find_manifest_digest("mirrors/app", "v2")
+                .await
+                .unwrap(),
+            Some(v2)
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_pages_through_upstream_tags() {
+        let index = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let tags = ["t1", "t2", "t3", "t4", "t5"];
+        let manifests = tags.map(|t| (t.to_string(), index.to_string())).into();
+        let addr = fake_upstream("org/app", manifests, HashMap::new()).await;
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = setup_service(&addr, repos.clone(), storage);
+        insert_removed_tag(&repos).await;
+
+        // The upstream lists FAKE_UPSTREAM_TAGS_PAGE tags per page
+        let mirror = RepoMirror {
+            source: format!("{addr}/org/app"),
+            target: "mirrors/app".to_string(),
+            delete_removed_tags: true,
+            ..Default::default()
+        };
+        svc.sync(&mirror).await.unwrap();
+        let local_tags = repos.tag.list("mirrors/app", "", 10).await.unwrap();
+        assert_eq!(local_tags, tags);
+    }
+
+    #[tokio::test]
+    async fn sync_keeps_tags_when_upstream_listing_is_incomplete() {
+        // Upstream failing on the second page of tags
+        let app = axum::Router::new().route(
+            "/v2/org/app/tags/list",
+            axum::routing::get(
+                |axum::extract::Query(query): axum::extract::Query<HashMap<String, String>>| async move {
+                    if query.contains_key("last") {
+                        return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+                    }
+                    Ok(axum::Json(
+                        serde_json::json!({"name": "org/app", "tags": ["v1"]}),
+                    ))
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let repos = repos_in_memory().await;
+        let dir = test_temp_dir::test_temp_dir!();
+        let storage = Arc::new(FileStorage::new(dir.as_path_untracked().to_owned()).unwrap());
+        let svc = setup_service(&addr, repos.clone(), storage);
+        insert_removed_tag(&repos).await;
+
+        let mirror = RepoMirror {
+            source: format!("{addr}/org/app"),
+            target: "mirrors/app".to_string(),
+            delete_removed_tags: true,
+            ..Default::default()
+        };
+        svc.sync(&mirror).await.unwrap();
+        let local_tags = repos.tag.list("mirrors/app", "", 10).await.unwrap();
+        assert_eq!(local_tags, ["gone"]);
+    }
+}
@@ -1,47 +1,22 @@
-//! FileStorage layer: filesystem operations for blob and upload storage.
+//! Filesystem storage backend.
 //!
-//! This module handles all filesystem I/O for container image blobs and
-//! in-progress uploads. Services interact with this layer through
-//! `FileStorage` methods.
+//! Blobs are stored in `<data_dir>/blobs` and in-progress uploads in
+//! `<data_dir>/uploads`, both named after their digest or upload UUID.
 
 use std::borrow::Cow;
 use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::{io, str};
 
-use bytes::Bytes;
-use futures::Stream;
+use async_trait::async_trait;
 use tokio::fs;
-use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::time::Duration;
 
+use super::{BlobReadStream, BlobStream, StorageBackend, StorageBackendError, Stored};
 use crate::types::BoundedStream;
-
-pub struct Stored {
-    pub total_stored: u64,
-    pub chunk: u64,
-}
 use crate::utils::temporary_file::FileWrapper;
 
-// Storage Driver Error
-#[derive(thiserror::Error, Debug)]
-pub enum StorageBackendError {
-    #[error("the name `{0}` is not valid")]
-    InvalidName(String),
-    #[error("Blob not found:{0}")]
-    BlobNotFound(PathBuf),
-    #[error("Digest did not match content")]
-    InvalidDigest,
-    #[error("Unsupported Operation")]
-    Unsupported,
-    #[error("Invalid content range")]
-    InvalidContentRange,
-    #[error("Internal error: {0}")]
-    Internal(Cow<'static, str>),
-    #[error("IO error: {0}")]
-    Io(#[from] io::Error),
-}
-
 #[derive(Clone, Debug)]
 pub struct FileStorage {
     blobs_dir: PathBuf,
@@ -76,38 +51,59 @@ impl FileStorage {
             uploads_dir,
         })
     }
+}
 
-    pub async fn get_blob_stream<'a>(
+#[async_trait]
+impl StorageBackend for FileStorage {
+    async fn get_blob_stream(
         &self,
         repo_name: &str,
         digest: &str,
-    ) -> Result<BoundedStream<impl AsyncRead + use<'a>>, StorageBackendError> {
-        tracing::debug!("Get blob {repo_name}@{digest}");
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<BlobReadStream, StorageBackendError> {
+        tracing::debug!("Get blob {repo_name}@{digest} ({range:?})");
         let path = self.blobs_dir.join(digest);
-        let file = tokio::fs::File::open(&path).await.map_err(|e| {
+        let mut file = tokio::fs::File::open(&path).await.map_err(|e| {
             tracing::error!("Could not open blob: {}", e);
-            StorageBackendError::BlobNotFound(path)
+            StorageBackendError::BlobNotFound(path.display().to_string())
         })?;
-        let size = file.metadata().await?.len() as usize;
-        Ok(BoundedStream::new(size, file))
+        let size = file.metadata().await?.len();
+        match range {
+            Some(range) => {
+                if *range.end() >= size {
+                    return Err(StorageBackendError::InvalidContentRange);
+                }
+                let len = range.end() - range.start() + 1;
+                file.seek(io::SeekFrom::Start(*range.start())).await?;
+                Ok(BoundedStream::new(len as usize, Box::pin(file.take(len))))
+            }
+            None => Ok(BoundedStream::new(size as usize, Box::pin(file))),
+        }
+    }
+
+    async fn blob_size(&self, digest: &str) -> Result<u64, StorageBackendError> {
+        let path = self.blobs_dir.join(digest);
+        match fs::metadata(&path).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(
+                StorageBackendError::BlobNotFound(path.display().to_string()),
+            ),
+            Err(e) => Err(StorageBackendError::Io(e)),
+        }
     }
 
-    pub async fn write_blob_stream<S, E>(
+    async fn write_blob_stream(
         &self,
         digest: &str,
-        stream: S,
+        stream: BlobStream<'_>,
         verify: bool,
-    ) -> Result<PathBuf, StorageBackendError>
-    where
-        S: Stream<Item = Result<Bytes, E>> + Unpin,
-        E: std::error::Error + Send + Sync + 'static,
-    {
+    ) -> Result<u64, StorageBackendError> {
         tracing::debug!("Write blob {digest}");
         let tmp_location = self.uploads_dir.join(digest);
         let location = self.blobs_dir.join(digest);
         if location.exists() {
             tracing::info!(digest = digest, "Blob already exists");
-            return Ok(location);
+            return Ok(fs::metadata(&location).await?.len());
         }
         let mut tmp_file = match FileWrapper::new_tmp(tmp_location.clone()).await {
             // All good
@@ -120,9 +116,11 @@ impl FileStorage {
                     tokio::time::sleep(Duration::from_millis(200)).await;
                 }
                 if location.exists() {
-                    return Ok(location);
+                    return Ok(fs::metadata(&location).await?.len());
                 } else {
-                    return Err(StorageBackendError::BlobNotFound(location));
+                    return Err(StorageBackendError::BlobNotFound(
+                        location.display().to_string(),
+                    ));
                 }
             }
             Err(e) => {
@@ -130,7 +128,7 @@ impl FileStorage {
                 return Err(StorageBackendError::Io(e));
             }
         };
-        tmp_file.write_stream(stream).await?;
+        let size = tmp_file.write_stream(stream).await? as u64;
         if verify {
             let tmp_digest = tmp_file.digest().await.map_err(|e| {
                 StorageBackendError::Internal(Cow::Owned(format!(
@@ -142,21 +140,15 @@ impl FileStorage {
             }
         }
         tmp_file.rename(&location).await?;
-        Ok(location)
+        Ok(size)
     }
 
-    /// Writes part of a blob to disk.
-    /// Upload then needs to be "completed"
-    pub async fn write_blob_part_stream<S, E>(
+    async fn write_blob_part_stream(
         &self,
         upload_id: &uuid::Uuid,
-        stream: S,
+        stream: BlobStream<'_>,
         range: Option<RangeInclusive<u64>>,
-    ) -> Result<Stored, StorageBackendError>
-    where
-        S: Stream<Item = Result<Bytes, E>> + Unpin,
-        E: std::error::Error + Send + Sync + 'static,
-    {
+    ) -> Result<Stored, StorageBackendError> {
         tracing::debug!("Write blob part {upload_id} ({range:?})");
         let tmp_location = self.uploads_dir.join(upload_id.to_string());
         let mut tmp_file = FileWrapper::append(tmp_location.clone())
@@ -164,7 +156,9 @@ impl FileStorage {
             .map_err(|e| {
                 tracing::error!("Could not open tmp file {}: {}", tmp_location.display(), e);
                 match e.kind() {
-                    io::ErrorKind::NotFound => StorageBackendError::BlobNotFound(tmp_location),
+                    io::ErrorKind::NotFound => {
+                        StorageBackendError::BlobNotFound(tmp_location.display().to_string())
+                    }
                     io::ErrorKind::AlreadyExists => StorageBackendError::InvalidContentRange,
                     _ => StorageBackendError::Io(e),
                 }
@@ -202,7 +196,7 @@ impl FileStorage {
         })
     }
 
-    pub async fn complete_blob_write(
+    async fn complete_blob_write(
         &self,
         upload_id: &uuid::Uuid,
         user_digest: &str,
@@ -210,17 +204,6 @@ impl FileStorage {
         tracing::debug!("Complete blob write {upload_id}");
         let tmp_location = self.uploads_dir.join(upload_id.to_string());
         let final_location = self.blobs_dir.join(user_digest);
-        // Should we even do this ? It breaks OCI tests:
-        // let f = std::fs::File::open(&tmp_location)?;
-        // let calculated_digest = Digest::digest_sha256(f)?;
-        // if &calculated_digest != user_digest {
-        //     tracing::error!(
-        //         "Upload did not match given digest. Was given {} but got {}",
-        //         user_digest,
-        //         calculated_digest
-        //     );
-        //     return Err(StorageBackendError::InvalidDigest);
-        // }
         fs::create_dir_all(final_location.parent().unwrap())
             .await
             .unwrap();
@@ -230,7 +213,7 @@ impl FileStorage {
         Ok(())
     }
 
-    pub async fn delete_blob(&self, digest: &str) -> Result<(), StorageBackendError> {
+    async fn delete_blob(&self, digest: &str) -> Result<(), StorageBackendError> {
         tracing::debug!("Delete blob {digest}");
         let blob_path = self.blobs_dir.join(digest);
         if let Err(e) = tokio::fs::remove_file(blob_path).await
@@ -241,7 +224,7 @@ impl FileStorage {
         Ok(())
     }
 
-    pub async fn delete_upload(&self, uuid: &str) -> Result<(), StorageBackendError> {
+    async fn delete_upload(&self, uuid: &str) -> Result<(), StorageBackendError> {
         tracing::debug!("Delete upload {uuid}");
         let blob_path = self.uploads_dir.join(uuid);
         if let Err(e) = tokio::fs::remove_file(blob_path).await
@@ -253,7 +236,7 @@ impl FileStorage {
     }
 
     // TODO: generator / coroutine
-    pub async fn list_blobs(&self) -> Result<Vec<String>, StorageBackendError> {
+    async fn list_blobs(&self) -> Result<Vec<String>, StorageBackendError> {
         let mut read_dir = fs::read_dir(&self.blobs_dir).await?;
         let mut entries = Vec::new();
         while let Some(entry) = read_dir.next_entry().await? {
@@ -264,7 +247,7 @@ impl FileStorage {
         Ok(entries)
     }
 
-    pub async fn is_ready(&self) -> Result<(), StorageBackendError> {
+    async fn is_ready(&self) -> Result<(), StorageBackendError> {
         let path = self.uploads_dir.join("fs-ready");
         let mut file = tokio::fs::File::create(path).await?;
         let size = file.write(b"Hello World").await?;
@@ -289,15 +272,11 @@ impl FileStorage {
 
 #[cfg(test)]
 mod tests {
-
-    use std::pin::pin;
+    use bytes::Bytes;
+    use futures::StreamExt;
 
     use super::*;
 
-    fn bytes_to_stream(bytes: Bytes) -> impl Stream<Item = Result<Bytes, reqwest::Error>> {
-        futures::stream::once(async move { Ok(bytes) })
-    }
-
     #[test]
     fn file_storage_new() {
         let dir = test_temp_dir::test_temp_dir!();
@@ -311,18 +290,18 @@ mod tests {
     async fn file_storage_write_blob_stream() {
         let dir = test_temp_dir::test_temp_dir!();
         let store = FileStorage::new(dir.as_path_untracked().to_owned()).unwrap();
-        let stream = pin!(bytes_to_stream(Bytes::from("test")));
+        let stream = futures::stream::once(async { Ok(Bytes::from("test")) }).boxed();
         let digest = "sha256:123456789101112131415161718192021";
-        let location = store
+        let size = store
             .write_blob_stream(digest, stream, false)
             .await
             .unwrap();
-        assert!(location.exists());
-        assert_eq!(
-            location,
+        assert_eq!(size, 4);
+        assert!(
             store
                 .blobs_dir
                 .join("sha256:123456789101112131415161718192021")
+                .exists()
         );
         drop(dir);
     }
@@ -0,0 +1,123 @@
+//! Storage layer: blob and upload persistence.
+//!
+//! Services interact with this layer through the [`StorageBackend`] trait.
+//! Two implementations are provided: [`FileStorage`] keeps everything on the
+//! local filesystem, [`S3Storage`] keeps blobs in an S3-compatible object store.
+
+mod filesystem;
+mod s3;
+
+use std::borrow::Cow;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use tokio::io::AsyncRead;
+
+pub use self::filesystem::FileStorage;
+pub use self::s3::S3Storage;
+use crate::configuration::StorageConfig;
+use crate::types::BoundedStream;
+
+pub struct Stored {
+    pub total_stored: u64,
+    pub chunk: u64,
+}
+
+/// Stream of blob bytes handed to a [`StorageBackend`] for writing.
+pub type BlobStream<'a> = BoxStream<'a, Result<Bytes, io::Error>>;
+
+/// Reader over a stored blob, along with its size.
+pub type BlobReadStream = BoundedStream<Pin<Box<dyn AsyncRead + Send>>>;
+
+// Storage Driver Error
+#[derive(thiserror::Error, Debug)]
+pub enum StorageBackendError {
+    #[error("the name `{0}` is not valid")]
+    InvalidName(String),
+    #[error("Blob not found:{0}")]
+    BlobNotFound(String),
+    #[error("Digest did not match content")]
+    InvalidDigest,
+    #[error("Unsupported Operation")]
+    Unsupported,
+    #[error("Invalid content range")]
+    InvalidContentRange,
+    #[error("Internal error: {0}")]
+    Internal(Cow<'static, str>),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    /// Reads a blob, or only the given byte range of it.
+    /// The range must be within the blob, see [`StorageBackend::blob_size`].
+    async fn get_blob_stream(
+        &self,
+        repo_name: &str,
+        digest: &str,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<BlobReadStream, StorageBackendError>;
+
+    async fn blob_size(&self, digest: &str) -> Result<u64, StorageBackendError>;
+
+    /// Writes a whole blob in one go, returns the size of the stored blob.
+    /// If `verify` is set, the blob is only stored if its content matches `digest`.
+    async fn write_blob_stream(
+        &self,
+        digest: &str,
+        stream: BlobStream<'_>,
+        verify: bool,
+    ) -> Result<u64, StorageBackendError>;
+
+    /// Writes part of a blob.
+    /// Upload then needs to be "completed"
+    async fn write_blob_part_stream(
+        &self,
+        upload_id: &uuid::Uuid,
+        stream: BlobStream<'_>,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<Stored, StorageBackendError>;
+
+    /// Moves a finished upload to its final location.
+    /// The content is not checked against `user_digest`, this is up to the caller.
+    async fn complete_blob_write(
+        &self,
+        upload_id: &uuid::Uuid,
+        user_digest: &str,
+    ) -> Result<(), StorageBackendError>;
+
+    async fn delete_blob(&self, digest: &str) -> Result<(), StorageBackendError>;
+
+    async fn delete_upload(&self, uuid: &str) -> Result<(), StorageBackendError>;
+
+    async fn list_blobs(&self) -> Result<Vec<String>, StorageBackendError>;
+
+    async fn is_ready(&self) -> Result<(), StorageBackendError>;
+}
+
+/// Boxes any byte stream into a [`BlobStream`].
+pub fn into_blob_stream<'a, S, E>(stream: S) -> BlobStream<'a>
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'a,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    stream.map(|r| r.map_err(io::Error::other)).boxed()
+}
+
+/// Builds the storage backend selected in the config file.
+pub async fn from_config(
+    config: &StorageConfig,
+    data_dir: PathBuf,
+) -> Result<Arc<dyn StorageBackend>, StorageBackendError> {
+    Ok(match config {
+        StorageConfig::Filesystem => Arc::new(FileStorage::new(data_dir)?),
+        StorageConfig::S3(s3_config) => Arc::new(S3Storage::new(s3_config).await?),
+    })
+}
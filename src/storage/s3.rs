@@ -0,0 +1,1174 @@
+//! S3 storage backend.
+//!
+//! Blobs are stored under `<prefix>blobs/<digest>`. An in-progress upload is
+//! made of an S3 multipart upload on `<prefix>uploads/<uuid>` plus a
+//! `<prefix>uploads/<uuid>.tail` object buffering the bytes that don't yet
+//! fill a part. No state is kept in memory between requests, so uploads
+//! survive restarts and can be spread over several Trow instances.
+
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{
+    Credentials, Region, RequestChecksumCalculation, ResponseChecksumValidation,
+};
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+
+use super::{BlobReadStream, BlobStream, StorageBackend, StorageBackendError, Stored};
+use crate::configuration::S3StorageConfig;
+use crate::types::BoundedStream;
+use crate::utils::digest::IncrementalDigest;
+
+const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+/// Largest object that can be copied with a single CopyObject request
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+fn s3_error<E, R>(op: &'static str) -> impl FnOnce(SdkError<E, R>) -> StorageBackendError
+where
+    E: std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug,
+{
+    move |e| {
+        tracing::error!("S3 {op} failed: {}", DisplayErrorContext(&e));
+        StorageBackendError::Internal(Cow::Owned(format!(
+            "S3 {op} failed: {}",
+            DisplayErrorContext(&e)
+        )))
+    }
+}
+
+/// State of an upload, as recovered from the bucket
+#[derive(Debug, Default)]
+struct UploadState {
+    multipart_id: Option<String>,
+    parts: Vec<CompletedPart>,
+    parts_size: u64,
+    tail: Option<Bytes>,
+}
+
+impl UploadState {
+    fn exists(&self) -> bool {
+        self.multipart_id.is_some() || self.tail.is_some()
+    }
+
+    fn size(&self) -> u64 {
+        self.parts_size + self.tail.as_ref().map_or(0, |t| t.len() as u64)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+    part_size: usize,
+}
+
+impl S3Storage {
+    pub async fn new(config: &S3StorageConfig) -> Result<Self, StorageBackendError> {
+        let mut loader = aws_config::defaults(BehaviorVersion::v2026_01_12());
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint) = &config.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if let (Some(key_id), Some(secret)) = (&config.access_key_id, &config.secret_access_key) {
+            loader = loader.credentials_provider(Credentials::new(
+                key_id,
+                secret,
+                None,
+                None,
+                "trow-config",
+            ));
+        }
+        let sdk_config = loader.load().await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(config.force_path_style)
+            // S3-compatible stores don't all support the newer checksum headers
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+        if sdk_config.region().is_none() {
+            builder = builder.region(Region::from_static("us-east-1"));
+        }
+        let part_size = config
+            .part_size
+            .map(|s| s.bytes() as usize)
+            .unwrap_or(DEFAULT_PART_SIZE);
+        if part_size == 0 {
+            return Err(StorageBackendError::Internal(Cow::Borrowed(
+                "S3 part_size must be greater than 0",
+            )));
+        }
+
+        Ok(Self {
+            client: Client::from_conf(builder.build()),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+            part_size,
+        })
+    }
+
+    fn blob_key(&self, digest: &str) -> String {
+        format!("{}blobs/{digest}", self.prefix)
+    }
+
+    fn upload_key(&self, uuid: &str) -> String {
+        format!("{}uploads/{uuid}", self.prefix)
+    }
+
+    fn tail_key(&self, uuid: &str) -> String {
+        format!("{}uploads/{uuid}.tail", self.prefix)
+    }
+
+    async fn get_object_bytes(&self, key: &str) -> Result<Option<Bytes>, StorageBackendError> {
+        let resp = match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                return Ok(None);
+            }
+            Err(e) => return Err(s3_error("GetObject")(e)),
+        };
+        let data = resp.body.collect().await.map_err(|e| {
+            StorageBackendError::Internal(Cow::Owned(format!("Could not read S3 object: {e}")))
+        })?;
+        Ok(Some(data.into_bytes()))
+    }
+
+    async fn put_object(&self, key: &str, data: Bytes) -> Result<(), StorageBackendError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(s3_error("PutObject"))?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), StorageBackendError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error("DeleteObject"))?;
+        Ok(())
+    }
+
+    async fn head_object_size(&self, key: &str) -> Result<Option<u64>, StorageBackendError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(resp) => Ok(Some(resp.content_length().unwrap_or(0) as u64)),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+            Err(e) => Err(s3_error("HeadObject")(e)),
+        }
+    }
+
+    async fn find_multipart_upload(
+        &self,
+        key: &str,
+    ) -> Result<Option<String>, StorageBackendError> {
+        let resp = self
+            .client
+            .list_multipart_uploads()
+            .bucket(&self.bucket)
+            .prefix(key)
+            .send()
+            .await
+            .map_err(s3_error("ListMultipartUploads"))?;
+        Ok(resp
+            .uploads()
+            .iter()
+            .find(|u| u.key() == Some(key))
+            .and_then(|u| u.upload_id().map(str::to_owned)))
+    }
+
+    async fn create_multipart_upload(&self, key: &str) -> Result<String, StorageBackendError> {
+        let resp = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(s3_error("CreateMultipartUpload"))?;
+        resp.upload_id()
+            .map(str::to_owned)
+            .ok_or(StorageBackendError::Internal(Cow::Borrowed(
+                "S3 did not return an upload ID",
+            )))
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        multipart_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<CompletedPart, StorageBackendError> {
+        let resp = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(multipart_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(s3_error("UploadPart"))?;
+        Ok(CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(resp.e_tag().map(str::to_owned))
+            .build())
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        multipart_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<(), StorageBackendError> {
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(multipart_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(s3_error("CompleteMultipartUpload"))?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        key: &str,
+        multipart_id: &str,
+    ) -> Result<(), StorageBackendError> {
+        match self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(multipart_id)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_upload()) => Ok(()),
+            Err(e) => Err(s3_error("AbortMultipartUpload")(e)),
+        }
+    }
+
+    /// Aborts a multipart upload that failed with `err`, so that its parts
+    /// don't stay in the bucket
+    async fn abort_after_error<T>(
+        &self,
+        key: &str,
+        multipart_id: &str,
+        err: StorageBackendError,
+    ) -> Result<T, StorageBackendError> {
+        if let Err(e) = self.abort_multipart_upload(key, multipart_id).await {
+            tracing::warn!("Could not abort multipart upload of {key}: {e}");
+        }
+        Err(err)
+    }
+
+    async fn upload_state(&self, uuid: &str) -> Result<UploadState, StorageBackendError> {
+        let key = self.upload_key(uuid);
+        let mut state = UploadState {
+            tail: self.get_object_bytes(&self.tail_key(uuid)).await?,
+            ..Default::default()
+        };
+        let Some(multipart_id) = self.find_multipart_upload(&key).await? else {
+            return Ok(state);
+        };
+        let mut marker = None;
+        loop {
+            let resp = self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(&key)
+                .upload_id(&multipart_id)
+                .set_part_number_marker(marker)
+                .send()
+                .await
+                .map_err(s3_error("ListParts"))?;
+            for part in resp.parts() {
+                state.parts_size += part.size().unwrap_or(0) as u64;
+                state.parts.push(
+                    CompletedPart::builder()
+                        .set_part_number(part.part_number())
+                        .set_e_tag(part.e_tag().map(str::to_owned))
+                        .build(),
+                );
+            }
+            marker = resp.next_part_number_marker().map(str::to_owned);
+            if !resp.is_truncated().unwrap_or(false) || marker.is_none() {
+                break;
+            }
+        }
+        state.multipart_id = Some(multipart_id);
+        Ok(state)
+    }
+
+    /// Server side copy, using a multipart copy for objects too large for CopyObject
+    async fn copy_object(
+        &self,
+        src: &str,
+        dst: &str,
+        size: u64,
+    ) -> Result<(), StorageBackendError> {
+        let copy_source = format!("{}/{src}", self.bucket);
+        if size <= MAX_COPY_SIZE {
+            self.client
+                .copy_object()
+                .bucket(&self.bucket)
+                .key(dst)
+                .copy_source(copy_source)
+                .send()
+                .await
+                .map_err(s3_error("CopyObject"))?;
+            return Ok(());
+        }
+
+        let multipart_id = self.create_multipart_upload(dst).await?;
+        let mut parts = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let part_number = parts.len() as i32 + 1;
+            let resp = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(dst)
+                .upload_id(&multipart_id)
+                .part_number(part_number)
+                .copy_source(&copy_source)
+                .copy_source_range(format!("bytes={start}-{end}"))
+                .send()
+                .await;
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) => {
+                    return self
+                        .abort_after_error(dst, &multipart_id, s3_error("UploadPartCopy")(e))
+                        .await;
+                }
+            };
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(
+                        resp.copy_part_result()
+                            .and_then(|r| r.e_tag())
+                            .map(str::to_owned),
+                    )
+                    .build(),
+            );
+            start = end + 1;
+        }
+        match self
+            .complete_multipart_upload(dst, &multipart_id, parts)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => self.abort_after_error(dst, &multipart_id, e).await,
+        }
+    }
+
+    /// Writes a whole blob without verification, multipart if it is large enough.
+    async fn write_blob_unverified(
+        &self,
+        key: &str,
+        mut stream: BlobStream<'_>,
+        hasher: &mut IncrementalDigest,
+    ) -> Result<(u64, Option<(String, Vec<CompletedPart>)>, Bytes), StorageBackendError> {
+        let mut buffer = BytesMut::new();
+        let mut multipart: Option<(String, Vec<CompletedPart>)> = None;
+        let mut size = 0;
+        let written = async {
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                buffer.extend_from_slice(&chunk);
+                if buffer.len() >= self.part_size {
+                    if multipart.is_none() {
+                        multipart = Some((self.create_multipart_upload(key).await?, Vec::new()));
+                    }
+                    let (id, parts) = multipart.as_mut().unwrap();
+                    let part = self
+                        .upload_part(key, id, parts.len() as i32 + 1, buffer.split().freeze())
+                        .await?;
+                    parts.push(part);
+                }
+            }
+            Ok(())
+        }
+        .await;
+        match (written, &multipart) {
+            (Ok(()), _) => Ok((size, multipart, buffer.freeze())),
+            (Err(e), Some((id, _))) => self.abort_after_error(key, id, e).await,
+            (Err(e), None) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn get_blob_stream(
+        &self,
+        repo_name: &str,
+        digest: &str,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<BlobReadStream, StorageBackendError> {
+        tracing::debug!("Get blob {repo_name}@{digest} ({range:?})");
+        let key = self.blob_key(digest);
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .set_range(range.map(|r| format!("bytes={}-{}", r.start(), r.end())))
+            .send()
+            .await
+            .map_err(|e| {
+                if e.as_service_error().is_some_and(|e| e.is_no_such_key()) {
+                    StorageBackendError::BlobNotFound(key.clone())
+                } else if e.raw_response().is_some_and(|r| r.status().as_u16() == 416) {
+                    StorageBackendError::InvalidContentRange
+                } else {
+                    s3_error("GetObject")(e)
+                }
+            })?;
+        let size = resp.content_length().unwrap_or(0) as usize;
+        Ok(BoundedStream::new(
+            size,
+            Box::pin(resp.body.into_async_read()),
+        ))
+    }
+
+    async fn blob_size(&self, digest: &str) -> Result<u64, StorageBackendError> {
+        let key = self.blob_key(digest);
+        self.head_object_size(&key)
+            .await?
+            .ok_or(StorageBackendError::BlobNotFound(key))
+    }
+
+    async fn write_blob_stream(
+        &self,
+        digest: &str,
+        stream: BlobStream<'_>,
+        verify: bool,
+    ) -> Result<u64, StorageBackendError> {
+        tracing::debug!("Write blob {digest}");
+        let key = self.blob_key(digest);
+        if let Some(size) = self.head_object_size(&key).await? {
+            tracing::info!(digest = digest, "Blob already exists");
+            return Ok(size);
+        }
+        // Parts are written straight to the final key, the multipart upload
+        // is only completed once the content has been verified.
+        let mut hasher = IncrementalDigest::new();
+        let (size, multipart, remainder) = self
+            .write_blob_unverified(&key, stream, &mut hasher)
+            .await?;
+        let verified = !verify || hasher.finalize().as_str() == digest;
+        let Some((id, mut parts)) = multipart else {
+            if !verified {
+                return Err(StorageBackendError::InvalidDigest);
+            }
+            self.put_object(&key, remainder).await?;
+            return Ok(size);
+        };
+        let completed = async {
+            if !verified {
+                return Err(StorageBackendError::InvalidDigest);
+            }
+            if !remainder.is_empty() {
+                let part = self
+                    .upload_part(&key, &id, parts.len() as i32 + 1, remainder)
+                    .await?;
+                parts.push(part);
+            }
+            self.complete_multipart_upload(&key, &id, parts).await
+        }
+        .await;
+        match completed {
+            Ok(()) => Ok(size),
+            Err(e) => self.abort_after_error(&key, &id, e).await,
+        }
+    }
+
+    async fn write_blob_part_stream(
+        &self,
+        upload_id: &uuid::Uuid,
+        mut stream: BlobStream<'_>,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<Stored, StorageBackendError> {
+        tracing::debug!("Write blob part {upload_id} ({range:?})");
+        let uuid = upload_id.to_string();
+        let key = self.upload_key(&uuid);
+        let mut state = self.upload_state(&uuid).await?;
+        let offset = state.size();
+        let range_len = range.as_ref().map(|r| r.end() - r.start() + 1);
+
+        if let Some(range) = &range
+            && *range.start() != offset
+        {
+            tracing::error!(
+                "Invalid content-range: start={} upload_pos={}",
+                range.start(),
+                offset
+            );
+            return Err(StorageBackendError::InvalidContentRange);
+        }
+
+        let mut buffer = BytesMut::from(state.tail.take().unwrap_or_default());
+        let mut bytes_written = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|_e| {
+                StorageBackendError::Internal(Cow::Borrowed("Couldn't read upload stream"))
+            })?;
+            bytes_written += chunk.len() as u64;
+            buffer.extend_from_slice(&chunk);
+            if buffer.len() >= self.part_size {
+                let multipart_id = match &state.multipart_id {
+                    Some(id) => id.clone(),
+                    None => {
+                        let id = self.create_multipart_upload(&key).await?;
+                        state.multipart_id = Some(id.clone());
+                        id
+                    }
+                };
+                let part_number = state.parts.len() as i32 + 1;
+                let part = self
+                    .upload_part(&key, &multipart_id, part_number, buffer.split().freeze())
+                    .await?;
+                state.parts.push(part);
+                // Flushed bytes are now in a part, keep the tail consistent
+                self.put_object(&self.tail_key(&uuid), Bytes::new()).await?;
+            }
+        }
+        self.put_object(&self.tail_key(&uuid), buffer.freeze())
+            .await?;
+
+        if matches!(range_len, Some(len) if len != bytes_written) {
+            tracing::error!(
+                "Invalid content-length: expected={} actual={}",
+                range_len.unwrap(),
+                bytes_written
+            );
+            return Err(StorageBackendError::InvalidContentRange);
+        }
+
+        Ok(Stored {
+            total_stored: bytes_written + offset,
+            chunk: bytes_written,
+        })
+    }
+
+    async fn complete_blob_write(
+        &self,
+        upload_id: &uuid::Uuid,
+        user_digest: &str,
+    ) -> Result<(), StorageBackendError> {
+        tracing::debug!("Complete blob write {upload_id}");
+        let uuid = upload_id.to_string();
+        let key = self.upload_key(&uuid);
+        let final_key = self.blob_key(user_digest);
+        let mut state = self.upload_state(&uuid).await?;
+        if !state.exists() {
+            return Err(StorageBackendError::BlobNotFound(key));
+        }
+        let size = state.size();
+        let tail = state.tail.take().unwrap_or_default();
+
+        match state.multipart_id {
+            Some(multipart_id) => {
+                if !tail.is_empty() {
+                    let part_number = state.parts.len() as i32 + 1;
+                    let part = self
+                        .upload_part(&key, &multipart_id, part_number, tail)
+                        .await?;
+                    state.parts.push(part);
+                }
+                self.complete_multipart_upload(&key, &multipart_id, state.parts)
+                    .await?;
+                self.copy_object(&key, &final_key, size).await?;
+                self.delete_object(&key).await?;
+            }
+            None => self.put_object(&final_key, tail).await?,
+        }
+        self.delete_object(&self.tail_key(&uuid)).await
+    }
+
+    async fn delete_blob(&self, digest: &str) -> Result<(), StorageBackendError> {
+        tracing::debug!("Delete blob {digest}");
+        self.delete_object(&self.blob_key(digest)).await
+    }
+
+    async fn delete_upload(&self, uuid: &str) -> Result<(), StorageBackendError> {
+        tracing::debug!("Delete upload {uuid}");
+        let key = self.upload_key(uuid);
+        if let Some(multipart_id) = self.find_multipart_upload(&key).await? {
+            self.abort_multipart_upload(&key, &multipart_id).await?;
+        }
+        self.delete_object(&self.tail_key(uuid)).await?;
+        self.delete_object(&key).await
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<String>, StorageBackendError> {
+        let blobs_prefix = format!("{}blobs/", self.prefix);
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&blobs_prefix)
+            .into_paginator()
+            .send();
+        let mut entries = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(s3_error("ListObjectsV2"))?;
+            entries.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|o| o.key()?.strip_prefix(&blobs_prefix))
+                    .map(str::to_owned),
+            );
+        }
+        Ok(entries)
+    }
+
+    async fn is_ready(&self) -> Result<(), StorageBackendError> {
+        self.put_object(
+            &self.upload_key("s3-ready"),
+            Bytes::from_static(b"Hello World"),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+
+    use axum::Router;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{HeaderMap, Method, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::storage::into_blob_stream;
+    use crate::utils::digest::Digest;
+
+    /// In-memory stand-in for the subset of the S3 API used by [`S3Storage`]
+    #[derive(Default)]
+    struct FakeS3 {
+        objects: BTreeMap<String, Bytes>,
+        /// upload ID -> (key, part number -> data)
+        multipart: HashMap<String, (String, BTreeMap<i32, Bytes>)>,
+        /// UploadPart of this part number fails
+        fail_part: Option<i32>,
+        /// CompleteMultipartUpload fails
+        fail_complete: bool,
+    }
+
+    type FakeS3State = Arc<Mutex<FakeS3>>;
+
+    fn xml(body: String) -> Response {
+        (
+            [("content-type", "application/xml")],
+            format!(r#"<?xml version="1.0" encoding="UTF-8"?>{body}"#),
+        )
+            .into_response()
+    }
+
+    fn s3_err(status: StatusCode, code: &str) -> Response {
+        let mut resp = xml(format!(
+            "<Error><Code>{code}</Code><Message>{code}</Message></Error>"
+        ));
+        *resp.status_mut() = status;
+        resp
+    }
+
+    fn etag(data: &[u8]) -> String {
+        format!("\"{}\"", &Digest::digest_sha256_slice(data).hash()[..32])
+    }
+
+    async fn bucket_handler(
+        State(s3): State<FakeS3State>,
+        method: Method,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> Response {
+        let s3 = s3.lock().unwrap();
+        let prefix = query.get("prefix").cloned().unwrap_or_default();
+        match method {
+            Method::HEAD => StatusCode::OK.into_response(),
+            Method::GET if query.contains_key("uploads") => {
+                let uploads: String = s3
+                    .multipart
+                    .iter()
+                    .filter(|(_, (key, _))| key.starts_with(&prefix))
+                    .map(|(id, (key, _))| {
+                        format!("<Upload><Key>{key}</Key><UploadId>{id}</UploadId></Upload>")
+                    })
+                    .collect();
+                xml(format!(
+                    "<ListMultipartUploadsResult><IsTruncated>false</IsTruncated>{uploads}</ListMultipartUploadsResult>"
+                ))
+            }
+            Method::GET => {
+                // Two objects per page to exercise pagination
+                let start = query.get("continuation-token").cloned().unwrap_or_default();
+                let mut keys = s3
+                    .objects
+                    .iter()
+                    .filter(|(k, _)| k.starts_with(&prefix) && **k > start);
+                let page: Vec<_> = keys.by_ref().take(2).collect();
+                let next = match (keys.next(), page.last()) {
+                    (Some(_), Some((last, _))) => format!(
+                        "<IsTruncated>true</IsTruncated><NextContinuationToken>{last}</NextContinuationToken>"
+                    ),
+                    _ => "<IsTruncated>false</IsTruncated>".to_string(),
+                };
+                let contents: String = page
+                    .iter()
+                    .map(|(k, v)| {
+                        format!(
+                            "<Contents><Key>{k}</Key><Size>{}</Size></Contents>",
+                            v.len()
+                        )
+                    })
+                    .collect();
+                xml(format!(
+                    "<ListBucketResult><KeyCount>{}</KeyCount>{next}{contents}</ListBucketResult>",
+                    page.len()
+                ))
+            }
+            _ => s3_err(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+        }
+    }
+
+    async fn object_handler(
+        State(s3): State<FakeS3State>,
+        method: Method,
+        Path((bucket, key)): Path<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response {
+        let mut s3 = s3.lock().unwrap();
+        let upload_id = query.get("uploadId");
+        let copy_source = headers
+            .get("x-amz-copy-source")
+            .map(|v| v.to_str().unwrap().trim_start_matches('/').to_string());
+        let copy_data = match copy_source {
+            Some(src) => {
+                let src = src.strip_prefix(&format!("{bucket}/")).unwrap().to_string();
+                match s3.objects.get(&src) {
+                    Some(data) => Some(data.clone()),
+                    None => return s3_err(StatusCode::NOT_FOUND, "NoSuchKey"),
+                }
+            }
+            None => None,
+        };
+
+        match method {
+            Method::PUT if upload_id.is_some() => {
+                let part_number: i32 = query["partNumber"].parse().unwrap();
+                if s3.fail_part == Some(part_number) {
+                    return s3_err(StatusCode::BAD_REQUEST, "InvalidArgument");
+                }
+                let Some((_, parts)) = s3.multipart.get_mut(upload_id.unwrap()) else {
+                    return s3_err(StatusCode::NOT_FOUND, "NoSuchUpload");
+                };
+                match copy_data {
+                    Some(data) => {
+                        let range = headers["x-amz-copy-source-range"].to_str().unwrap();
+                        let (start, end) =
+                            range.trim_start_matches("bytes=").split_once('-').unwrap();
+                        let part =
+                            data.slice(start.parse::<usize>().unwrap()..=end.parse().unwrap());
+                        let tag = etag(&part);
+                        parts.insert(part_number, part);
+                        xml(format!(
+                            "<CopyPartResult><ETag>{tag}</ETag></CopyPartResult>"
+                        ))
+                    }
+                    None => {
+                        let tag = etag(&body);
+                        parts.insert(part_number, body);
+                        ([("etag", tag)], "").into_response()
+                    }
+                }
+            }
+            Method::PUT => match copy_data {
+                Some(data) => {
+                    let tag = etag(&data);
+                    s3.objects.insert(key, data);
+                    xml(format!(
+                        "<CopyObjectResult><ETag>{tag}</ETag></CopyObjectResult>"
+                    ))
+                }
+                None => {
+                    let tag = etag(&body);
+                    s3.objects.insert(key, body);
+                    ([("etag", tag)], "").into_response()
+                }
+            },
+            Method::POST if query.contains_key("uploads") => {
+                let id = uuid::Uuid::new_v4().to_string();
+                s3.multipart
+                    .insert(id.clone(), (key.clone(), BTreeMap::new()));
+                xml(format!(
+                    "<InitiateMultipartUploadResult><Bucket>{bucket}</Bucket><Key>{key}</Key><UploadId>{id}</UploadId></InitiateMultipartUploadResult>"
+                ))
+            }
+            Method::POST if upload_id.is_some() => {
+                if s3.fail_complete {
+                    return s3_err(StatusCode::BAD_REQUEST, "InvalidPart");
+                }
+                let Some((_, parts)) = s3.multipart.remove(upload_id.unwrap()) else {
+                    return s3_err(StatusCode::NOT_FOUND, "NoSuchUpload");
+                };
+                let requested = regex::Regex::new(r"<PartNumber>(\d+)</PartNumber>").unwrap();
+                let body = std::str::from_utf8(&body).unwrap();
+                let mut data = BytesMut::new();
+                for cap in requested.captures_iter(body) {
+                    let part_number: i32 = cap[1].parse().unwrap();
+                    data.extend_from_slice(&parts[&part_number]);
+                }
+                let tag = etag(&data);
+                s3.objects.insert(key.clone(), data.freeze());
+                xml(format!(
+                    "<CompleteMultipartUploadResult><Bucket>{bucket}</Bucket><Key>{key}</Key><ETag>{tag}</ETag></CompleteMultipartUploadResult>"
+                ))
+            }
+            Method::GET if upload_id.is_some() => {
+                let Some((_, parts)) = s3.multipart.get(upload_id.unwrap()) else {
+                    return s3_err(StatusCode::NOT_FOUND, "NoSuchUpload");
+                };
+                let parts: String = parts
+                    .iter()
+                    .map(|(n, data)| {
+                        format!(
+                            "<Part><PartNumber>{n}</PartNumber><ETag>{}</ETag><Size>{}</Size></Part>",
+                            etag(data),
+                            data.len()
+                        )
+                    })
+                    .collect();
+                xml(format!(
+                    "<ListPartsResult><IsTruncated>false</IsTruncated>{parts}</ListPartsResult>"
+                ))
+            }
+            Method::GET => {
+                let Some(data) = s3.objects.get(&key) else {
+                    return s3_err(StatusCode::NOT_FOUND, "NoSuchKey");
+                };
+                let Some(range) = headers.get("range") else {
+                    return ([("etag", etag(data))], data.clone()).into_response();
+                };
+                let (start, end) = range
+                    .to_str()
+                    .unwrap()
+                    .trim_start_matches("bytes=")
+                    .split_once('-')
+                    .unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                if end >= data.len() {
+                    return s3_err(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange");
+                }
+                let content_range = format!("bytes {start}-{end}/{}", data.len());
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [("content-range", content_range)],
+                    data.slice(start..=end),
+                )
+                    .into_response()
+            }
+            Method::HEAD => match s3.objects.get(&key) {
+                Some(data) => ([("content-length", data.len().to_string())], "").into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            },
+            Method::DELETE if upload_id.is_some() => {
+                match s3.multipart.remove(upload_id.unwrap()) {
+                    Some(_) => StatusCode::NO_CONTENT.into_response(),
+                    None => s3_err(StatusCode::NOT_FOUND, "NoSuchUpload"),
+                }
+            }
+            Method::DELETE => {
+                s3.objects.remove(&key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => s3_err(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+        }
+    }
+
+    async fn fake_s3_storage(part_size: u64) -> (S3Storage, FakeS3State) {
+        let state = FakeS3State::default();
+        let app = Router::new()
+            .route("/{bucket}", axum::routing::any(bucket_handler))
+            .route("/{bucket}/", axum::routing::any(bucket_handler))
+            .route("/{bucket}/{*key}", axum::routing::any(object_handler))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = S3StorageConfig {
+            bucket: "trow".to_string(),
+            prefix: "registry/".to_string(),
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(format!("http://{addr}")),
+            force_path_style: true,
+            access_key_id: Some("access".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            part_size: Some(size::Size::from_bytes(part_size)),
+        };
+        (S3Storage::new(&config).await.unwrap(), state)
+    }
+
+    fn chunks(data: &'static [u8], chunk_size: usize) -> BlobStream<'static> {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = data
+            .chunks(chunk_size)
+            .map(|c| Ok(Bytes::from_static(c)))
+            .collect();
+        futures::stream::iter(chunks).boxed()
+    }
+
+    async fn read_blob(
+        store: &S3Storage,
+        digest: &str,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Vec<u8> {
+        let stream = store.get_blob_stream("repo", digest, range).await.unwrap();
+        let size = stream.size();
+        let mut data = Vec::new();
+        stream.reader().read_to_end(&mut data).await.unwrap();
+        assert_eq!(size, data.len());
+        data
+    }
+
+    const DATA: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    #[tokio::test]
+    async fn s3_chunked_upload_multipart() {
+        let (store, s3) = fake_s3_storage(10).await;
+        let uuid = uuid::Uuid::new_v4();
+        let digest = Digest::digest_sha256_slice(DATA);
+
+        let stored = store
+            .write_blob_part_stream(&uuid, chunks(&DATA[..15], 4), Some(0..=14))
+            .await
+            .unwrap();
+        assert_eq!((stored.total_stored, stored.chunk), (15, 15));
+        {
+            let s3 = s3.lock().unwrap();
+            assert_eq!(s3.multipart.len(), 1);
+            assert_eq!(
+                s3.objects[&format!("registry/uploads/{uuid}.tail")].len(),
+                3
+            );
+        }
+
+        let err = store
+            .write_blob_part_stream(&uuid, chunks(&DATA[15..], 4), Some(10..=42))
+            .await;
+        assert!(matches!(err, Err(StorageBackendError::InvalidContentRange)));
+
+        let stored = store
+            .write_blob_part_stream(&uuid, chunks(&DATA[15..], 7), None)
+            .await
+            .unwrap();
+        assert_eq!(stored.total_stored, DATA.len() as u64);
+
+        store
+            .complete_blob_write(&uuid, digest.as_str())
+            .await
+            .unwrap();
+        assert_eq!(read_blob(&store, digest.as_str(), None).await, DATA);
+        let s3 = s3.lock().unwrap();
+        assert!(s3.multipart.is_empty());
+        assert_eq!(
+            s3.objects.keys().collect::<Vec<_>>(),
+            vec![&format!("registry/blobs/{digest}")]
+        );
+    }
+
+    #[tokio::test]
+    async fn s3_monolithic_upload() {
+        let (store, s3) = fake_s3_storage(1024).await;
+        let uuid = uuid::Uuid::new_v4();
+        let digest = Digest::digest_sha256_slice(DATA);
+        store
+            .write_blob_part_stream(&uuid, chunks(DATA, 8), None)
+            .await
+            .unwrap();
+        store
+            .complete_blob_write(&uuid, digest.as_str())
+            .await
+            .unwrap();
+        assert!(s3.lock().unwrap().multipart.is_empty());
+        assert_eq!(read_blob(&store, digest.as_str(), None).await, DATA);
+        assert_eq!(store.list_blobs().await.unwrap(), vec![digest.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn s3_write_blob_stream_verifies_digest() {
+        let (store, s3) = fake_s3_storage(10).await;
+        let digest = Digest::digest_sha256_slice(DATA);
+        let bad_digest = Digest::digest_sha256_slice(b"something else");
+
+        let err = store
+            .write_blob_stream(bad_digest.as_str(), chunks(DATA, 4), true)
+            .await;
+        assert!(matches!(err, Err(StorageBackendError::InvalidDigest)));
+        {
+            let s3 = s3.lock().unwrap();
+            assert!(s3.multipart.is_empty());
+            assert!(s3.objects.is_empty());
+        }
+
+        let size = store
+            .write_blob_stream(digest.as_str(), chunks(DATA, 4), true)
+            .await
+            .unwrap();
+        assert_eq!(size, DATA.len() as u64);
+        assert_eq!(read_blob(&store, digest.as_str(), None).await, DATA);
+        // Already stored: nothing is read from the stream
+        let size = store
+            .write_blob_stream(digest.as_str(), futures::stream::empty().boxed(), true)
+            .await
+            .unwrap();
+        assert_eq!(size, DATA.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn s3_write_blob_stream_aborts_failed_multipart_upload() {
+        let (store, s3) = fake_s3_storage(10).await;
+        let digest = Digest::digest_sha256_slice(DATA);
+
+        // Failing part while streaming, then failing remainder part
+        for part in [2, 4] {
+            s3.lock().unwrap().fail_part = Some(part);
+            let res = store
+                .write_blob_stream(digest.as_str(), chunks(DATA, 4), true)
+                .await;
+            assert!(res.is_err());
+            assert!(s3.lock().unwrap().multipart.is_empty());
+        }
+        s3.lock().unwrap().fail_part = None;
+
+        s3.lock().unwrap().fail_complete = true;
+        let res = store
+            .write_blob_stream(digest.as_str(), chunks(DATA, 4), true)
+            .await;
+        assert!(res.is_err());
+        {
+            let s3 = s3.lock().unwrap();
+            assert!(s3.multipart.is_empty());
+            assert!(s3.objects.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn s3_delete_and_list() {
+        let (store, s3) = fake_s3_storage(10).await;
+        let mut digests = Vec::new();
+        for data in [&b"a"[..], b"bb", b"ccc", b"dddd", b"eeeee"] {
+            let digest = Digest::digest_sha256_slice(data).to_string();
+            store
+                .write_blob_stream(
+                    &digest,
+                    into_blob_stream(futures::stream::once(async move {
+                        Ok::<_, std::io::Error>(Bytes::copy_from_slice(data))
+                    })),
+                    false,
+                )
+                .await
+                .unwrap();
+            digests.push(digest);
+        }
+        let mut listed = store.list_blobs().await.unwrap();
+        listed.sort();
+        digests.sort();
+        assert_eq!(listed, digests);
+
+        store.delete_blob(&digests[0]).await.unwrap();
+        assert_eq!(store.list_blobs().await.unwrap().len(), 4);
+        assert!(matches!(
+            store.get_blob_stream("repo", &digests[0], None).await,
+            Err(StorageBackendError::BlobNotFound(_))
+        ));
+
+        let uuid = uuid::Uuid::new_v4();
+        store
+            .write_blob_part_stream(&uuid, chunks(DATA, 20), None)
+            .await
+            .unwrap();
+        store.delete_upload(&uuid.to_string()).await.unwrap();
+        assert!(s3.lock().unwrap().multipart.is_empty());
+        assert!(matches!(
+            store.complete_blob_write(&uuid, &digests[1]).await,
+            Err(StorageBackendError::BlobNotFound(_))
+        ));
+        store.is_ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn s3_get_blob_range() {
+        let (store, _s3) = fake_s3_storage(1024).await;
+        let digest = Digest::digest_sha256_slice(DATA);
+        store
+            .write_blob_stream(digest.as_str(), chunks(DATA, 8), true)
+            .await
+            .unwrap();
+        assert_eq!(store.blob_size(digest.as_str()).await.unwrap(), 43);
+        assert_eq!(
+            read_blob(&store, digest.as_str(), Some(4..=8)).await,
+            b"quick"
+        );
+        assert!(matches!(
+            store
+                .get_blob_stream("repo", digest.as_str(), Some(40..=43))
+                .await,
+            Err(StorageBackendError::InvalidContentRange)
+        ));
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use axum::Router;
 use axum::body::Body;
+use axum::extract::{Path, Query};
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
 use http_body_util::BodyExt;
 use hyper::Response;
 use serde::de::DeserializeOwned;
@@ -11,6 +15,7 @@ use sqlx::sqlite::{
 use test_temp_dir::TestTempDir;
 
 use crate::repositories::Repositories;
+use crate::utils::digest::Digest;
 use crate::{TrowConfig, TrowServerState, routes};
 
 pub const DIST_API_HEADER: &str = "Docker-Distribution-API-Version";
@@ -86,6 +91,134 @@ pub async fn response_body_json<T: DeserializeOwned>(resp: Response<Body>) -> T
     }
 }
 
+/// Most tags listed per page by [`fake_upstream`] when the client sets `n`
+pub const FAKE_UPSTREAM_TAGS_PAGE: usize = 2;
+
+/// Starts a stand-in upstream registry serving the repository `repo`, and
+/// returns its `host:port`.
+///
+/// `manifests` are served by tag, and by digest, with their `mediaType` as
+/// `Content-Type`. Their tags are listed after `last`, and like registries
+/// capping the page size, a page asked with `n` has at most
+/// [`FAKE_UPSTREAM_TAGS_PAGE`] tags. The manifests with a `subject` are its
+/// referrers.
+/// `blobs` are served by digest.
+pub async fn fake_upstream(
+    repo: &str,
+    manifests: HashMap<String, String>,
+    blobs: HashMap<String, Vec<u8>>,
+) -> String {
+    let mut by_reference = HashMap::new();
+    for (reference, manifest) in manifests {
+        let digest = Digest::digest_sha256_slice(manifest.as_bytes()).to_string();
+        by_reference.insert(digest, manifest.clone());
+        by_reference.insert(reference, manifest);
+    }
+    let state = Arc::new((repo.to_string(), by_reference, blobs));
+    let app = Router::new().route(
+        "/v2/{*path}",
+        axum::routing::get(
+            move |Path(path): Path<String>, Query(query): Query<HashMap<String, String>>| {
+                let state = state.clone();
+                async move {
+                    let (repo, manifests, blobs) = &*state;
+                    let Some(path) = path.strip_prefix(repo.as_str()) else {
+                        return StatusCode::NOT_FOUND.into_response();
+                    };
+                    if path == "/tags/list" {
+                        let mut tags: Vec<_> = manifests
+                            .keys()
+                            .filter(|r| !r.starts_with("sha256:"))
+                            .filter(|t| query.get("last").is_none_or(|last| *t > last))
+                            .collect();
+                        tags.sort();
+                        if let Some(n) = query.get("n").and_then(|n| n.parse::<usize>().ok()) {
+                            tags.truncate(n.min(FAKE_UPSTREAM_TAGS_PAGE));
+                        }
+                        let list = serde_json::json!({ "name": repo, "tags": tags });
+                        return axum::Json(list).into_response();
+                    }
+                    if let Some(reference) = path.strip_prefix("/manifests/") {
+                        let Some(manifest) = manifests.get(reference) else {
+                            return StatusCode::NOT_FOUND.into_response();
+                        };
+                        return manifest_response(manifest.clone());
+                    }
+                    if let Some(subject) = path.strip_prefix("/referrers/") {
+                        return referrers_response(manifests, subject);
+                    }
+                    if let Some(digest) = path.strip_prefix("/blobs/")
+                        && let Some(blob) = blobs.get(digest)
+                    {
+                        return blob.clone().into_response();
+                    }
+                    StatusCode::NOT_FOUND.into_response()
+                }
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    host
+}
+
+fn manifest_response(manifest: String) -> axum::response::Response {
+    let parsed: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+    let media_type = parsed["mediaType"]
+        .as_str()
+        .unwrap_or("application/vnd.oci.image.manifest.v1+json")
+        .to_string();
+    let digest = Digest::digest_sha256_slice(manifest.as_bytes()).to_string();
+    (
+        [
+            (header::CONTENT_TYPE, media_type),
+            (
+                header::HeaderName::from_static("docker-content-digest"),
+                digest,
+            ),
+        ],
+        manifest,
+    )
+        .into_response()
+}
+
+/// Index of the manifests whose subject is `subject`
+fn referrers_response(
+    manifests: &HashMap<String, String>,
+    subject: &str,
+) -> axum::response::Response {
+    let mut referrers: Vec<_> = manifests
+        .iter()
+        .filter(|(reference, _)| reference.starts_with("sha256:"))
+        .filter_map(|(digest, manifest)| {
+            let parsed: serde_json::Value = serde_json::from_str(manifest).unwrap();
+            (parsed["subject"]["digest"] == subject).then(|| {
+                serde_json::json!({
+                    "mediaType": parsed["mediaType"],
+                    "artifactType": parsed["artifactType"],
+                    "digest": digest,
+                    "size": manifest.len(),
+                })
+            })
+        })
+        .collect();
+    referrers.sort_by_key(|r| r["digest"].to_string());
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": referrers,
+    });
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/vnd.oci.image.index.v1+json",
+        )],
+        index.to_string(),
+    )
+        .into_response()
+}
+
 /// test_temp_dir if thread name != module path, which is the case in parametrized tests
 pub fn test_temp_dir_from_thread_name(mod_path: &str) -> TestTempDir {
     let path = {
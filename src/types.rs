@@ -38,6 +38,20 @@ pub struct OptionalDigestQuery {
     pub digest: Option<Digest>,
 }
 
+/// `?mount=<digest>&from=<repo>` parameters of a cross-repository blob mount.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MountQuery {
+    pub mount: Option<String>,
+    pub from: Option<String>,
+}
+
+/// `?artifactType=` filter of the referrers API.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ArtifactTypeQuery {
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct DigestQuery {
     pub digest: Digest,
@@ -50,6 +64,7 @@ pub struct UploadInfo {
     range: (u64, u64),
 }
 
+#[derive(Debug)]
 pub struct BlobDeleted {}
 
 pub struct ManifestDeleted {}
@@ -111,10 +126,31 @@ impl AcceptedUpload {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct MountedBlob {
+    digest: Digest,
+    repo_name: String,
+}
+
+impl MountedBlob {
+    pub fn new(digest: Digest, repo_name: String) -> Self {
+        Self { digest, repo_name }
+    }
+
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    pub fn repo_name(&self) -> &String {
+        &self.repo_name
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub enum Upload {
     Accepted(AcceptedUpload),
     Info(UploadInfo),
+    Mounted(MountedBlob),
 }
 
 #[derive(Debug, Serialize)]
@@ -4,7 +4,8 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha2::digest::OutputSizeUser;
-use sha2::{Digest as ShaDigest, Sha256};
+use sha2::digest::common::hazmat::{SerializableState, SerializedState};
+use sha2::{Digest as ShaDigest, Sha256, Sha512};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
@@ -119,10 +120,101 @@ impl Digest {
     }
 }
 
+/// Hasher whose state can be saved and restored, so that the digest of a blob
+/// can be computed while it's being uploaded over several requests.
+#[derive(Clone)]
+pub enum IncrementalDigest {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Default for IncrementalDigest {
+    fn default() -> Self {
+        Self::Sha256(Sha256::default())
+    }
+}
+
+impl fmt::Debug for IncrementalDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IncrementalDigest")
+            .field("algo", &self.algo_str())
+            .finish_non_exhaustive()
+    }
+}
+
+impl IncrementalDigest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hasher for the algorithm of a digest (`sha256` or `sha512`)
+    pub fn for_algo(algo: &str) -> Result<Self, DigestError> {
+        match algo {
+            "sha256" => Ok(Self::Sha256(Sha256::default())),
+            "sha512" => Ok(Self::Sha512(Sha512::default())),
+            _ => Err(DigestError::InvalidDigest(format!(
+                "Unsupported algorithm: `{algo}`"
+            ))),
+        }
+    }
+
+    pub fn algo_str(&self) -> &'static str {
+        match self {
+            Self::Sha256(_) => "sha256",
+            Self::Sha512(_) => "sha512",
+        }
+    }
+
+    /// Restores a hasher from a state produced by [`IncrementalDigest::state`]
+    pub fn from_state(state: &[u8]) -> Result<Self, DigestError> {
+        let invalid = || DigestError::InvalidDigest("Invalid hasher state".to_owned());
+        let sep = state.iter().position(|&b| b == b':').ok_or_else(invalid)?;
+        let (algo, state) = (&state[..sep], &state[sep + 1..]);
+        match algo {
+            b"sha256" => SerializedState::<Sha256>::try_from(state)
+                .ok()
+                .and_then(|s| Sha256::deserialize(&s).ok())
+                .map(Self::Sha256),
+            b"sha512" => SerializedState::<Sha512>::try_from(state)
+                .ok()
+                .and_then(|s| Sha512::deserialize(&s).ok())
+                .map(Self::Sha512),
+            _ => None,
+        }
+        .ok_or_else(invalid)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+
+    /// Serialized hasher state, prefixed with the algorithm (`sha256:<state>`)
+    pub fn state(&self) -> Vec<u8> {
+        let mut state = format!("{}:", self.algo_str()).into_bytes();
+        match self {
+            Self::Sha256(h) => state.extend_from_slice(&h.serialize()),
+            Self::Sha512(h) => state.extend_from_slice(&h.serialize()),
+        }
+        state
+    }
+
+    pub fn finalize(self) -> Digest {
+        let algo = self.algo_str();
+        let hash = match self {
+            Self::Sha256(h) => hex::encode(h.finalize()),
+            Self::Sha512(h) => hex::encode(h.finalize()),
+        };
+        Digest(format!("{algo}:{hash}"))
+    }
+}
+
 #[cfg(test)]
 mod test {
 
-    use crate::utils::digest::Digest;
+    use crate::utils::digest::{Digest, IncrementalDigest};
 
     #[test]
     fn sha256_digest_test() {
@@ -168,4 +260,36 @@ mod test {
             "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"
         );
     }
+
+    #[test]
+    fn incremental_digest_resumes_from_state() {
+        let data = "the quick brown fox jumps over the lazy dog".repeat(10);
+        let (first, second) = data.as_bytes().split_at(71);
+        let mut hasher = IncrementalDigest::new();
+        hasher.update(first);
+        let state = hasher.state();
+
+        let mut hasher = IncrementalDigest::from_state(&state).unwrap();
+        hasher.update(second);
+        assert_eq!(
+            hasher.finalize(),
+            Digest::digest_sha256_slice(data.as_bytes())
+        );
+
+        assert!(IncrementalDigest::from_state(&state[1..]).is_err());
+        assert!(IncrementalDigest::from_state(&state[..state.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn incremental_digest_sha512() {
+        let mut hasher = IncrementalDigest::for_algo("sha512").unwrap();
+        hasher.update(b"hello ");
+        let mut hasher = IncrementalDigest::from_state(&hasher.state()).unwrap();
+        hasher.update(b"world");
+        assert_eq!(
+            hasher.finalize().as_str(),
+            "sha512:309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f"
+        );
+        assert!(IncrementalDigest::for_algo("md5").is_err());
+    }
 }
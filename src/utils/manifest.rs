@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use lazy_static::lazy_static;
-use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType};
+use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType, Platform};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +9,11 @@ use crate::utils::digest::DigestError;
 
 lazy_static! {
     pub static ref REGEX_TAG: Regex = Regex::new("^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$").unwrap();
+    /// `type/subtype` with the naming rules of RFC 6838 section 4.2
+    pub static ref REGEX_MEDIA_TYPE: Regex = Regex::new(
+        r"^[a-zA-Z0-9][a-zA-Z0-9!#$&^_.+-]{0,126}/[a-zA-Z0-9][a-zA-Z0-9!#$&^_.+-]{0,126}$"
+    )
+    .unwrap();
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -105,6 +110,18 @@ impl OCIManifest {
     }
 }
 
+/// Whether `platform` is `wanted`, written `os/arch[/variant]`.
+/// Without variant, all variants of the architecture match.
+pub fn platform_matches(platform: &Platform, wanted: &str) -> bool {
+    let mut wanted = wanted.split('/');
+    let (Some(os), Some(arch), variant) = (wanted.next(), wanted.next(), wanted.next()) else {
+        return false;
+    };
+    platform.os().to_string() == os
+        && platform.architecture().to_string() == arch
+        && variant.is_none_or(|v| platform.variant().as_deref() == Some(v))
+}
+
 pub fn layer_is_distributable(layer: &MediaType) -> bool {
     let non_distributable = [
         MediaType::ImageLayerNonDistributable,
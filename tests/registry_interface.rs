@@ -329,6 +329,310 @@ mod registry_interface {
         );
     }
 
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_push_manifest_invalid_config_size() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let (config, config_digest) = upload_blob_with_post(&trow, "sizetest").await;
+        let manifest = format!(
+            r#"{{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                 "config": {{ "digest": "{config_digest}",
+                             "mediaType": "application/vnd.oci.image.config.v1+json",
+                             "size": {} }},
+                 "layers": [], "schemaVersion": 2 }}"#,
+            config.len() + 1
+        );
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::put("/v2/sizetest/manifests/latest")
+                    .body(Body::from(manifest))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(body["errors"][0]["code"], "SIZE_INVALID");
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_manifest_accept() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        upload_blob_with_post(&trow, "accepttest").await;
+        push_oci_manifest(&trow, "accepttest", "latest").await;
+        let get = |accept: &'static str| {
+            trow.clone().oneshot(
+                Request::get("/v2/accepttest/manifests/latest")
+                    .header("Accept", accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let resp = get("application/vnd.docker.distribution.manifest.v2+json")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        for accept in [
+            "application/vnd.docker.distribution.manifest.v2+json, application/vnd.oci.image.manifest.v1+json",
+            "application/vnd.docker.distribution.manifest.v2+json;q=0.9, */*",
+        ] {
+            let resp = get(accept).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers().get("Content-Type").unwrap(),
+                "application/vnd.oci.image.manifest.v1+json"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_list_upstreams() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let resp = trow
+            .oneshot(
+                Request::get("/admin/upstreams")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let upstreams: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(upstreams, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_list_prefetch() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let resp = trow
+            .oneshot(Request::get("/admin/prefetch").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let prefetched: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(prefetched, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_cancel_upload() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::post("/v2/canceltest/blobs/uploads/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let uuid = resp.headers().get(common::UPLOAD_HEADER).unwrap();
+        let uuid = uuid.to_str().unwrap().to_owned();
+
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::get("/admin/uploads?repo=canceltest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let uploads: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(uploads[0]["uuid"], uuid);
+        assert_eq!(uploads[0]["offset"], 0);
+        assert_eq!(uploads[0]["owner"], "none");
+
+        let upload_url = format!("/v2/canceltest/blobs/uploads/{uuid}");
+        for expected in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            let resp = trow
+                .clone()
+                .oneshot(Request::delete(&upload_url).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), expected);
+        }
+        let resp = trow
+            .clone()
+            .oneshot(Request::get(&upload_url).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_delete_blob() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let delete = |repo: &'static str, digest: String| {
+            trow.clone().oneshot(
+                Request::delete(format!("/v2/{repo}/blobs/{digest}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        // Referenced by a manifest of the repo
+        let (_, digest) = upload_blob_with_post(&trow, "deltest").await;
+        push_oci_manifest(&trow, "deltest", "latest").await;
+        let resp = delete("deltest", digest.to_string()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(body["errors"][0]["code"], "UNSUPPORTED");
+        assert_eq!(
+            body["errors"][0]["detail"]["Manifests"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Same blob, not referenced in that repo
+        upload_blob_with_post(&trow, "deltest2").await;
+        let resp = delete("deltest2", digest.to_string()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::get(format!("/v2/deltest2/blobs/{digest}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = delete("deltest2", digest.to_string()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_blob_scoped_to_repo() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let get = |method: &'static str, repo: &'static str, digest: String| {
+            trow.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(format!("/v2/{repo}/blobs/{digest}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let (_, digest) = upload_blob_with_post(&trow, "scoped").await;
+        for method in ["GET", "HEAD"] {
+            let resp = get(method, "scoped", digest.to_string()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            // Stored, but never pushed to nor mounted in this repo: blobs
+            // used to be served from any repository.
+            let resp = get(method, "scoped-other", digest.to_string())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_mount_blob() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let (_, digest) = upload_blob_with_post(&trow, "mountsrc").await;
+        let post = |uri: String| {
+            trow.clone()
+                .oneshot(Request::post(uri).body(Body::empty()).unwrap())
+        };
+
+        let resp = post(format!(
+            "/v2/mountdst/blobs/uploads/?mount={digest}&from=mountsrc"
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers().get("Location").unwrap(),
+            &format!("/v2/mountdst/blobs/{digest}")
+        );
+        assert_eq!(
+            resp.headers().get("Docker-Content-Digest").unwrap(),
+            digest.as_str()
+        );
+
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::get(format!("/v2/mountdst/blobs/{digest}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Blob unknown to the source repository: a regular upload is started
+        let resp = post(format!(
+            "/v2/mountdst2/blobs/uploads/?mount={digest}&from=nosuchrepo"
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert!(resp.headers().contains_key(common::UPLOAD_HEADER));
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_get_blob_range() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+        let (_, digest) = upload_blob_with_post(&trow, "rangetest").await;
+        let get = |range: &'static str| {
+            trow.clone().oneshot(
+                Request::get(format!("/v2/rangetest/blobs/{digest}"))
+                    .header("Range", range)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let resp = get("bytes=1-2").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 1-2/4");
+        assert_eq!(resp.headers().get("Content-Length").unwrap(), "2");
+        assert_eq!(resp.headers().get("Accept-Ranges").unwrap(), "bytes");
+        assert_eq!(response_body_string(resp).await, " }");
+
+        let resp = get("bytes=-1").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response_body_string(resp).await, "\n");
+
+        let resp = get("bytes=4-").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes */4");
+
+        let resp = get("bytes=0-0,2-3").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // Unknown units are ignored
+        let resp = get("lines=0-1").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Accept-Ranges").unwrap(), "bytes");
+        assert_eq!(response_body_string(resp).await, "{ }\n");
+    }
+
     #[tokio::test]
     #[tracing_test::traced_test]
     async fn upload_image() {
@@ -389,6 +693,38 @@ mod registry_interface {
         assert_eq!(range, format!("0-{}", (config.len() - 1))); //note first byte is 0, hence len - 1
     }
 
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn blob_upload_digest_mismatch() {
+        let tmp_dir = test_temp_dir!();
+        let trow = start_trow(&tmp_dir).await;
+
+        let name = "digesttest";
+        let wrong_digest = digest::Digest::digest_sha256_slice(b"what the client claims");
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::post(format!("/v2/{name}/blobs/uploads/?digest={wrong_digest}"))
+                    .body(Body::from("what the client sends"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(response_body_string(resp).await.contains("DIGEST_INVALID"));
+
+        let resp = trow
+            .clone()
+            .oneshot(
+                Request::get(format!("/v2/{name}/blobs/{wrong_digest}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
     #[tokio::test]
     #[tracing_test::traced_test]
     async fn blob_upload_with_post() {
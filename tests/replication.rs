@@ -0,0 +1,130 @@
+#![cfg(test)]
+
+mod common;
+
+mod replication {
+
+    use axum::Router;
+    use axum::body::Body;
+    use hyper::Request;
+    use reqwest::StatusCode;
+    use test_temp_dir::test_temp_dir;
+    use tower::ServiceExt;
+    use trow::configuration::{RegistryEndpoint, ReplicationConfig, ReplicationRule};
+
+    use crate::common::{self, trow_router};
+
+    async fn get_status(cl: &Router, uri: &str) -> StatusCode {
+        cl.clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_replicate_pushed_image() {
+        let tmp_dir = test_temp_dir!();
+        let source_dir = tmp_dir.as_path_untracked().join("source");
+        let target_dir = tmp_dir.as_path_untracked().join("target");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        std::fs::create_dir_all(&target_dir).unwrap();
+
+        // The target is a second Trow, served over HTTP
+        let (_, target) = trow_router(&target_dir, |_| {}).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn({
+            let target = target.clone();
+            async move { axum::serve(listener, target).await.unwrap() }
+        });
+
+        let replication = ReplicationConfig {
+            max_attempts: 1,
+            rules: vec![
+                ReplicationRule {
+                    repositories: "^team/".to_string(),
+                    target: RegistryEndpoint {
+                        host: addr.clone(),
+                        insecure: true,
+                        repository_prefix: Some("dr".to_string()),
+                        ..Default::default()
+                    },
+                },
+                ReplicationRule {
+                    repositories: "^team/".to_string(),
+                    target: RegistryEndpoint {
+                        host: "127.0.0.1:1".to_string(),
+                        insecure: true,
+                        ..Default::default()
+                    },
+                },
+            ],
+        };
+        let (state, source) = trow_router(&source_dir, |cfg| {
+            cfg.config_file.replication = Some(replication);
+        })
+        .await;
+
+        let (blob_digest, manifest_digest) =
+            common::upload_fake_image(&source, "team/app", "v1").await;
+        common::upload_fake_image(&source, "other/app", "v1").await;
+        assert_eq!(state.services.replication.run_once().await, 2);
+
+        assert_eq!(
+            get_status(
+                &target,
+                &format!("/v2/dr/team/app/manifests/{manifest_digest}")
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_status(&target, "/v2/dr/team/app/manifests/v1").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_status(&target, &format!("/v2/dr/team/app/blobs/{blob_digest}")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_status(&target, "/v2/dr/other/app/manifests/v1").await,
+            StatusCode::NOT_FOUND
+        );
+
+        let resp = source
+            .clone()
+            .oneshot(
+                Request::get("/admin/replication")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let jobs: serde_json::Value = common::response_body_json(resp).await;
+        let jobs = jobs.as_array().unwrap();
+        assert_eq!(jobs.len(), 2);
+        let done = jobs.iter().find(|j| j["status"] == "done").unwrap();
+        assert_eq!(done["target"], format!("{addr}/dr/team/app"));
+        assert_eq!(done["reference"], "v1");
+        assert_eq!(done["manifest_digest"], manifest_digest.to_string());
+        assert_eq!(done["attempts"], 1);
+
+        // The unreachable target is out of attempts
+        let resp = source
+            .clone()
+            .oneshot(
+                Request::get("/admin/replication?status=failed")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let failed: serde_json::Value = common::response_body_json(resp).await;
+        assert_eq!(failed[0]["target"], "127.0.0.1:1/team/app");
+        assert!(failed[0]["last_error"].is_string());
+        assert_eq!(state.services.replication.run_once().await, 0);
+    }
+}